@@ -1,9 +1,21 @@
 mod utils;
 
-use banjoc::{ast::Source, error::Error, output::Output, vm::Vm};
+use banjoc::{
+    ast::{LiteralType, Source},
+    config::OutputLimits,
+    error::Error,
+    output::{MemoryStats, Output},
+    program::{CompiledProgram, Inputs},
+    value::{NumberFormat, Value},
+    vm::{Step, Vm},
+};
+use js_sys::{
+    Array, ArrayBuffer, Date, Float64Array, Function, Math, Object, Promise, Reflect, WebAssembly,
+};
 use serde::Serialize;
 use utils::set_panic_hook;
 use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -11,23 +23,384 @@ use wasm_bindgen::prelude::*;
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
-#[wasm_bindgen(catch)]
-pub fn interpret(source: JsValue) -> JsValue {
+/// Instructions executed between checks of the time limit of [`interpret`]
+const TIME_CHECK_INTERVAL: usize = 10_000;
+
+/// Evaluate a graph. If given, `on_output` is called with `(nodeId, value)` as
+/// soon as each node's output is computed, so that results can be shown before
+/// the whole graph has finished.
+///
+/// The evaluation stops with an error once it has executed `max_instructions`
+/// or taken `max_millis`, if given, so that untrusted graphs can't hang the
+/// page.
+#[wasm_bindgen]
+pub fn interpret(
+    source: JsValue,
+    on_output: Option<Function>,
+    max_instructions: Option<usize>,
+    max_millis: Option<f64>,
+) -> JsValue {
     set_panic_hook();
-    let value = parse_interpret(source);
-    let serializer = serde_wasm_bindgen::Serializer::new().serialize_maps_as_objects(true);
-    value
-        .serialize(&serializer)
-        .unwrap_or_else(|_| JsValue::from_str("compile error: couldn't serialize result"))
+    // Values in the output live on the VM's heap, so serialize before dropping the
+    // VM
+    let mut vm = new_vm();
+    let output = match parse_source(source) {
+        Ok(source) => {
+            let mut on_output = |node_id: &str, value: Value| {
+                if let Some(on_output) = &on_output {
+                    let value = value.serialize(&serializer()).unwrap_or(JsValue::UNDEFINED);
+                    // An exception thrown by the callback shouldn't stop the graph
+                    let _ = on_output.call2(&JsValue::NULL, &JsValue::from_str(node_id), &value);
+                }
+            };
+            vm.start(source);
+            run_limited(&mut vm, &mut on_output, max_instructions, max_millis)
+        }
+        Err(e) => Output::from_single_error(e),
+    };
+    to_js(&output)
 }
 
-fn parse_interpret(source: JsValue) -> Output {
-    let mut vm = Vm::new();
-    let source: Source = match serde_wasm_bindgen::from_value(source) {
-        Ok(source) => source,
-        Err(e) => {
-            return Output::from_single_error(Error::Compile(format!("JSON parsing error: {e}")))
+fn run_limited(
+    vm: &mut Vm,
+    on_output: &mut dyn FnMut(&str, Value),
+    max_instructions: Option<usize>,
+    max_millis: Option<f64>,
+) -> Output {
+    let deadline = max_millis.map(|millis| Date::now() + millis);
+    let mut remaining = max_instructions;
+    loop {
+        let mut slice = remaining.unwrap_or(usize::MAX);
+        if deadline.is_some() {
+            slice = slice.min(TIME_CHECK_INTERVAL);
+        }
+        if let Step::Done(output) = vm.step_with(slice, on_output) {
+            return output;
+        }
+        if let Some(remaining) = &mut remaining {
+            *remaining -= slice;
+            if *remaining == 0 {
+                let message = format!(
+                    "Exceeded the limit of {} instructions.",
+                    max_instructions.unwrap_or_default()
+                );
+                return vm.stop(Error::runtime(message), "instruction_limit");
+            }
+        }
+        if deadline.is_some_and(|deadline| Date::now() > deadline) {
+            let message = format!(
+                "Exceeded the time limit of {} ms.",
+                max_millis.unwrap_or_default()
+            );
+            return vm.stop(Error::runtime(message), "time_limit");
+        }
+    }
+}
+
+/// A VM kept alive between evaluations, so that the host can inspect it
+#[wasm_bindgen]
+pub struct BanjoVm {
+    vm: Vm,
+    /// Indexed by the handles returned by [`BanjoVm::compile`], `None` once
+    /// freed
+    programs: Vec<Option<CompiledProgram>>,
+}
+
+/// Memory used by a [`BanjoVm`], in bytes
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MemoryUsage {
+    #[serde(flatten)]
+    heap: MemoryStats,
+    /// Size of the whole wasm linear memory, which never shrinks
+    wasm_memory_bytes: u32,
+}
+
+#[wasm_bindgen]
+impl BanjoVm {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> BanjoVm {
+        set_panic_hook();
+        BanjoVm {
+            vm: new_vm(),
+            programs: vec![],
+        }
+    }
+
+    /// Compile a graph once, returning a handle to [`run`](Self::run) it
+    /// with, so that a graph evaluated often isn't recompiled each time.
+    /// Compile errors are reported by each run.
+    pub fn compile(&mut self, source: JsValue) -> Result<usize, JsValue> {
+        let source = parse_source(source).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.programs.push(Some(self.vm.compile(source)));
+        Ok(self.programs.len() - 1)
+    }
+
+    /// Free a program compiled by [`compile`](Self::compile) once it's no
+    /// longer needed. Its handle can't be run anymore.
+    pub fn free(&mut self, handle: usize) {
+        if let Some(program) = self.programs.get_mut(handle).and_then(Option::take) {
+            self.vm.free_program(program);
+        }
+    }
+
+    /// Run a graph compiled by [`compile`](Self::compile), optionally
+    /// replacing the output of nodes with `inputs`, an object keyed by node id
+    pub fn run(&mut self, handle: usize, inputs: JsValue) -> JsValue {
+        let inputs = if inputs.is_undefined() || inputs.is_null() {
+            Ok(Inputs::default())
+        } else {
+            parse_inputs(inputs)
+        };
+        let output = match (self.programs.get(handle).and_then(Option::as_ref), inputs) {
+            (Some(program), Ok(inputs)) => self
+                .vm
+                .interpret_batch(program, &[inputs])
+                .pop()
+                .expect("One output per set of inputs"),
+            (None, _) => Output::from_single_error(Error::runtime("No program with this handle.")),
+            (_, Err(e)) => Output::from_single_error(e),
+        };
+        to_js(&output)
+    }
+
+    pub fn interpret(&mut self, source: JsValue) -> JsValue {
+        let output = match parse_source(source) {
+            Ok(source) => self.vm.interpret(source),
+            Err(e) => Output::from_single_error(e),
+        };
+        to_js(&output)
+    }
+
+    /// Bytes allocated by the garbage collector along with the size of the
+    /// wasm linear memory, so that the host can warn before running out of
+    /// memory
+    pub fn memory_stats(&self) -> JsValue {
+        let memory: WebAssembly::Memory = wasm_bindgen::memory().unchecked_into();
+        let buffer: ArrayBuffer = memory.buffer().unchecked_into();
+        let usage = MemoryUsage {
+            heap: self.vm.memory_stats(),
+            wasm_memory_bytes: buffer.byte_length(),
+        };
+        usage.serialize(&serializer()).unwrap_or(JsValue::NULL)
+    }
+}
+
+impl Default for BanjoVm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Check a graph for errors without running it, for feedback while it's being
+/// edited. Returns an output with errors and diagnostics but no node values.
+#[wasm_bindgen]
+pub fn validate(source: JsValue) -> JsValue {
+    set_panic_hook();
+    let mut vm = new_vm();
+    let output = match parse_source(source) {
+        Ok(source) => vm.validate(source),
+        Err(e) => Output::from_single_error(e),
+    };
+    to_js(&output)
+}
+
+/// The bytecode which a graph compiles to, for each function, without running
+/// it
+#[wasm_bindgen]
+pub fn disassemble(source: JsValue) -> String {
+    set_panic_hook();
+    match parse_source(source) {
+        Ok(source) => new_vm().disassemble(source),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Like [`interpret`], but replace the output of nodes with the values in
+/// `inputs`, an object keyed by node id, so that hosts can vary `const` and
+/// `var` nodes without rewriting the source
+#[wasm_bindgen]
+pub fn interpret_with_inputs(source: JsValue, inputs: JsValue) -> JsValue {
+    set_panic_hook();
+    let mut vm = new_vm();
+    let output = match parse_source(source).and_then(|source| Ok((source, parse_inputs(inputs)?))) {
+        Ok((source, inputs)) => {
+            let program = vm.compile(source);
+            vm.interpret_batch(&program, &[inputs])
+                .pop()
+                .expect("One output per set of inputs")
+        }
+        Err(e) => Output::from_single_error(e),
+    };
+    to_js(&output)
+}
+
+/// Like [`interpret`], but execute at most `fuel` instructions at a time,
+/// awaiting a microtask in between, so that long evaluations don't freeze the
+/// page. Once the optional `AbortSignal` is aborted, evaluation stops and the
+/// outputs computed so far are returned along with a `cancelled` error.
+#[wasm_bindgen]
+pub async fn interpret_async(source: JsValue, fuel: usize, signal: JsValue) -> JsValue {
+    set_panic_hook();
+    let mut vm = new_vm();
+    let output = match parse_source(source) {
+        Ok(source) => {
+            vm.start(source);
+            loop {
+                if let Step::Done(output) = vm.step(fuel.max(1)) {
+                    break output;
+                }
+                yield_now().await;
+                if is_aborted(&signal) {
+                    break vm.cancel();
+                }
+            }
         }
+        Err(e) => Output::from_single_error(e),
+    };
+    to_js(&output)
+}
+
+fn is_aborted(signal: &JsValue) -> bool {
+    !signal.is_undefined()
+        && !signal.is_null()
+        && Reflect::get(signal, &JsValue::from_str("aborted"))
+            .is_ok_and(|aborted| aborted.is_truthy())
+}
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout(handler: &Function, timeout: i32) -> JsValue;
+}
+
+async fn yield_now() {
+    // A timer schedules a task, so that rendering and input events can run
+    // before the next slice. A resolved promise would only be a microtask,
+    // which runs before them.
+    let timeout = Promise::new(&mut |resolve, _reject| {
+        set_timeout(&resolve, 0);
+    });
+    let _ = JsFuture::from(timeout).await;
+}
+
+/// Like [`interpret`], but with the source and output encoded as MessagePack
+#[cfg(feature = "msgpack")]
+#[wasm_bindgen]
+pub fn interpret_msgpack(source: &[u8]) -> Vec<u8> {
+    set_panic_hook();
+    let mut vm = new_vm();
+    let output = match Source::from_msgpack(source) {
+        Ok(source) => vm.interpret(source),
+        Err(e) => Output::from_single_error(e),
+    };
+    output
+        .to_msgpack()
+        .or_else(|e| Output::from_single_error(e).to_msgpack())
+        .unwrap_or_default()
+}
+
+/// Like [`interpret`], but with the source and output encoded as CBOR
+#[cfg(feature = "cbor")]
+#[wasm_bindgen]
+pub fn interpret_cbor(source: &[u8]) -> Vec<u8> {
+    set_panic_hook();
+    let mut vm = new_vm();
+    let output = match Source::from_cbor(source) {
+        Ok(source) => vm.interpret(source),
+        Err(e) => Output::from_single_error(e),
     };
-    vm.interpret(source)
+    output
+        .to_cbor()
+        .or_else(|e| Output::from_single_error(e).to_cbor())
+        .unwrap_or_default()
+}
+
+fn parse_source(source: JsValue) -> Result<Source, Error> {
+    serde_wasm_bindgen::from_value(source)
+        .map_err(|e| Error::Compile(format!("JSON parsing error: {e}")))
+}
+
+fn parse_inputs(inputs: JsValue) -> Result<Inputs, Error> {
+    let parse_error =
+        |e: serde_wasm_bindgen::Error| Error::Compile(format!("Inputs parsing error: {e}"));
+    let is_record =
+        inputs.is_object() && !Array::is_array(&inputs) && !inputs.is_instance_of::<js_sys::Map>();
+    if !is_record {
+        return serde_wasm_bindgen::from_value(inputs).map_err(parse_error);
+    }
+    Object::entries(inputs.unchecked_ref())
+        .iter()
+        .map(|entry| {
+            let entry: Array = entry.unchecked_into();
+            let value = entry.get(1);
+            // Typed arrays aren't deserialized as lists. Copying them in one go is
+            // also much faster than element by element.
+            let value = match value.dyn_ref::<Float64Array>() {
+                Some(numbers) => LiteralType::List(
+                    numbers
+                        .to_vec()
+                        .into_iter()
+                        .map(LiteralType::Number)
+                        .collect(),
+                ),
+                None => serde_wasm_bindgen::from_value(value).map_err(parse_error)?,
+            };
+            Ok((entry.get(0).as_string().unwrap_or_default(), value))
+        })
+        .collect()
+}
+
+fn serializer() -> serde_wasm_bindgen::Serializer {
+    serde_wasm_bindgen::Serializer::new().serialize_maps_as_objects(true)
+}
+
+fn to_js(output: &Output) -> JsValue {
+    let Ok(js) = output.serialize(&serializer()) else {
+        return JsValue::from_str("compile error: couldn't serialize result");
+    };
+
+    // Converting each element of a large list to a `JsValue` is slow, so pass lists
+    // of numbers as typed arrays instead. Only possible if they're serialized
+    // as is.
+    let serialized_as_is = output.number_format == NumberFormat::default()
+        && !output.tag_types
+        && output.limits == OutputLimits::default();
+    if serialized_as_is {
+        if let Ok(node_values) = Reflect::get(&js, &JsValue::from_str("nodeValues")) {
+            for (node_id, value) in &output.node_values {
+                if let Some(numbers) = as_numbers(*value) {
+                    let array = Float64Array::from(numbers.as_slice());
+                    let _ = Reflect::set(&node_values, &JsValue::from_str(node_id), &array);
+                }
+            }
+        }
+    }
+    js
+}
+
+/// The elements of a non-empty list made only of numbers
+fn as_numbers(value: Value) -> Option<Vec<f64>> {
+    let values = Vec::<Value>::try_from(value).ok()?;
+    if values.is_empty() {
+        return None;
+    }
+    values
+        .into_iter()
+        .map(|value| match value {
+            Value::Number(n) => Some(n),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A VM whose `random` natives are seeded by the browser. The standard library
+/// has no source of randomness on `wasm32-unknown-unknown`, so VMs seeded by
+/// themselves would return the same numbers on every page load.
+fn new_vm() -> Vm {
+    // Each call gives at least 32 random bits
+    let bits = || (Math::random() * f64::from(u32::MAX)) as u64;
+    let mut vm = Vm::new();
+    vm.set_random_seed((bits() << 32) | bits());
+    vm
 }
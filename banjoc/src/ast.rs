@@ -1,16 +1,27 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use serde::{Deserialize, Deserializer};
 
 use crate::error::Error;
 
 pub type NodeId = String;
+/// A node id interned by [`Ast`], indexing [`Ast::node_ids`]. Compiled code
+/// and outputs refer to nodes by index, so that ids are only cloned once.
+pub type NodeIndex = u32;
 type Nodes = HashMap<String, Node>;
 
 #[derive(Deserialize, Debug)]
 pub struct Source {
     #[serde(deserialize_with = "deserialize_nodes")]
     pub nodes: Nodes,
+    /// Only preview the values of these nodes, so that big graphs don't pay
+    /// for outputs nobody looks at. Nodes these don't depend on aren't even
+    /// compiled. All nodes are previewed if `None`.
+    #[serde(default)]
+    pub outputs: Option<Vec<NodeId>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -28,10 +39,14 @@ pub enum NodeType {
         #[serde(default)]
         args: Vec<NodeId>,
     },
-    #[serde(alias = "fn")]
+    #[serde(alias = "fn", rename_all = "camelCase")]
     FunctionDefinition {
         #[serde(default)]
         args: Vec<NodeId>,
+        /// Arguments to call the function with once the rest of the graph has
+        /// run, so that the nodes inside it can be previewed
+        #[serde(default)]
+        sample_args: Option<Vec<LiteralType>>,
     },
     #[serde(alias = "ref", rename_all = "camelCase")]
     VariableReference {
@@ -60,6 +75,8 @@ pub enum NodeType {
 pub enum LiteralType {
     Bool(bool),
     Nil,
+    /// Only for integers which a number can't represent exactly
+    Int(#[serde(deserialize_with = "deserialize_big_int")] i64),
     Number(f64),
     String(String),
     List(Vec<LiteralType>),
@@ -134,25 +151,74 @@ where
     Ok(map)
 }
 
-impl Source {}
+impl Source {
+    /// Decode a source encoded with MessagePack, with the same structure as
+    /// the JSON encoding
+    #[cfg(feature = "msgpack")]
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, Error> {
+        rmp_serde::from_slice(bytes)
+            .map_err(|e| Error::compile(format!("MessagePack parsing error: {e}")))
+    }
+
+    /// Decode a source encoded with CBOR, with the same structure as the JSON
+    /// encoding
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, Error> {
+        ciborium::from_reader(bytes).map_err(|e| Error::compile(format!("CBOR parsing error: {e}")))
+    }
+}
 
 pub struct Ast<'source> {
     nodes: &'source Nodes,
     arities: HashMap<&'source str, usize>,
     roots: HashMap<&'source str, &'source Node>,
+    outputs: Option<HashSet<&'source str>>,
+    /// The nodes which the outputs depend on, including the roots containing
+    /// them. `None` if every node is needed.
+    needed: Option<HashSet<&'source str>>,
+    /// Every node id, shared by the chunks and outputs compiled from this AST
+    node_ids: Arc<[NodeId]>,
+    node_indices: HashMap<&'source str, NodeIndex>,
 }
 
 impl<'source> Ast<'source> {
     pub fn new(source: &'source Source) -> Self {
         let arities = Self::calculate_arities(&source.nodes);
         let roots = Self::find_roots(&source.nodes);
+        let outputs = source
+            .outputs
+            .as_ref()
+            .map(|outputs| outputs.iter().map(String::as_str).collect());
+        let needed = outputs
+            .as_ref()
+            .map(|outputs| Self::find_needed(&source.nodes, &roots, outputs));
+        let node_ids: Arc<[NodeId]> = source.nodes.keys().cloned().collect();
+        let node_indices = source
+            .nodes
+            .keys()
+            .enumerate()
+            .map(|(index, node_id)| (node_id.as_str(), index as NodeIndex))
+            .collect();
         Self {
             nodes: &source.nodes,
             arities,
             roots,
+            outputs,
+            needed,
+            node_ids,
+            node_indices,
         }
     }
 
+    pub fn node_ids(&self) -> &Arc<[NodeId]> {
+        &self.node_ids
+    }
+
+    /// The index of a node of this AST
+    pub fn node_index(&self, node_id: &str) -> NodeIndex {
+        self.node_indices[node_id]
+    }
+
     pub fn get_node(&self, node_id: &str) -> Result<&Node, Error> {
         self.nodes
             .get(node_id)
@@ -173,10 +239,59 @@ impl<'source> Ast<'source> {
         self.arities.get(fn_node_id)
     }
 
+    /// Whether the value of the node should be previewed
+    pub fn is_output(&self, node_id: &str) -> bool {
+        self.outputs
+            .as_ref()
+            .is_none_or(|outputs| outputs.contains(node_id))
+    }
+
+    /// Whether the node must be compiled to compute the outputs
+    pub fn is_needed(&self, node_id: &str) -> bool {
+        self.needed
+            .as_ref()
+            .is_none_or(|needed| needed.contains(node_id))
+    }
+
     pub fn get_roots(&self) -> impl Iterator<Item = &Node> {
         self.roots.values().map(|n| &**n)
     }
 
+    fn find_needed<'a>(
+        nodes: &'a Nodes,
+        roots: &HashMap<&'a str, &'a Node>,
+        outputs: &HashSet<&'a str>,
+    ) -> HashSet<&'a str> {
+        /// Every node reachable from `stack`, following `edges`
+        fn reachable<'a, I>(
+            nodes: &'a Nodes,
+            mut stack: Vec<&'a str>,
+            edges: impl Fn(&'a Node) -> I,
+        ) -> HashSet<&'a str>
+        where
+            I: Iterator<Item = &'a str>,
+        {
+            let mut visited = HashSet::new();
+            while let Some(node_id) = stack.pop() {
+                if let Some((node_id, node)) = nodes.get_key_value(node_id) {
+                    if visited.insert(node_id.as_str()) {
+                        stack.extend(edges(node));
+                    }
+                }
+            }
+            visited
+        }
+
+        // Outputs nested in the arguments of a root are computed by compiling the root
+        let containing_roots = roots.keys().copied().filter(|root| {
+            reachable(nodes, vec![*root], Node::args)
+                .iter()
+                .any(|node_id| outputs.contains(node_id))
+        });
+        let stack = outputs.iter().copied().chain(containing_roots).collect();
+        reachable(nodes, stack, |node| node.args().chain(node.dependencies()))
+    }
+
     fn find_roots(nodes: &Nodes) -> HashMap<&str, &Node> {
         let mut roots: HashMap<&str, &Node> =
             nodes.iter().map(|(id, n)| (id.as_str(), n)).collect();
@@ -213,3 +328,19 @@ impl<'source> Ast<'source> {
             .collect()
     }
 }
+
+/// Accept only integers beyond ±2^53, so that other numbers stay numbers
+fn deserialize_big_int<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    const MAX_EXACT: u64 = 1 << 53;
+    let n = i64::deserialize(deserializer)?;
+    if n.unsigned_abs() > MAX_EXACT {
+        Ok(n)
+    } else {
+        Err(serde::de::Error::custom(
+            "Integer is exactly representable as a number",
+        ))
+    }
+}
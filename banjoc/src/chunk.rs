@@ -1,15 +1,23 @@
+use std::{collections::HashMap, sync::Arc};
+
 use crate::{
-    ast::{BinaryType, LiteralType, UnaryType},
+    ast::{BinaryType, LiteralType, NodeId, NodeIndex, UnaryType},
     error::{Error, Result},
     gc::Gc,
     obj::List,
-    op_code::{Constant, OpCode},
+    op_code::{Constant, GlobalSlot, OpCode},
     value::Value,
 };
 
 pub struct Chunk {
     pub code: Vec<OpCode>,
     pub constants: Vec<Value>,
+    /// The node each instruction was compiled from, as an index into
+    /// `node_ids`
+    pub nodes: Vec<Option<NodeIndex>>,
+    /// The ids of the nodes of the graph, shared by every chunk compiled from
+    /// it
+    pub node_ids: Arc<[NodeId]>,
 }
 
 impl Chunk {
@@ -17,12 +25,126 @@ impl Chunk {
         Chunk {
             code: vec![],
             constants: vec![],
+            nodes: vec![],
+            node_ids: Arc::new([]),
+        }
+    }
+
+    /// The id of the node which the instruction at `offset` was compiled from
+    pub fn node_id(&self, offset: usize) -> Option<&str> {
+        let index = self.nodes.get(offset).copied().flatten()?;
+        Some(self.node_ids[index as usize].as_str())
+    }
+}
+
+/// A constant of a [`ChunkBuilder`], put on the heap once the chunk is linked
+pub enum UnlinkedConstant<'ast> {
+    Literal(&'ast LiteralType),
+    /// Index of a function definition found while compiling the chunk
+    Function(usize),
+}
+
+/// A chunk being compiled. It doesn't refer to the heap nor to the VM, so
+/// that functions can be compiled on other threads. Until the chunk is linked
+/// into a [`Chunk`], the operands of slot instructions index `globals` and
+/// those of output instructions index `outputs`.
+pub struct ChunkBuilder<'ast> {
+    pub code: Vec<OpCode>,
+    pub constants: Vec<UnlinkedConstant<'ast>>,
+    /// The node each instruction was compiled from
+    pub nodes: Vec<Option<NodeIndex>>,
+    /// The names of the globals used by the chunk
+    pub globals: Vec<&'ast str>,
+    global_indices: HashMap<&'ast str, GlobalSlot>,
+    /// The nodes whose value is output by the chunk
+    pub outputs: Vec<NodeIndex>,
+    /// The node currently being compiled into this chunk
+    current_node: Option<NodeIndex>,
+}
+
+impl<'ast> ChunkBuilder<'ast> {
+    pub fn new() -> Self {
+        Self {
+            code: vec![],
+            constants: vec![],
+            nodes: vec![],
+            globals: vec![],
+            global_indices: HashMap::new(),
+            outputs: vec![],
+            current_node: None,
         }
     }
 
     /// Write the given op code to the chunk
     pub fn emit(&mut self, opcode: OpCode) {
         self.code.push(opcode);
+        self.nodes.push(self.current_node);
+    }
+
+    /// Attribute instructions emitted from now on to the given node. Returns
+    /// the previous node, to be passed to [`Self::exit_node`] once the node is
+    /// compiled.
+    pub fn enter_node(&mut self, node: NodeIndex) -> Option<NodeIndex> {
+        self.current_node.replace(node)
+    }
+
+    pub fn exit_node(&mut self, previous: Option<NodeIndex>) {
+        self.current_node = previous;
+    }
+
+    /// The operand referring to the global with this name until the chunk is
+    /// linked
+    pub fn global(&mut self, name: &'ast str) -> Result<GlobalSlot> {
+        if let Some(index) = self.global_indices.get(name) {
+            return Ok(*index);
+        }
+        let Ok(index) = GlobalSlot::try_from(self.globals.len()) else {
+            return Error::compile_err("Too many globals.");
+        };
+        self.globals.push(name);
+        self.global_indices.insert(name, index);
+        Ok(index)
+    }
+
+    /// The operand referring to the output of this node until the chunk is
+    /// linked
+    pub fn output(&mut self, node: NodeIndex) -> Result<u8> {
+        let Ok(index) = u8::try_from(self.outputs.len()) else {
+            return Error::compile_err("Can't preview the output of more than 255 nodes");
+        };
+        self.outputs.push(node);
+        Ok(index)
+    }
+
+    /// Peephole pass replacing common pairs of instructions with a single
+    /// superinstruction. Only pairs emitted for the same node are fused, so
+    /// that breakpoints, profiles and errors still point at the right node.
+    /// There are no jumps, so no offsets need fixing.
+    pub fn fuse_instructions(&mut self) {
+        let mut code = Vec::with_capacity(self.code.len());
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let next = self.code.get(offset + 1).copied();
+            let same_node = self.nodes.get(offset + 1) == Some(&self.nodes[offset]);
+            match next
+                .filter(|_| same_node)
+                .and_then(|next| fuse(self.code[offset], next))
+            {
+                Some(fused) => {
+                    code.push(fused);
+                    nodes.push(self.nodes[offset]);
+                    offset += 2;
+                }
+                None => {
+                    code.push(self.code[offset]);
+                    nodes.push(self.nodes[offset]);
+                    offset += 1;
+                }
+            }
+        }
+        self.code = code;
+        self.nodes = nodes;
     }
 
     pub fn emit_unary(&mut self, unary_type: &UnaryType) {
@@ -55,59 +177,66 @@ impl Chunk {
         }
     }
 
-    pub fn literal(&mut self, gc: &mut Gc, value: &LiteralType) -> Result<()> {
+    pub fn literal(&mut self, value: &'ast LiteralType) -> Result<()> {
         match value {
             LiteralType::Bool(b) => self.emit(if *b { OpCode::True } else { OpCode::False }),
             LiteralType::Nil => self.emit(OpCode::Nil),
-            LiteralType::Number(n) => self.emit_constant(Value::Number(*n))?,
-            LiteralType::String(s) => {
-                let value = Value::String(gc.intern(s));
-                self.emit_constant(value)?;
-            }
-            LiteralType::List(l) => {
-                let values = l.iter().map(|v| from(v, gc)).collect();
-                let value = Value::List(gc.alloc(List::new(values)));
-                self.emit_constant(value)?;
+            LiteralType::Number(_)
+            | LiteralType::Int(_)
+            | LiteralType::String(_)
+            | LiteralType::List(_) => {
+                let slot = self.make_constant(UnlinkedConstant::Literal(value))?;
+                self.emit(OpCode::Constant(slot));
             }
         }
         Ok(())
     }
 
-    pub fn make_constant(&mut self, value: Value) -> Result<Constant> {
-        let constant = self.add_constant(value);
-        if constant > u8::MAX.into() {
+    pub fn make_constant(&mut self, value: UnlinkedConstant<'ast>) -> Result<Constant> {
+        if self.constants.len() > u8::MAX.into() {
             // TODO we'd want to add another instruction like OpCode::Constant16 which
             // stores the index as a two-byte operand when this limit is hit
             return Error::compile_err("Too many constants in one chunk.");
         }
+        self.constants.push(value);
         Ok(Constant {
-            slot: constant.try_into().unwrap(),
+            slot: (self.constants.len() - 1).try_into().unwrap(),
         })
     }
+}
 
-    fn emit_constant(&mut self, value: Value) -> Result<()> {
-        let slot = self.make_constant(value)?;
-        self.emit(OpCode::Constant(slot));
-        Ok(())
-    }
-
-    fn add_constant(&mut self, value: Value) -> usize {
-        self.constants.push(value);
-        self.constants.len() - 1
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl Default for Chunk {
+impl Default for ChunkBuilder<'_> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-fn from(lit: &LiteralType, gc: &mut Gc) -> Value {
+/// The superinstruction doing the same as `first` followed by `second`, if any
+fn fuse(first: OpCode, second: OpCode) -> Option<OpCode> {
+    match (first, second) {
+        (OpCode::GetLocal(local), OpCode::Call { arg_count }) => {
+            Some(OpCode::GetLocalCall { local, arg_count })
+        }
+        (OpCode::Constant(constant), OpCode::Add) => Some(OpCode::ConstantAdd(constant)),
+        (OpCode::GetSlot(slot), OpCode::Output { output_index }) => {
+            Some(OpCode::GetSlotOutput { slot, output_index })
+        }
+        _ => None,
+    }
+}
+
+pub fn from(lit: &LiteralType, gc: &mut Gc) -> Value {
     match lit {
         LiteralType::Nil => Value::Nil,
         LiteralType::Bool(a) => Value::Bool(*a),
         LiteralType::Number(a) => Value::Number(*a),
+        LiteralType::Int(a) => Value::Int(*a),
         LiteralType::String(a) => Value::String(gc.intern(a)),
         LiteralType::List(a) => {
             let l = a.iter().map(|v| from(v, gc)).collect();
@@ -1,51 +1,273 @@
-use std::{collections::HashSet, mem};
+use std::collections::HashSet;
 
 use crate::{
     ast::{Ast, LiteralType, Node, NodeType},
-    error::{Context, Error, Result},
+    chunk::{self, Chunk, ChunkBuilder, UnlinkedConstant},
+    error::{Context, Diagnostic, Error, Result},
     func_compiler::FuncCompiler,
     gc::{Gc, GcRef},
+    globals::Globals,
     obj::Function,
-    op_code::{Constant, OpCode},
+    op_code::{GlobalSlot, OpCode},
     output::OutputValues,
     value::Value,
 };
 
+/// Compiles a graph in two steps. The graph and each function it defines are
+/// first compiled into [`ChunkBuilder`]s, which don't touch the VM. They're
+/// then linked in a fixed order: their constants are put on the heap, their
+/// globals assigned slots and their outputs indices. With the `parallel`
+/// feature, the functions are compiled concurrently in between.
 pub struct Compiler<'ast> {
     /// The abstract syntax tree to compile
     ast: &'ast Ast<'ast>,
-    /// Needed so we can allocate functions and interned strings
+    /// Needed so we can allocate functions and constants
     gc: &'ast mut Gc,
+    /// Needed so we can assign slots to globals
+    globals: &'ast mut Globals,
     /// Needed so we can inform VM of nodes that expect output values
     output: &'ast mut OutputValues,
-    // TODO: this should be an option
-    compiler: Box<FuncCompiler<'ast>>,
-}
-
-macro_rules! current_chunk {
-    ($self:ident) => {
-        $self.compiler.function.chunk
-    };
 }
 
 impl<'ast> Compiler<'ast> {
     pub fn new(
         ast: &'ast Ast<'ast>,
         gc: &'ast mut Gc,
+        globals: &'ast mut Globals,
         output: &'ast mut OutputValues,
     ) -> Compiler<'ast> {
+        output.set_node_ids(ast.node_ids().clone());
         Self {
-            compiler: Box::new(FuncCompiler::new(None, 0)),
-            gc,
             ast,
+            gc,
+            globals,
             output,
         }
     }
 
     pub fn compile(&mut self) -> GcRef<Function> {
+        let script = Codegen::script(self.ast);
+        let functions = compile_functions(self.ast, &script.functions);
+
+        // Link the functions first, so that the script's constants can refer to them.
+        // Functions which failed to compile are left undefined.
+        let functions: Vec<_> = functions
+            .into_iter()
+            .map(|function| {
+                let compiled = function.errors.is_empty();
+                let function = self.link(function, &[]);
+                compiled.then(|| self.gc.alloc(function))
+            })
+            .collect();
+        let script = self.link(script, &functions);
+        self.gc.alloc(script)
+    }
+
+    /// Report the errors of a compiled function and put it on the heap.
+    /// `functions` are the functions its `Function` constants refer to, `None`
+    /// for those which failed to compile.
+    fn link(
+        &mut self,
+        unlinked: Unlinked<'ast>,
+        functions: &[Option<GcRef<Function>>],
+    ) -> Function {
+        let Unlinked {
+            name,
+            arity,
+            chunk: builder,
+            errors,
+            diagnostics,
+            ..
+        } = unlinked;
+        for error in errors {
+            self.output.add_error(error);
+        }
+        for (node_id, diagnostic) in diagnostics {
+            self.output.add_diagnostic(node_id, diagnostic);
+        }
+
+        let mut slots = Vec::with_capacity(builder.globals.len());
+        for name in &builder.globals {
+            let slot = self.globals.slot(name).node_context(name);
+            slots.push(slot.map_err(|e| self.output.add_error(e)).ok());
+        }
+        let mut outputs = Vec::with_capacity(builder.outputs.len());
+        for node in &builder.outputs {
+            let output_index = self.output.add_node(*node);
+            outputs.push(output_index.map_err(|e| self.output.add_error(e)).ok());
+        }
+        let constants = builder
+            .constants
+            .iter()
+            .map(|constant| match constant {
+                UnlinkedConstant::Literal(literal) => chunk::from(literal, self.gc),
+                UnlinkedConstant::Function(index) => {
+                    functions[*index].map_or(Value::Nil, Value::Function)
+                }
+            })
+            .collect();
+
+        // Instructions whose global or output couldn't be linked are replaced, keeping
+        // the stack balanced
+        let mut code = Vec::with_capacity(builder.code.len());
+        let mut nodes = Vec::with_capacity(builder.nodes.len());
+        let mut instructions = builder.code.iter().zip(&builder.nodes);
+        while let Some((&opcode, &node)) = instructions.next() {
+            let opcode = match opcode {
+                OpCode::SetSlot(slot) => slots[slot as usize].map_or(OpCode::Pop, OpCode::SetSlot),
+                OpCode::GetSlot(slot) => slots[slot as usize].map_or(OpCode::Nil, OpCode::GetSlot),
+                OpCode::Output { output_index } => match outputs[output_index as usize] {
+                    Some(output_index) => OpCode::Output { output_index },
+                    None => continue,
+                },
+                OpCode::GetSlotOutput { slot, output_index } => {
+                    match (slots[slot as usize], outputs[output_index as usize]) {
+                        (Some(slot), Some(output_index)) => {
+                            OpCode::GetSlotOutput { slot, output_index }
+                        }
+                        (Some(slot), None) => OpCode::GetSlot(slot),
+                        (None, _) => OpCode::Nil,
+                    }
+                }
+                OpCode::Function(constant) => {
+                    if let UnlinkedConstant::Function(index) =
+                        builder.constants[constant.slot as usize]
+                    {
+                        if functions[index].is_none() {
+                            // Also skip defining the function
+                            instructions.next();
+                            continue;
+                        }
+                    }
+                    opcode
+                }
+                opcode => opcode,
+            };
+            code.push(opcode);
+            nodes.push(node);
+        }
+
+        let mut function = Function::new(name.map(|name| self.gc.intern(name)), arity);
+        function.chunk = Chunk {
+            code,
+            constants,
+            nodes,
+            node_ids: self.ast.node_ids().clone(),
+        };
+
+        #[cfg(feature = "debug_print_code")]
+        crate::disassembler::disassemble(&function.chunk, name.unwrap_or("<script>"));
+
+        function
+    }
+}
+
+/// Compile each function on its own thread. Collecting keeps the order of the
+/// functions, so that they're linked the same as when compiled sequentially.
+#[cfg(feature = "parallel")]
+fn compile_functions<'ast>(
+    ast: &'ast Ast<'ast>,
+    functions: &[FunctionJob<'ast>],
+) -> Vec<Unlinked<'ast>> {
+    use rayon::prelude::*;
+
+    functions
+        .par_iter()
+        .map(|function| Codegen::compile_function(ast, function))
+        .collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+fn compile_functions<'ast>(
+    ast: &'ast Ast<'ast>,
+    functions: &[FunctionJob<'ast>],
+) -> Vec<Unlinked<'ast>> {
+    functions
+        .iter()
+        .map(|function| Codegen::compile_function(ast, function))
+        .collect()
+}
+
+/// A function definition, compiled separately from the graph
+struct FunctionJob<'ast> {
+    node_id: &'ast str,
+    body: &'ast Node,
+    arity: usize,
+}
+
+/// A function compiled without touching the VM, waiting to be linked
+struct Unlinked<'ast> {
+    name: Option<&'ast str>,
+    arity: usize,
+    chunk: ChunkBuilder<'ast>,
+    /// The function definitions found while compiling, which the `Function`
+    /// constants of `chunk` refer to
+    functions: Vec<FunctionJob<'ast>>,
+    errors: Vec<Error>,
+    diagnostics: Vec<(&'ast str, Diagnostic)>,
+}
+
+/// Generates the code of the graph, or of one of its functions
+struct Codegen<'ast> {
+    /// The abstract syntax tree to compile
+    ast: &'ast Ast<'ast>,
+    compiler: FuncCompiler<'ast>,
+    /// The function definitions found so far, to be compiled separately
+    functions: Vec<FunctionJob<'ast>>,
+    errors: Vec<Error>,
+    diagnostics: Vec<(&'ast str, Diagnostic)>,
+}
+
+macro_rules! current_chunk {
+    ($self:ident) => {
+        $self.compiler.chunk
+    };
+}
+
+impl<'ast> Codegen<'ast> {
+    fn new(ast: &'ast Ast<'ast>, name: Option<&'ast str>, arity: usize) -> Self {
+        let mut this = Self {
+            ast,
+            compiler: FuncCompiler::new(name, arity),
+            functions: vec![],
+            errors: vec![],
+            diagnostics: vec![],
+        };
+        if let Some(name) = name {
+            current_chunk!(this).enter_node(ast.node_index(name));
+        }
+        this
+    }
+
+    /// Compile the body of a function definition
+    fn compile_function(ast: &'ast Ast<'ast>, function: &FunctionJob<'ast>) -> Unlinked<'ast> {
+        let mut this = Self::new(ast, Some(function.node_id), function.arity);
+        this.compiler.begin_scope();
+        // Because we end the compiler completely, there’s no need to close the
+        // lingering outermost scope with end_scope().
+        this.node(function.body)
+            .unwrap_or_else(|e| this.errors.push(e));
+        this.finish()
+    }
+
+    fn finish(mut self) -> Unlinked<'ast> {
+        current_chunk!(self).emit(OpCode::Return);
+        current_chunk!(self).fuse_instructions();
+        Unlinked {
+            name: self.compiler.name,
+            arity: self.compiler.arity,
+            chunk: self.compiler.chunk,
+            functions: self.functions,
+            errors: self.errors,
+            diagnostics: self.diagnostics,
+        }
+    }
+
+    /// Compile the top-level code of the graph
+    fn script(ast: &'ast Ast<'ast>) -> Unlinked<'ast> {
         // Topological sort
         fn visit<'ast>(
-            this: &mut Compiler<'ast>,
+            this: &mut Codegen<'ast>,
             in_branch: &mut HashSet<&'ast str>,
             visited: &mut HashSet<&'ast str>,
             node: &'ast Node,
@@ -65,13 +287,14 @@ impl<'ast> Compiler<'ast> {
                 // referenced
                 if let Ok(child_node) = this.ast.get_node(child) {
                     visit(this, in_branch, visited, child_node)
-                        .unwrap_or_else(|e| this.output.add_error(e));
+                        .unwrap_or_else(|e| this.errors.push(e));
                 }
             }
 
             in_branch.remove(node.id.as_str());
             visited.insert(node.id.as_str());
 
+            let previous_node = current_chunk!(this).enter_node(this.ast.node_index(&node.id));
             match &node.node_type {
                 NodeType::FunctionDefinition { args, .. } => {
                     if args.len() != 1 {
@@ -87,6 +310,13 @@ impl<'ast> Compiler<'ast> {
                     } else {
                         // Treat a function defn with no parameters as a variable defn, effectively
                         // memoizing it
+                        this.diagnostics.push((
+                            &node.id,
+                            Diagnostic::info(
+                                "parameterless_function",
+                                "Without parameters, the function is evaluated once.",
+                            ),
+                        ));
                         this.node_variable_definition(&node.id, args)
                     }
                 }
@@ -103,10 +333,13 @@ impl<'ast> Compiler<'ast> {
                 NodeType::Const { value } => this.node_const_declaration(value, &node.id),
                 _ => Ok(()),
             }
-            .unwrap_or_else(|e| this.output.add_error(e));
+            .unwrap_or_else(|e| this.errors.push(e));
+            current_chunk!(this).exit_node(previous_node);
             Ok(())
         }
 
+        let mut this = Self::new(ast, None, 0);
+
         // Node is in the current topological sort branch.
         // If true and this node is visited during compilation, then graph is cyclic
         let mut in_branch = HashSet::<&str>::new();
@@ -114,37 +347,71 @@ impl<'ast> Compiler<'ast> {
         let mut visited = HashSet::<&str>::new();
 
         // Compile var/fn definitions
-        for node in self.ast.get_roots() {
+        for node in this.ast.get_roots() {
+            if !this.ast.is_needed(&node.id) {
+                continue;
+            }
             match node.node_type {
                 NodeType::VariableDefinition { .. }
                 | NodeType::FunctionDefinition { .. }
                 | NodeType::Const { .. } => {
-                    visit(self, &mut in_branch, &mut visited, node)
-                        .unwrap_or_else(|e| self.output.add_error(e));
+                    visit(&mut this, &mut in_branch, &mut visited, node)
+                        .unwrap_or_else(|e| this.errors.push(e));
                 }
                 _ => {}
             }
         }
         // Also compile disconnected roots AFTER definitions
-        for node in self.ast.get_roots() {
+        for node in this.ast.get_roots() {
+            if !this.ast.is_needed(&node.id) {
+                continue;
+            }
             match node.node_type {
                 NodeType::VariableDefinition { .. }
                 | NodeType::FunctionDefinition { .. }
                 | NodeType::Const { .. } => {}
-                _ => self.node(node).unwrap_or_else(|e| self.output.add_error(e)),
+                NodeType::VariableReference { .. } | NodeType::FunctionCall { .. } => {
+                    this.node(node).unwrap_or_else(|e| this.errors.push(e))
+                }
+                // Nothing uses the value of these, nor is it previewed
+                _ => {
+                    this.diagnostics
+                        .push((&node.id, Diagnostic::warning("unused_node", "Unused node.")));
+                    this.node(node).unwrap_or_else(|e| this.errors.push(e))
+                }
+            }
+        }
+        // Finally call functions with their sample arguments, so that the values
+        // previewed inside them come from these calls
+        for node in this.ast.get_roots() {
+            if !this.ast.is_needed(&node.id) {
+                continue;
+            }
+            if let NodeType::FunctionDefinition {
+                sample_args: Some(sample_args),
+                ..
+            } = &node.node_type
+            {
+                this.sample_call(node, sample_args)
+                    .unwrap_or_else(|e| this.errors.push(e));
             }
         }
 
-        let function = self.pop_func_compiler().function;
-
-        self.gc.alloc(function)
+        this.finish()
     }
 
     fn node(&mut self, node: &'ast Node) -> Result<()> {
+        let previous_node = current_chunk!(self).enter_node(self.ast.node_index(&node.id));
+        let result = self.node_body(node);
+        current_chunk!(self).exit_node(previous_node);
+        result
+    }
+
+    fn node_body(&mut self, node: &'ast Node) -> Result<()> {
         match &node.node_type {
-            NodeType::Literal { value } => current_chunk!(self)
-                .literal(self.gc, value)
-                .node_context(&node.id)?,
+            NodeType::Literal { value } => {
+                current_chunk!(self).literal(value).node_context(&node.id)?
+            }
             NodeType::Param => {
                 if !self.compiler.is_local_scope() {
                     return Error::node_err(
@@ -233,8 +500,7 @@ impl<'ast> Compiler<'ast> {
             if let Some(index) = self.compiler.resolve_local(node_id)? {
                 OpCode::GetLocal(index)
             } else {
-                let constant = self.identifier_constant(node_id)?;
-                OpCode::GetGlobal(constant)
+                OpCode::GetSlot(self.global_slot(node_id)?)
             }
         };
 
@@ -255,21 +521,18 @@ impl<'ast> Compiler<'ast> {
         Ok(())
     }
 
-    fn function(&mut self, body_node: &'ast Node, node_id: &str, arity: usize) -> Result<()> {
-        self.push_func_compiler(node_id, arity);
-        self.compiler.begin_scope();
-
-        self.node(body_node)?;
-
-        // Because we end the compiler completely, there’s no need to close the
-        // lingering outermost scope with end_scope().
-        let FuncCompiler { function, .. } = self.pop_func_compiler();
-        let value = Value::Function(self.gc.alloc(function));
-
+    /// Load a function whose body is compiled separately
+    fn function(&mut self, body: &'ast Node, node_id: &'ast str, arity: usize) -> Result<()> {
+        let index = self.functions.len();
         let constant = current_chunk!(self)
-            .make_constant(value)
+            .make_constant(UnlinkedConstant::Function(index))
             .node_context(node_id)?;
         current_chunk!(self).emit(OpCode::Function(constant));
+        self.functions.push(FunctionJob {
+            node_id,
+            body,
+            arity,
+        });
         Ok(())
     }
 
@@ -284,13 +547,65 @@ impl<'ast> Compiler<'ast> {
         Ok(())
     }
 
+    fn sample_call(&mut self, node: &'ast Node, sample_args: &'ast [LiteralType]) -> Result<()> {
+        let arity = *self.ast.get_arity(&node.id).unwrap_or(&0);
+        if arity != sample_args.len() {
+            return Error::node_err(
+                &node.id,
+                format!(
+                    "Expected {} sample arguments but got {}.",
+                    arity,
+                    sample_args.len()
+                ),
+            );
+        }
+        // Functions without parameters are compiled as variables, so they're
+        // previewed anyway
+        if arity == 0 {
+            return Ok(());
+        }
+
+        let previous_node = current_chunk!(self).enter_node(self.ast.node_index(&node.id));
+        let result = (|| {
+            self.named_variable(&node.id)?;
+            for arg in sample_args {
+                current_chunk!(self).literal(arg).node_context(&node.id)?;
+            }
+            current_chunk!(self).emit(OpCode::Call {
+                arg_count: arity as u8,
+            });
+            current_chunk!(self).emit(OpCode::Pop);
+            Ok(())
+        })();
+        current_chunk!(self).exit_node(previous_node);
+        result
+    }
+
+    /// Whether the function being compiled is called with sample arguments
+    fn has_sample_args(&self) -> bool {
+        self.compiler
+            .name
+            .and_then(|name| self.ast.get_node(name).ok())
+            .is_some_and(|node| {
+                matches!(
+                    node.node_type,
+                    NodeType::FunctionDefinition {
+                        sample_args: Some(_),
+                        ..
+                    }
+                )
+            })
+    }
+
     /// A shortcut node for literal + var declaration
-    fn node_const_declaration(&mut self, value: &LiteralType, node_id: &'ast str) -> Result<()> {
+    fn node_const_declaration(
+        &mut self,
+        value: &'ast LiteralType,
+        node_id: &'ast str,
+    ) -> Result<()> {
         let global = self.declare_variable(node_id);
 
-        current_chunk!(self)
-            .literal(self.gc, value)
-            .node_context(node_id)?;
+        current_chunk!(self).literal(value).node_context(node_id)?;
 
         self.output(node_id)?;
 
@@ -309,15 +624,14 @@ impl<'ast> Compiler<'ast> {
     }
 
     /// Declare existence of local or global variable, not yet assigning a value
-    fn declare_variable(&mut self, node_id: &'ast str) -> Option<Constant> {
-        // At runtime, locals aren’t looked up by name.
-        // There’s no need to stuff the variable’s name into the constant table, so if
-        // the declaration is inside a local scope, we return None instead.
+    fn declare_variable(&mut self, node_id: &'ast str) -> Option<GlobalSlot> {
+        // Locals live on the stack rather than in a global slot, so if the declaration
+        // is inside a local scope, we return None instead.
         if self.compiler.is_local_scope() {
             self.declare_local_variable(node_id).ok()?;
             None
         } else {
-            Some(self.identifier_constant(node_id).ok()?)
+            Some(self.global_slot(node_id).ok()?)
         }
     }
 
@@ -331,9 +645,9 @@ impl<'ast> Compiler<'ast> {
         self.compiler.add_local(node_id)
     }
 
-    fn define_variable(&mut self, global: Option<Constant>) {
+    fn define_variable(&mut self, global: Option<GlobalSlot>) {
         if let Some(global) = global {
-            current_chunk!(self).emit(OpCode::DefineGlobal(global));
+            current_chunk!(self).emit(OpCode::SetSlot(global));
         } else {
             // For local variables, we just save references to values on the stack. No need
             // to store them somewhere else like globals do.
@@ -342,49 +656,18 @@ impl<'ast> Compiler<'ast> {
         }
     }
 
-    fn identifier_constant(&mut self, node_id: &str) -> Result<Constant> {
-        let value = Value::String(self.gc.intern(node_id));
-        current_chunk!(self)
-            .make_constant(value)
-            .node_context(node_id)
-    }
-
-    fn push_func_compiler(&mut self, func_id: &str, arity: usize) {
-        let graph_name = self.gc.intern(func_id);
-        let new_compiler = Box::new(FuncCompiler::new(Some(graph_name), arity));
-        let old_compiler = mem::replace(&mut self.compiler, new_compiler);
-        self.compiler.enclosing = Some(old_compiler);
-    }
-
-    fn pop_func_compiler(&mut self) -> FuncCompiler<'_> {
-        current_chunk!(self).emit(OpCode::Return);
-
-        #[cfg(feature = "debug_print_code")]
-        {
-            let name = self
-                .compiler
-                .function
-                .name
-                .map_or_else(|| "<script>".to_string(), |ls| ls.as_str().to_string());
-
-            crate::disassembler::disassemble(&self.compiler.function.chunk, &name);
-        }
-
-        if let Some(enclosing) = self.compiler.enclosing.take() {
-            let compiler = mem::replace(&mut self.compiler, enclosing);
-            *compiler
-        } else {
-            // TODO no need to put a random object into self.compiler
-            let compiler = mem::replace(&mut self.compiler, Box::new(FuncCompiler::new(None, 0)));
-            *compiler
-        }
+    fn global_slot(&mut self, node_id: &'ast str) -> Result<GlobalSlot> {
+        current_chunk!(self).global(node_id).node_context(node_id)
     }
 
     fn output(&mut self, node_id: &'ast str) -> Result<()> {
         // We can preview the result only if we're in a function which isn't
-        // parameterized
-        if self.compiler.function.arity == 0 {
-            let output_index = self.output.add_node(node_id)?;
+        // parameterized, or which is called with sample arguments
+        let previewable = self.compiler.arity == 0 || self.has_sample_args();
+        if previewable && self.ast.is_output(node_id) {
+            let output_index = current_chunk!(self)
+                .output(self.ast.node_index(node_id))
+                .node_context(node_id)?;
             current_chunk!(self).emit(OpCode::Output { output_index });
         }
 
@@ -0,0 +1,201 @@
+use crate::value::NumberFormat;
+
+/// Settings used when constructing a [`Vm`](crate::vm::Vm).
+///
+/// Start from [`VmConfig::default`] and override the fields you need:
+///
+/// ```
+/// use banjoc::{config::VmConfig, vm::Vm};
+///
+/// let vm = Vm::with_config(VmConfig {
+///     frames_max: 16,
+///     ..VmConfig::default()
+/// });
+/// ```
+#[derive(Clone, Debug)]
+pub struct VmConfig {
+    /// Maximum depth of nested function calls
+    pub frames_max: usize,
+    /// Maximum number of values on the value stack, shared by all call frames
+    pub stack_max: usize,
+    /// Measure how long each output node and function call takes and report
+    /// it in [`Output::timings`](crate::output::Output::timings). Relies on
+    /// `std::time::Instant`, which isn't available on `wasm32-unknown-unknown`.
+    pub record_timings: bool,
+    /// Count executions and cumulated time per opcode and per function,
+    /// retrievable with [`Vm::take_profile`](crate::vm::Vm::take_profile).
+    /// Slows execution down considerably and, like `record_timings`, isn't
+    /// available on `wasm32-unknown-unknown`.
+    pub profile: bool,
+    /// Report the size of the heap and the number of garbage collections in
+    /// [`Output::memory`](crate::output::Output::memory)
+    pub record_memory: bool,
+    /// Abort the run with a runtime error when the garbage collected heap
+    /// grows beyond this many bytes, so that untrusted graphs can't exhaust
+    /// the host's memory. `None` means no limit.
+    pub max_heap_bytes: Option<usize>,
+    /// When the garbage collector runs
+    pub gc: GcConfig,
+    /// How long the definitions of a graph, and the strings interned for
+    /// their names, are kept
+    pub globals_scope: GlobalsScope,
+    /// Which groups of natives are registered. Natives left out are simply
+    /// undefined, so graphs calling them fail with a runtime error.
+    pub capabilities: Capabilities,
+    /// How numbers are rendered in outputs and by the `string` native
+    pub number_format: NumberFormat,
+    /// Serialize each of
+    /// [`Output::node_values`](crate::output::Output::node_values)
+    /// as `{ "type": "number", "value": 3 }` instead of the bare value, so
+    /// that frontends can tell nil from missing and `"3"` from `3`
+    pub tag_types: bool,
+    /// How much of each of
+    /// [`Output::node_values`](crate::output::Output::node_values) is
+    /// serialized
+    pub output_limits: OutputLimits,
+    /// Seed for the `random` natives, making runs reproducible. `None` seeds
+    /// them differently for every VM, except on `wasm32-unknown-unknown`,
+    /// which has no source of randomness for the VM to seed them with.
+    pub random_seed: Option<u64>,
+}
+
+impl VmConfig {
+    const FRAMES_MAX: usize = 64;
+    /// Each frame can address at most this many stack slots
+    const FRAME_SLOTS: usize = u8::MAX as usize + 1;
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            frames_max: Self::FRAMES_MAX,
+            stack_max: Self::FRAMES_MAX * Self::FRAME_SLOTS,
+            record_timings: false,
+            profile: false,
+            record_memory: false,
+            max_heap_bytes: None,
+            gc: GcConfig::default(),
+            globals_scope: GlobalsScope::default(),
+            capabilities: Capabilities::default(),
+            number_format: NumberFormat::default(),
+            tag_types: false,
+            output_limits: OutputLimits::default(),
+            random_seed: None,
+        }
+    }
+}
+
+/// Caps on the size of serialized output values, so that a node producing a
+/// huge list doesn't produce a huge output. Values cut short are serialized
+/// as `{ "value": ..., "truncated": true }`. Nothing is capped by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutputLimits {
+    /// Serialize at most this many elements of each list, and entries of each
+    /// map
+    pub max_list_elements: Option<usize>,
+    /// Serialize at most this many bytes of each string, cut at a character
+    /// boundary
+    pub max_string_bytes: Option<usize>,
+}
+
+impl OutputLimits {
+    /// How many of `len` elements are serialized
+    pub(crate) fn list_len(&self, len: usize) -> usize {
+        self.max_list_elements.map_or(len, |max| len.min(max))
+    }
+
+    /// The part of `s` which is serialized
+    pub(crate) fn truncate_str<'a>(&self, s: &'a str) -> &'a str {
+        let Some(mut end) = self.max_string_bytes.filter(|max| *max < s.len()) else {
+            return s;
+        };
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        &s[..end]
+    }
+}
+
+/// Natives which reach outside the graph. Everything is allowed by default;
+/// hosts running untrusted graphs can turn off what they don't want exposed.
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    /// Natives reading the system clock, such as `clock`
+    pub allow_time: bool,
+    /// Natives producing random numbers
+    pub allow_random: bool,
+    /// Natives reading data provided by the host with
+    /// [`Vm::set_host_data`](crate::vm::Vm::set_host_data), such as `data`
+    pub allow_host_data: bool,
+}
+
+impl Capabilities {
+    /// Only pure natives, whose results depend on nothing but their arguments
+    #[must_use]
+    pub fn none() -> Self {
+        Self {
+            allow_time: false,
+            allow_random: false,
+            allow_host_data: false,
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            allow_time: true,
+            allow_random: true,
+            allow_host_data: true,
+        }
+    }
+}
+
+/// Objects are freed by the garbage collector once unreachable, but the
+/// globals defined by a graph keep their values reachable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GlobalsScope {
+    /// Globals stay defined until [`Vm::reset`](crate::vm::Vm::reset), so
+    /// later graphs can refer to them
+    #[default]
+    Vm,
+    /// Globals are forgotten whenever a new run starts, so that long-lived VMs
+    /// compiling many graphs don't grow unboundedly
+    Run,
+}
+
+/// Tuning for the garbage collector
+#[derive(Clone, Debug)]
+pub struct GcConfig {
+    /// Collect once the heap grows beyond this many bytes
+    pub initial_threshold: usize,
+    /// After each collection, the next one happens once the heap grows to
+    /// this multiple of the bytes which survived
+    pub grow_factor: f64,
+    /// Never collect automatically. Worth it for short-lived runs, whose
+    /// heap is freed all at once when the VM is dropped.
+    /// [`Vm::collect_garbage`](crate::vm::Vm::collect_garbage) still works.
+    pub disabled: bool,
+    /// Mark incrementally, tracing at most this many objects per allocation
+    /// once a collection is due, instead of pausing for a whole collection at
+    /// once. Hosts can also drive collections between steps with
+    /// [`Vm::collect_garbage_step`](crate::vm::Vm::collect_garbage_step).
+    pub incremental_step: Option<usize>,
+    /// Never collect automatically. Instead, everything allocated by a run is
+    /// freed at once when the next run starts, or when a program is compiled
+    /// with [`Vm::compile`](crate::vm::Vm::compile). The values of an
+    /// [`Output`](crate::output::Output) are only valid until then.
+    pub arena: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            initial_threshold: 1024 * 1024,
+            grow_factor: 2.0,
+            disabled: false,
+            incremental_step: None,
+            arena: false,
+        }
+    }
+}
@@ -1,70 +1,121 @@
+use std::fmt::Write;
+
 use crate::{
     chunk::Chunk,
-    op_code::{Constant, OpCode},
+    obj::Function,
+    op_code::{Constant, GlobalSlot, OpCode},
+    value::Value,
 };
 
 #[cfg(feature = "debug_print_code")]
 pub fn disassemble(chunk: &Chunk, name: &str) {
-    println!("== {name} ==");
-    let mut offset = 0;
-    while offset < chunk.code.len() {
-        offset = disassemble_instruction(chunk, offset);
-    }
+    let mut out = String::new();
+    disassemble_chunk(&mut out, chunk, name);
+    print!("{out}");
 }
 
 #[cfg(feature = "debug_trace_execution")]
 pub fn disassemble_instruction_ptr(chunk: &Chunk, ip: *const OpCode) -> usize {
     let offset = unsafe { ip.offset_from(chunk.code.as_ptr()) as usize };
-    disassemble_instruction(chunk, offset)
+    let mut out = String::new();
+    let next = disassemble_instruction(&mut out, chunk, offset);
+    print!("{out}");
+    next
 }
 
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-    print!("{offset:04} ");
+/// Disassemble the given function followed by every function it defines
+pub fn disassemble_function(out: &mut String, function: &Function) {
+    let name = function
+        .name
+        .as_ref()
+        .map_or("<script>", |name| name.as_str());
+    disassemble_chunk(out, &function.chunk, name);
+    for constant in &function.chunk.constants {
+        if let Value::Function(function) = constant {
+            out.push('\n');
+            disassemble_function(out, function);
+        }
+    }
+}
+
+fn disassemble_chunk(out: &mut String, chunk: &Chunk, name: &str) {
+    let _ = writeln!(out, "== {name} ==");
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(out, chunk, offset);
+    }
+}
+
+fn disassemble_instruction(out: &mut String, chunk: &Chunk, offset: usize) -> usize {
+    let _ = write!(out, "{offset:04} ");
 
     let instruction = chunk.code[offset];
     match instruction {
-        OpCode::Constant(constant) => constant_instruction("OP_CONSTANT", chunk, offset, constant),
-        OpCode::Negate => simple_instruction("OP_NEGATE", offset),
-        OpCode::Return => simple_instruction("OP_RETURN", offset),
-        OpCode::Add => simple_instruction("OP_ADD", offset),
-        OpCode::Subtract => simple_instruction("OP_SUBTRACT", offset),
-        OpCode::Multiply => simple_instruction("OP_MULTIPLY", offset),
-        OpCode::Divide => simple_instruction("OP_DIVIDE", offset),
-        OpCode::Nil => simple_instruction("OP_NIL", offset),
-        OpCode::True => simple_instruction("OP_TRUE", offset),
-        OpCode::False => simple_instruction("OP_FALSE", offset),
-        OpCode::Not => simple_instruction("OP_NOT", offset),
-        OpCode::Equal => simple_instruction("OP_EQUAL", offset),
-        OpCode::Greater => simple_instruction("OP_GREATER", offset),
-        OpCode::Less => simple_instruction("OP_LESS", offset),
-        OpCode::Pop => simple_instruction("OP_POP", offset),
-        OpCode::DefineGlobal(constant) => {
-            constant_instruction("OP_DEFINE_GLOBAL", chunk, offset, constant)
+        OpCode::Constant(constant) => {
+            constant_instruction(out, "OP_CONSTANT", chunk, offset, constant)
+        }
+        OpCode::Negate => simple_instruction(out, "OP_NEGATE", offset),
+        OpCode::Return => simple_instruction(out, "OP_RETURN", offset),
+        OpCode::Add => simple_instruction(out, "OP_ADD", offset),
+        OpCode::Subtract => simple_instruction(out, "OP_SUBTRACT", offset),
+        OpCode::Multiply => simple_instruction(out, "OP_MULTIPLY", offset),
+        OpCode::Divide => simple_instruction(out, "OP_DIVIDE", offset),
+        OpCode::Nil => simple_instruction(out, "OP_NIL", offset),
+        OpCode::True => simple_instruction(out, "OP_TRUE", offset),
+        OpCode::False => simple_instruction(out, "OP_FALSE", offset),
+        OpCode::Not => simple_instruction(out, "OP_NOT", offset),
+        OpCode::Equal => simple_instruction(out, "OP_EQUAL", offset),
+        OpCode::Greater => simple_instruction(out, "OP_GREATER", offset),
+        OpCode::Less => simple_instruction(out, "OP_LESS", offset),
+        OpCode::Pop => simple_instruction(out, "OP_POP", offset),
+        OpCode::SetSlot(slot) => slot_instruction(out, "OP_SET_SLOT", offset, slot),
+        OpCode::GetSlot(slot) => slot_instruction(out, "OP_GET_SLOT", offset, slot),
+        OpCode::GetLocal(index) => byte_instruction(out, "OP_GET_LOCAL", offset, index),
+        OpCode::Call { arg_count } => byte_instruction(out, "OP_CALL", offset, arg_count),
+        OpCode::Function(constant) => {
+            constant_instruction(out, "OP_FUNCTION", chunk, offset, constant)
+        }
+        OpCode::Output { output_index } => byte_instruction(out, "OP_OUTPUT", offset, output_index),
+        OpCode::GetLocalCall { local, arg_count } => {
+            let _ = writeln!(out, "{:-16} {local:4} {arg_count:4}", instruction.name());
+            offset + 1
         }
-        OpCode::GetGlobal(constant) => {
-            constant_instruction("OP_GET_GLOBAL", chunk, offset, constant)
+        OpCode::ConstantAdd(constant) => {
+            constant_instruction(out, "OP_CONSTANT_ADD", chunk, offset, constant)
+        }
+        OpCode::GetSlotOutput { slot, output_index } => {
+            let _ = writeln!(out, "{:-16} {slot:4} {output_index:4}", instruction.name());
+            offset + 1
         }
-        OpCode::GetLocal(index) => byte_instruction("OP_GET_LOCAL", offset, index),
-        OpCode::Call { arg_count } => byte_instruction("OP_CALL", offset, arg_count),
-        OpCode::Function(constant) => constant_instruction("OP_FUNCTION", chunk, offset, constant),
-        OpCode::Output { output_index } => byte_instruction("OP_OUTPUT", offset, output_index),
     }
 }
 
-fn simple_instruction(name: &str, offset: usize) -> usize {
-    println!("{name}");
+fn simple_instruction(out: &mut String, name: &str, offset: usize) -> usize {
+    let _ = writeln!(out, "{name}");
     offset + 1
 }
 
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize, constant: Constant) -> usize {
-    println!(
+fn constant_instruction(
+    out: &mut String,
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+    constant: Constant,
+) -> usize {
+    let _ = writeln!(
+        out,
         "{:-16} {:4} '{:?}'",
         name, constant.slot, chunk.constants[constant.slot as usize]
     );
     offset + 1
 }
 
-fn byte_instruction(name: &str, offset: usize, slot: u8) -> usize {
-    println!("{name:-16} {slot:4}");
+fn slot_instruction(out: &mut String, name: &str, offset: usize, slot: GlobalSlot) -> usize {
+    let _ = writeln!(out, "{name:-16} {slot:4}");
+    offset + 1
+}
+
+fn byte_instruction(out: &mut String, name: &str, offset: usize, slot: u8) -> usize {
+    let _ = writeln!(out, "{name:-16} {slot:4}");
     offset + 1
 }
@@ -1,4 +1,9 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+};
+
+use serde::Serialize;
 
 use crate::ast::NodeId;
 
@@ -6,7 +11,7 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     Compile(String),
-    /// A compile error with a known node
+    /// A compile or runtime error with a known node
     Node((NodeId, String)),
     Runtime(String),
 }
@@ -39,6 +44,64 @@ impl Error {
     }
 }
 
+/// How serious a [`Diagnostic`] is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The node couldn't be compiled or run
+    Error,
+    /// The node runs, but probably not as intended
+    Warning,
+    /// Advice which doesn't need acting on
+    Info,
+}
+
+/// A problem with a node, reported without halting compilation or execution
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Identifies the kind of problem, such as `unused_node`, so that tools
+    /// don't have to match messages
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error<M: Into<String>>(code: &'static str, msg: M) -> Self {
+        Self {
+            severity: Severity::Error,
+            code,
+            message: msg.into(),
+        }
+    }
+    pub fn warning<M: Into<String>>(code: &'static str, msg: M) -> Self {
+        Self {
+            severity: Severity::Warning,
+            code,
+            message: msg.into(),
+        }
+    }
+    pub fn info<M: Into<String>>(code: &'static str, msg: M) -> Self {
+        Self {
+            severity: Severity::Info,
+            code,
+            message: msg.into(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Compile(msg) => write!(f, "Compile error: {msg}"),
+            Self::Node((node_id, msg)) => write!(f, "Error in node '{node_id}': {msg}"),
+            Self::Runtime(msg) => write!(f, "Runtime error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 pub trait Context<T> {
     /// Wrap the error value with additional context.
     fn node_context(self, node_id: &str) -> Result<T>;
@@ -1,15 +1,16 @@
 use crate::{
+    chunk::ChunkBuilder,
     error::{Error, Result},
-    gc::GcRef,
-    obj::{BanjoString, Function},
     op_code::LocalIndex,
 };
 
-/// A compiler for a graph, including the implicit top-level function, <script>
+/// A compiler for a function of a graph, or for the implicit top-level
+/// function, <script>
 pub struct FuncCompiler<'ast> {
-    // TODO can this be improved without using the heap?
-    pub enclosing: Option<Box<FuncCompiler<'ast>>>,
-    pub function: Function,
+    /// The id of the function definition node, `None` for <script>
+    pub name: Option<&'ast str>,
+    pub arity: usize,
+    pub chunk: ChunkBuilder<'ast>,
     /// Keeps track of which stack slots are associated with which local
     /// variables or temporaries
     locals: Vec<Local<'ast>>,
@@ -20,7 +21,7 @@ pub struct FuncCompiler<'ast> {
 impl<'ast> FuncCompiler<'ast> {
     const MAX_LOCAL_COUNT: usize = u8::MAX as usize + 1;
 
-    pub fn new(function_name: Option<GcRef<BanjoString>>, arity: usize) -> Self {
+    pub fn new(name: Option<&'ast str>, arity: usize) -> Self {
         let mut locals = Vec::with_capacity(Self::MAX_LOCAL_COUNT);
         // Claim stack slot zero for the VM's own internal use
         locals.push(Local {
@@ -29,9 +30,10 @@ impl<'ast> FuncCompiler<'ast> {
         });
 
         Self {
-            enclosing: None,
+            name,
+            arity,
+            chunk: ChunkBuilder::new(),
             locals,
-            function: Function::new(function_name, arity),
             scope_depth: 0,
         }
     }
@@ -6,19 +6,53 @@ use std::{
 };
 
 use crate::{
-    obj::{hash_string, BanjoString, Function, List, NativeFunction, ObjectType},
+    config::GcConfig,
+    obj::{
+        hash_string, BanjoString, Buffer, Function, List, Map, MapKey, NativeFunction, ObjectType,
+        WeakRef,
+    },
+    op_code::OpCode,
+    output::MemoryStats,
     table::Table,
     value::Value,
+    vm::HeapObject,
 };
 
 struct HeaderPtr(NonNull<ObjHeader>);
 impl HeaderPtr {
+    /// Size of the object including the buffers it owns. Objects aren't
+    /// mutated after being allocated, so this doesn't change over their
+    /// lifetime.
     fn size_of_val(self) -> usize {
         match self.obj_type {
-            ObjectType::String => mem::size_of::<BanjoString>(),
-            ObjectType::NativeFunction => mem::size_of::<NativeFunction>(),
-            ObjectType::Function => mem::size_of::<Function>(),
-            ObjectType::List => mem::size_of::<List>(),
+            ObjectType::String => {
+                let string = self.transmute::<BanjoString>();
+                mem::size_of::<BanjoString>() + string.as_str().len()
+            }
+            ObjectType::NativeFunction => {
+                let native = self.transmute::<NativeFunction>();
+                mem::size_of::<NativeFunction>() + native.name.len()
+            }
+            ObjectType::Function => {
+                let function = self.transmute::<Function>();
+                mem::size_of::<Function>()
+                    + function.chunk.code.len() * mem::size_of::<OpCode>()
+                    + function.chunk.constants.len() * mem::size_of::<Value>()
+            }
+            ObjectType::List => {
+                let list = self.transmute::<List>();
+                mem::size_of::<List>() + list.values.len() * mem::size_of::<Value>()
+            }
+            ObjectType::WeakRef => mem::size_of::<WeakRef>(),
+            ObjectType::Map => {
+                let map = self.transmute::<Map>();
+                mem::size_of::<Map>()
+                    + map.entries.len() * (mem::size_of::<MapKey>() + mem::size_of::<Value>())
+            }
+            ObjectType::Buffer => {
+                let buffer = self.transmute::<Buffer>();
+                mem::size_of::<Buffer>() + buffer.data.len()
+            }
         }
     }
 
@@ -33,6 +67,9 @@ impl HeaderPtr {
             ObjectType::NativeFunction => self.transmute::<NativeFunction>().drop_ptr(),
             ObjectType::Function => self.transmute::<Function>().drop_ptr(),
             ObjectType::List => self.transmute::<List>().drop_ptr(),
+            ObjectType::WeakRef => self.transmute::<WeakRef>().drop_ptr(),
+            ObjectType::Map => self.transmute::<Map>().drop_ptr(),
+            ObjectType::Buffer => self.transmute::<Buffer>().drop_ptr(),
         }
     }
 }
@@ -66,6 +103,9 @@ impl Debug for HeaderPtr {
             ObjectType::NativeFunction => self.transmute::<NativeFunction>().fmt(f),
             ObjectType::Function => self.transmute::<Function>().fmt(f),
             ObjectType::List => self.transmute::<List>().fmt(f),
+            ObjectType::WeakRef => self.transmute::<WeakRef>().fmt(f),
+            ObjectType::Map => self.transmute::<Map>().fmt(f),
+            ObjectType::Buffer => self.transmute::<Buffer>().fmt(f),
         }
     }
 }
@@ -92,7 +132,8 @@ impl<T: Debug> GcRef<T> {
         {
             println!("{:?} free {}", self.pointer.as_ptr(), self.deref());
         }
-        unsafe { std::ptr::drop_in_place(self.pointer.as_ptr()) }
+        // Reclaim the box created by Gc::alloc, dropping the object along with it
+        unsafe { drop(Box::from_raw(self.pointer.as_ptr())) }
     }
 
     fn header(self) -> HeaderPtr {
@@ -100,7 +141,7 @@ impl<T: Debug> GcRef<T> {
     }
 
     fn size_of_val(self) -> usize {
-        mem::size_of_val(&*self)
+        self.header().size_of_val()
     }
 }
 
@@ -178,23 +219,77 @@ pub struct Gc {
     /// Table of interned strings
     strings: Table,
     gray_stack: Vec<HeaderPtr>,
+    /// Every weak reference on the heap, so their targets can be cleared
+    /// when freed
+    weak_refs: Vec<GcRef<WeakRef>>,
     bytes_allocated: usize,
+    /// Largest `bytes_allocated` since the last call to `reset_stats`
+    peak_bytes: usize,
+    /// Collections finished since the last call to `reset_stats`
+    collections: usize,
     next_gc: usize,
+    config: GcConfig,
+    /// The heap may not grow beyond this many bytes
+    max_bytes: Option<usize>,
+    /// Set when an allocation took the heap over `max_bytes`
+    exhausted: bool,
+    /// An incremental collection is in progress
+    marking: bool,
+    /// In arena mode, the newest object which outlives the current run
+    region_start: Option<HeaderPtr>,
 }
 
 impl Gc {
-    const HEAP_GROW_FACTOR: usize = 2;
-
     pub fn new() -> Self {
+        Self::with_config(GcConfig::default(), None)
+    }
+
+    pub fn with_config(config: GcConfig, max_bytes: Option<usize>) -> Self {
         Self {
             first: None,
             strings: Table::new(),
             gray_stack: Vec::new(),
+            weak_refs: Vec::new(),
             bytes_allocated: 0,
-            next_gc: 1024 * 1024,
+            peak_bytes: 0,
+            collections: 0,
+            next_gc: config.initial_threshold,
+            config,
+            max_bytes,
+            exhausted: false,
+            marking: false,
+            region_start: None,
         }
     }
 
+    /// Whether an allocation exceeded the heap limit since the last call.
+    pub fn take_exhausted(&mut self) -> bool {
+        mem::take(&mut self.exhausted)
+    }
+
+    /// Start measuring the heap usage reported by [`stats`](Self::stats) anew
+    pub fn reset_stats(&mut self) {
+        self.peak_bytes = self.bytes_allocated;
+        self.collections = 0;
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            bytes_allocated: self.bytes_allocated,
+            peak_heap: self.peak_bytes,
+            collections: self.collections,
+        }
+    }
+
+    pub fn max_bytes(&self) -> Option<usize> {
+        self.max_bytes
+    }
+
+    pub fn is_over_limit(&self) -> bool {
+        self.max_bytes
+            .is_some_and(|max_bytes| self.bytes_allocated > max_bytes)
+    }
+
     pub fn intern(&mut self, string: &str) -> GcRef<BanjoString> {
         let hash = hash_string(string);
 
@@ -233,6 +328,10 @@ impl Gc {
         obj.next = self.first.take();
         self.first = Some(obj);
 
+        if let ObjectType::WeakRef = obj.obj_type {
+            self.weak_refs.push(obj.transmute());
+        }
+
         #[cfg(feature = "debug_log_gc")]
         {
             println!(
@@ -243,11 +342,82 @@ impl Gc {
             );
         }
 
+        if self.marking {
+            // Objects created during marking may be the only ones referencing objects which
+            // haven't been traced yet, so they must be traced too
+            obj.mark();
+            self.gray_stack.push(obj);
+        }
+
         self.bytes_allocated += pointer.size_of_val();
+        self.peak_bytes = self.peak_bytes.max(self.bytes_allocated);
+        if self.is_over_limit() {
+            // The object still has to be handed out; it's up to the VM to abort
+            self.exhausted = true;
+        }
 
         pointer
     }
 
+    /// Describe every object on the heap, newest first
+    pub fn dump(&self) -> Vec<HeapObject> {
+        const MAX_REPR_CHARS: usize = 80;
+
+        let mut objects = vec![];
+        let mut maybe_obj = self.first;
+        while let Some(obj) = maybe_obj {
+            let mut repr = format!("{obj:?}");
+            if let Some((end, _)) = repr.char_indices().nth(MAX_REPR_CHARS) {
+                repr.truncate(end);
+                repr.push('…');
+            }
+            objects.push(HeapObject {
+                kind: obj.obj_type.name(),
+                size: obj.size_of_val(),
+                repr,
+            });
+            maybe_obj = obj.next;
+        }
+        objects
+    }
+
+    /// Whether `object` is still on the heap
+    #[cfg(test)]
+    pub fn is_live<T: Debug>(&self, object: GcRef<T>) -> bool {
+        let mut maybe_obj = self.first;
+        while let Some(obj) = maybe_obj {
+            if obj.0 == object.header().0 {
+                return true;
+            }
+            maybe_obj = obj.next;
+        }
+        false
+    }
+
+    pub fn is_marking(&self) -> bool {
+        self.marking
+    }
+
+    /// Begin an incremental collection. The roots must have been marked.
+    pub fn start_marking(&mut self) {
+        self.marking = true;
+    }
+
+    /// Trace at most `max_objects` gray objects. Returns true once there's
+    /// nothing left to trace, at which point the roots should be marked again
+    /// and the collection finished with
+    /// [`collect_garbage`](Self::collect_garbage).
+    pub fn mark_step(&mut self, max_objects: usize) -> bool {
+        for _ in 0..max_objects {
+            let Some(obj) = self.gray_stack.pop() else {
+                break;
+            };
+            self.blacken_object(obj);
+        }
+        self.gray_stack.is_empty()
+    }
+
+    /// Finish the collection, once the roots have been marked
     pub fn collect_garbage(&mut self) {
         #[cfg(feature = "debug_log_gc")]
         let before = self.bytes_allocated;
@@ -256,10 +426,13 @@ impl Gc {
 
         self.trace_references();
         self.strings.remove_white();
+        self.clear_weak_refs(false);
         self.sweep();
+        self.marking = false;
+        self.collections += 1;
 
         if self.bytes_allocated > 0 {
-            self.next_gc = self.bytes_allocated * Self::HEAP_GROW_FACTOR;
+            self.next_gc = (self.bytes_allocated as f64 * self.config.grow_factor) as usize;
         }
 
         #[cfg(feature = "debug_log_gc")]
@@ -288,8 +461,11 @@ impl Gc {
 
         // Mark all outgoing references
         match obj.obj_type {
-            ObjectType::String | ObjectType::NativeFunction => {
-                // No outgoing references
+            ObjectType::String
+            | ObjectType::NativeFunction
+            | ObjectType::WeakRef
+            | ObjectType::Buffer => {
+                // No outgoing (strong) references
             }
             ObjectType::Function => {
                 let mut function = obj.transmute::<Function>();
@@ -301,18 +477,72 @@ impl Gc {
                 }
             }
             ObjectType::List => {
-                let list = obj.transmute::<List>();
-                for value in &list.values {
-                    match value {
-                        Value::List(l) => self.blacken_object(l.header()),
-                        Value::Function(f) => self.blacken_object(f.header()),
-                        _ => {}
+                let mut list = obj.transmute::<List>();
+                for value in &mut list.values {
+                    value.mark_gray(self);
+                }
+            }
+            ObjectType::Map => {
+                let mut map = obj.transmute::<Map>();
+                for (key, value) in &mut map.entries {
+                    if let MapKey::String(mut key) = key {
+                        key.mark_gray(self);
                     }
+                    value.mark_gray(self);
                 }
             }
         }
     }
 
+    /// Forget weak references which are about to be freed, and clear the
+    /// targets which are about to be freed. Those are the objects whose mark
+    /// equals `doomed`.
+    fn clear_weak_refs(&mut self, doomed: bool) {
+        self.weak_refs.retain_mut(|weak_ref| {
+            if weak_ref.is_marked() == doomed {
+                return false;
+            }
+            if weak_ref.target.is_marked() == Some(doomed) {
+                weak_ref.target = Value::Nil;
+            }
+            true
+        });
+    }
+
+    /// Make every object allocated so far outlive the current region
+    pub fn start_region(&mut self) {
+        self.region_start = self.first;
+    }
+
+    /// Free every object allocated since [`start_region`](Self::start_region),
+    /// without tracing. Nothing outside the region may still reference them.
+    pub fn free_region(&mut self) {
+        debug_assert!(!self.marking);
+
+        // Flag the objects in the region so that references to them can be found
+        let mut maybe_obj = self.first;
+        while let Some(mut obj) = maybe_obj {
+            if self.region_start.is_some_and(|start| start.0 == obj.0) {
+                break;
+            }
+            obj.mark();
+            if let ObjectType::String = obj.obj_type {
+                self.strings.remove(obj.transmute());
+            }
+            maybe_obj = obj.next;
+        }
+        self.clear_weak_refs(true);
+
+        while let Some(mut obj) = self.first {
+            if self.region_start.is_some_and(|start| start.0 == obj.0) {
+                break;
+            }
+            self.first = obj.next;
+            self.bytes_allocated -= obj.size_of_val();
+            obj.drop_ptr();
+        }
+    }
+
     fn sweep(&mut self) {
         let mut prev = None;
         let mut maybe_obj = self.first;
@@ -330,6 +560,10 @@ impl Gc {
                 // Unlink and free unmarked (white) objects
                 let mut unreached = obj;
                 maybe_obj = obj.next;
+                if self.region_start.is_some_and(|start| start.0 == obj.0) {
+                    // The next object becomes the newest one outliving the region
+                    self.region_start = maybe_obj;
+                }
                 if let Some(mut prev) = prev {
                     prev.next = maybe_obj;
                 } else {
@@ -351,7 +585,11 @@ impl Gc {
     }
     #[cfg(not(feature = "debug_stress_gc"))]
     pub fn should_gc(&self) -> bool {
-        self.bytes_allocated > self.next_gc
+        if self.config.disabled || self.config.arena {
+            return false;
+        }
+        // Try to make room before giving up on a heap which hit its limit
+        self.bytes_allocated > self.next_gc || self.is_over_limit()
     }
 }
 
@@ -361,10 +599,22 @@ impl Default for Gc {
     }
 }
 
+impl Drop for Gc {
+    fn drop(&mut self) {
+        let mut maybe_obj = self.first.take();
+        while let Some(mut obj) = maybe_obj {
+            maybe_obj = obj.next;
+            obj.drop_ptr();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::rc::Rc;
+
     use super::*;
-    use crate::obj::List;
+    use crate::obj::{List, NativeFn};
 
     #[test]
     fn as_obj_transmute() {
@@ -441,8 +691,45 @@ mod tests {
     fn size_of() {
         let mut gc = Gc::new();
         let ls = BanjoString::new("first".to_string());
-        let size = std::mem::size_of_val(&ls);
+        let size = std::mem::size_of_val(&ls) + "first".len();
         gc.alloc(ls);
         assert_eq!(gc.first.unwrap().size_of_val(), size);
     }
+
+    #[test]
+    #[cfg(not(feature = "debug_stress_gc"))]
+    fn configured_threshold() {
+        let config = GcConfig {
+            initial_threshold: 10,
+            ..GcConfig::default()
+        };
+        let mut gc = Gc::with_config(config.clone(), None);
+        assert!(!gc.should_gc());
+        gc.intern("longer than the threshold");
+        assert!(gc.should_gc());
+
+        let mut gc = Gc::with_config(
+            GcConfig {
+                disabled: true,
+                ..config
+            },
+            None,
+        );
+        gc.intern("longer than the threshold");
+        assert!(!gc.should_gc());
+    }
+
+    #[test]
+    fn drop_heap() {
+        let mut gc = Gc::new();
+        let element = Value::String(gc.intern("element"));
+        gc.alloc(List::new(vec![element, element]));
+        gc.alloc(WeakRef::new(element));
+        // Only freeing the native drops its reference to the closure
+        let function: NativeFn = Rc::new(|_, _| Ok(Value::Nil));
+        gc.alloc(NativeFunction::new("native", None, function.clone()));
+        assert_eq!(Rc::strong_count(&function), 2);
+        drop(gc);
+        assert_eq!(Rc::strong_count(&function), 1);
+    }
 }
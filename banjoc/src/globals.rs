@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use crate::{
+    error::{Error, Result},
+    gc::{GarbageCollect, Gc},
+    op_code::GlobalSlot,
+    value::Value,
+};
+
+/// The global variables, each stored in a slot assigned when its name is
+/// first compiled or defined, so that instructions refer to globals by index
+/// rather than by name.
+///
+/// Slots keep their index for the life of the VM, as compiled programs refer to
+/// them, until [`free_undefined`](Self::free_undefined) frees them for reuse.
+#[derive(Default)]
+pub struct Globals {
+    /// `None` for globals which are referred to but not defined
+    values: Vec<Option<Value>>,
+    /// The name of each slot
+    names: Vec<String>,
+    slots: HashMap<String, GlobalSlot>,
+    /// Slots which no name is assigned to, reused before new ones
+    free: Vec<GlobalSlot>,
+}
+
+impl Globals {
+    /// The slot of the global with this name, assigning one if there's none
+    /// yet
+    pub fn slot(&mut self, name: &str) -> Result<GlobalSlot> {
+        if let Some(slot) = self.slots.get(name) {
+            return Ok(*slot);
+        }
+        if let Some(slot) = self.free.pop() {
+            self.names[slot as usize] = name.to_string();
+            self.slots.insert(name.to_string(), slot);
+            return Ok(slot);
+        }
+        let Ok(slot) = GlobalSlot::try_from(self.values.len()) else {
+            return Error::compile_err("Too many globals.");
+        };
+        self.values.push(None);
+        self.names.push(name.to_string());
+        self.slots.insert(name.to_string(), slot);
+        Ok(slot)
+    }
+
+    /// `None` if the global isn't defined
+    pub fn get(&self, slot: GlobalSlot) -> Option<Value> {
+        self.values.get(slot as usize).copied().flatten()
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<Value> {
+        self.get(*self.slots.get(name)?)
+    }
+
+    pub fn set(&mut self, slot: GlobalSlot, value: Value) -> Result<()> {
+        let Some(global) = self.values.get_mut(slot as usize) else {
+            return Error::runtime_err(format!("No global in slot {slot}."));
+        };
+        *global = Some(value);
+        Ok(())
+    }
+
+    /// Set the value of the global with this name, assigning it a slot if
+    /// needed
+    pub fn define(&mut self, name: &str, value: Value) -> Result<()> {
+        let slot = self.slot(name)?;
+        self.set(slot, value)
+    }
+
+    /// `None` if no name is assigned to the slot
+    pub fn name(&self, slot: GlobalSlot) -> Option<&str> {
+        let name = self.names.get(slot as usize)?;
+        (self.slots.get(name.as_str()) == Some(&slot)).then_some(name.as_str())
+    }
+
+    /// All the defined globals, in order of their slots
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Value)> {
+        self.names
+            .iter()
+            .zip(&self.values)
+            .filter_map(|(name, value)| Some((name.as_str(), (*value)?)))
+    }
+
+    /// Undefine every global for which `f` returns false. Their slots stay
+    /// assigned.
+    pub fn retain(&mut self, mut f: impl FnMut(&str, Value) -> bool) {
+        for (name, value) in self.names.iter().zip(&mut self.values) {
+            if value.is_some_and(|v| !f(name, v)) {
+                *value = None;
+            }
+        }
+    }
+
+    /// Free the slots of the globals which aren't defined, except those for
+    /// which `in_use` returns true, so that they're reused by other names.
+    /// Only valid once nothing compiled refers to the freed slots anymore.
+    pub fn free_undefined(&mut self, mut in_use: impl FnMut(GlobalSlot) -> bool) {
+        for (slot, value) in self.values.iter().enumerate() {
+            let slot = slot as GlobalSlot;
+            let name = &mut self.names[slot as usize];
+            let assigned = self.slots.get(name.as_str()) == Some(&slot);
+            if value.is_none() && assigned && !in_use(slot) {
+                self.slots.remove(name.as_str());
+                name.clear();
+                self.free.push(slot);
+            }
+        }
+    }
+
+    /// How many slots are allocated, whether or not they're assigned a name
+    #[cfg(test)]
+    pub fn slot_count(&self) -> usize {
+        self.values.len()
+    }
+}
+
+impl GarbageCollect for Globals {
+    fn mark_gray(&mut self, gc: &mut Gc) {
+        for value in self.values.iter_mut().flatten() {
+            value.mark_gray(gc);
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use crate::{value::Value, vm::Vm};
+
+/// Callbacks invoked by the VM while it executes a program, so that hosts can
+/// collect traces or metrics at runtime. Install with
+/// [`Vm::set_hooks`](crate::vm::Vm::set_hooks).
+///
+/// Every method does nothing by default. The VM is passed in read-only so that
+/// its state can be inspected, e.g. with [`Vm::stack`].
+pub trait VmHooks {
+    /// Called before each instruction is executed, with the instruction's
+    /// name as printed by the disassembler and the node it was compiled from
+    fn on_instruction(&mut self, _vm: &Vm, _instruction: &'static str, _node_id: Option<&str>) {}
+
+    /// Called when a banjo or native function is called
+    fn on_call(&mut self, _vm: &Vm, _function: &str) {}
+
+    /// Called when a banjo or native function returns
+    fn on_return(&mut self, _vm: &Vm, _function: &str, _value: Value) {}
+
+    /// Called when the value of a node is output
+    fn on_output(&mut self, _vm: &Vm, _node_id: &str, _value: Value) {}
+}
@@ -3,18 +3,24 @@
 
 mod chunk;
 mod compiler;
-#[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
 mod disassembler;
 mod func_compiler;
 mod gc;
+mod globals;
 mod native_functions;
 mod obj;
 mod op_code;
+mod random;
 mod stack;
 mod table;
 
 pub mod ast;
+pub mod config;
 pub mod error;
+pub mod hooks;
 pub mod output;
+pub mod profile;
+pub mod program;
+pub mod registry;
 pub mod value;
 pub mod vm;
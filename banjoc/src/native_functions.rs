@@ -1,11 +1,29 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    fmt::Write,
+    iter, mem,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use indexmap::IndexMap;
 
 use crate::{
     error::{Error, Result},
-    value::Value,
+    gc::GcRef,
+    obj::{Buffer, List, Map, MapKey, WeakRef},
+    value::{exact_number, NumberFormat, Value},
     vm::Vm,
 };
 
+/// The most decimal places a `format` placeholder can ask for, so that a
+/// graph can't make it write an unbounded number of zeros
+const MAX_DECIMAL_PLACES: usize = 100;
+
+/// The longest list `range` creates when the heap isn't limited, so that a
+/// huge range fails with an error rather than exhausting the host's memory
+const MAX_RANGE_LEN: usize = 1 << 24;
+
 pub fn clock(_args: &[Value], _vm: &mut Vm) -> Result<Value> {
     Ok(Value::Number(
         SystemTime::now()
@@ -15,11 +33,43 @@ pub fn clock(_args: &[Value], _vm: &mut Vm) -> Result<Value> {
     ))
 }
 
+/// The value the host provided under a key
+pub fn data(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [Value::String(key)] = args else {
+        return Error::runtime_err("Expected a string.");
+    };
+    vm.host_data(key.as_str())
+        .ok_or_else(|| Error::runtime(format!("No host data named '{}'.", key.as_str())))
+}
+
+/// Uniformly distributed in `[0, 1)`
+pub fn random(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    if !args.is_empty() {
+        return Error::runtime_err("Expected 0 arguments.");
+    }
+    Ok(Value::Number(vm.next_random()))
+}
+
+/// Uniformly distributed from `a` up to, but excluding, `b`
+pub fn random_range(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [a, b] = args else {
+        return Error::runtime_err("Expected 2 arguments.");
+    };
+    let (Some(a), Some(b)) = (a.as_f64(), b.as_f64()) else {
+        return Error::runtime_err("Operands must be numbers.");
+    };
+    Ok(Value::Number(a + (b - a) * vm.next_random()))
+}
+
 pub fn sum(args: &[Value], vm: &mut Vm) -> Result<Value> {
     Ok(args
         .iter()
         .copied()
-        .reduce(|accum, item| accum.add(item, vm))
+        .reduce(|accum, item| {
+            let sum = accum.add(item, vm);
+            vm.root(sum);
+            sum
+        })
         .unwrap_or(Value::Nil))
 }
 
@@ -29,8 +79,840 @@ pub fn product(args: &[Value], _vm: &mut Vm) -> Result<Value> {
         .copied()
         .reduce(|accum, item| {
             accum
-                .binary_op(item, |a, b| Value::Number(a * b))
+                .binary_op(
+                    item,
+                    |a, b| Value::Number(a * b),
+                    |a, b| a.checked_mul(b).map(Value::Int),
+                )
                 .unwrap_or(accum)
         })
         .unwrap_or(Value::Nil))
 }
+
+/// A weak reference to the argument, which doesn't keep it alive
+pub fn weak_ref(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [target] = args else {
+        return Error::runtime_err("Expected 1 argument.");
+    };
+    Ok(Value::WeakRef(vm.alloc(WeakRef::new(*target))))
+}
+
+/// The target of a weak reference, or nil if it has been freed
+pub fn weak_get(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    match args {
+        [Value::WeakRef(weak_ref)] => Ok(weak_ref.target),
+        _ => Error::runtime_err("Expected a weak reference."),
+    }
+}
+
+/// A map from alternating keys and values
+pub fn map_new(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    if !args.len().is_multiple_of(2) {
+        return Error::runtime_err("Expected a value for every key.");
+    }
+    let entries = args
+        .chunks(2)
+        .map(|pair| Ok((MapKey::try_from(pair[0])?, pair[1])))
+        .collect::<Result<_>>()?;
+    Ok(Value::Map(vm.alloc(Map::new(entries)?)))
+}
+
+/// A new list with the function applied to every element of a list
+pub fn list_map(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [function, list] = args else {
+        return Error::runtime_err("Expected 2 arguments.");
+    };
+    if !matches!(function, Value::Function(_) | Value::NativeFunction(_)) {
+        return Error::runtime_err("Expected a function.");
+    }
+    let values = list_arg(*list)?
+        .values
+        .iter()
+        .map(|element| {
+            let result = vm.call_value(*function, &[*element])?;
+            vm.root(result);
+            Ok(result)
+        })
+        .collect::<Result<_>>()?;
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+/// The value of a key in a map, or nil if there's none
+pub fn map_get(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    match args {
+        [Value::Map(map), key] => Ok(map
+            .entries
+            .get(&MapKey::try_from(*key)?)
+            .copied()
+            .unwrap_or_default()),
+        _ => Error::runtime_err("Expected a map and a key."),
+    }
+}
+
+/// Number of bytes in a byte buffer
+pub fn bytes_len(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    match args {
+        [Value::Bytes(buffer)] => Ok(Value::Number(buffer.data.len() as f64)),
+        _ => Error::runtime_err("Expected bytes."),
+    }
+}
+
+/// The bytes from `start` up to, but excluding, `end`
+pub fn bytes_slice(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [Value::Bytes(buffer), start, end] = args else {
+        return Error::runtime_err("Expected bytes, a start and an end.");
+    };
+    let range = index(*start)?..index(*end)?;
+    let Some(data) = buffer.data.get(range) else {
+        return Error::runtime_err("Slice out of range.");
+    };
+    let data = data.to_vec();
+    Ok(Value::Bytes(vm.alloc(Buffer::new(data))))
+}
+
+fn index(value: Value) -> Result<usize> {
+    match value.as_f64() {
+        Some(n) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+        _ => Error::runtime_err("Index must be a non-negative integer."),
+    }
+}
+
+/// Convert an integral number, or a string of digits, to an exact int
+pub fn int(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    match args {
+        [Value::Int(n)] => Ok(Value::Int(*n)),
+        [Value::Number(n)]
+            if n.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(n) =>
+        {
+            Ok(Value::Int(*n as i64))
+        }
+        [Value::String(s)] => s
+            .as_str()
+            .trim()
+            .parse()
+            .map(Value::Int)
+            .map_err(|_| Error::runtime(format!("Can't convert {:?} to an int.", s.as_str()))),
+        _ => Error::runtime_err("Expected an integral number or a string."),
+    }
+}
+
+/// The number written in a string, or nil if it isn't one. Integers which a
+/// number can't represent exactly become ints.
+pub fn parse_number(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    let [Value::String(s)] = args else {
+        return Error::runtime_err("Expected a string.");
+    };
+    let text = s.as_str().trim();
+    if let Ok(n) = text.parse::<i64>() {
+        return Ok(exact_number(n).map_or(Value::Int(n), Value::Number));
+    }
+    Ok(text.parse().map_or(Value::Nil, Value::Number))
+}
+
+/// Render a value as text, formatting numbers as configured
+pub fn string(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [value] = args else {
+        return Error::runtime_err("Expected 1 argument.");
+    };
+    let text = value.formatted(vm.config().number_format).to_string();
+    Ok(vm.string(&text))
+}
+
+/// Apply `int` to ints, falling back to `float` if it returns `None`, and
+/// `float` to numbers. Lists are mapped element-wise.
+fn unary_math(
+    args: &[Value],
+    vm: &mut Vm,
+    float: fn(f64) -> f64,
+    int: fn(i64) -> Option<i64>,
+) -> Result<Value> {
+    let [value] = args else {
+        return Error::runtime_err("Expected 1 argument.");
+    };
+    map_numbers(*value, vm, float, int)
+}
+
+fn map_numbers(
+    value: Value,
+    vm: &mut Vm,
+    float: fn(f64) -> f64,
+    int: fn(i64) -> Option<i64>,
+) -> Result<Value> {
+    match value {
+        Value::Int(n) => Ok(int(n).map_or_else(|| Value::Number(float(n as f64)), Value::Int)),
+        Value::Number(n) => Ok(Value::Number(float(n))),
+        Value::List(list) => {
+            let values = list
+                .values
+                .iter()
+                .map(|element| {
+                    let result = map_numbers(*element, vm, float, int)?;
+                    vm.root(result);
+                    Ok(result)
+                })
+                .collect::<Result<_>>()?;
+            Ok(Value::List(vm.alloc(List::new(values))))
+        }
+        _ => Error::runtime_err("Expected a number or a list of numbers."),
+    }
+}
+
+pub fn sqrt(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::sqrt, |_| None)
+}
+
+pub fn abs(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::abs, i64::checked_abs)
+}
+
+pub fn floor(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::floor, Some)
+}
+
+pub fn ceil(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::ceil, Some)
+}
+
+/// Round half away from zero
+pub fn round(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::round, Some)
+}
+
+/// Natural logarithm
+pub fn ln(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::ln, |_| None)
+}
+
+pub fn log10(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::log10, |_| None)
+}
+
+pub fn exp(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::exp, |_| None)
+}
+
+pub fn sin(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::sin, |_| None)
+}
+
+pub fn cos(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::cos, |_| None)
+}
+
+pub fn tan(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::tan, |_| None)
+}
+
+pub fn asin(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::asin, |_| None)
+}
+
+pub fn acos(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::acos, |_| None)
+}
+
+pub fn atan(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    unary_math(args, vm, f64::atan, |_| None)
+}
+
+/// The angle of the point `(x, y)`, taking arguments in the order `y, x`
+pub fn atan2(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    let [y, x] = args else {
+        return Error::runtime_err("Expected 2 arguments.");
+    };
+    y.binary_op(*x, |y, x| Value::Number(y.atan2(x)), |_, _| None)
+}
+
+/// Combine two numbers with `float`, or with `int` if both are ints and it
+/// doesn't overflow. Lists are combined element-wise, and a number is
+/// combined with every element of a list.
+fn zip_numbers(
+    a: Value,
+    b: Value,
+    vm: &mut Vm,
+    float: fn(f64, f64) -> f64,
+    int: fn(i64, i64) -> Option<i64>,
+) -> Result<Value> {
+    let values = match (a, b) {
+        (Value::List(a), Value::List(b)) => {
+            if a.values.len() != b.values.len() {
+                return Error::runtime_err("Lists must have the same length.");
+            }
+            iter::zip(&a.values, &b.values)
+                .map(|(a, b)| (*a, *b))
+                .collect::<Vec<_>>()
+        }
+        (Value::List(a), b) => a.values.iter().map(|a| (*a, b)).collect(),
+        (a, Value::List(b)) => b.values.iter().map(|b| (a, *b)).collect(),
+        (a, b) => {
+            return a
+                .binary_op(
+                    b,
+                    |a, b| Value::Number(float(a, b)),
+                    |a, b| int(a, b).map(Value::Int),
+                )
+                .map_err(|_| Error::runtime("Expected numbers or lists of numbers."));
+        }
+    };
+    let values = values
+        .into_iter()
+        .map(|(a, b)| {
+            let result = zip_numbers(a, b, vm, float, int)?;
+            vm.root(result);
+            Ok(result)
+        })
+        .collect::<Result<_>>()?;
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+fn reduce_numbers(
+    args: &[Value],
+    vm: &mut Vm,
+    float: fn(f64, f64) -> f64,
+    int: fn(i64, i64) -> Option<i64>,
+) -> Result<Value> {
+    let Some((first, rest)) = args.split_first() else {
+        return Error::runtime_err("Expected at least 1 argument.");
+    };
+    rest.iter().try_fold(*first, |accum, item| {
+        let result = zip_numbers(accum, *item, vm, float, int)?;
+        vm.root(result);
+        Ok(result)
+    })
+}
+
+pub fn min(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    reduce_numbers(args, vm, f64::min, |a, b| Some(a.min(b)))
+}
+
+pub fn max(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    reduce_numbers(args, vm, f64::max, |a, b| Some(a.max(b)))
+}
+
+/// Limit a value to the range from `lo` to `hi`. If `lo` is greater than
+/// `hi`, the result is `hi`.
+pub fn clamp(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [value, lo, hi] = args else {
+        return Error::runtime_err("Expected 3 arguments.");
+    };
+    let above = zip_numbers(*value, *lo, vm, f64::max, |a, b| Some(a.max(b)))?;
+    vm.root(above);
+    zip_numbers(above, *hi, vm, f64::min, |a, b| Some(a.min(b)))
+}
+
+fn list_arg(value: Value) -> Result<GcRef<List>> {
+    match value {
+        Value::List(list) => Ok(list),
+        _ => Error::runtime_err("Expected a list."),
+    }
+}
+
+/// Number of elements in a list
+pub fn len(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    let [list] = args else {
+        return Error::runtime_err("Expected 1 argument.");
+    };
+    Ok(Value::Number(list_arg(*list)?.values.len() as f64))
+}
+
+/// A new list with `value` added at the end
+pub fn append(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [list, value] = args else {
+        return Error::runtime_err("Expected 2 arguments.");
+    };
+    let mut values = list_arg(*list)?.values.clone();
+    values.push(*value);
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+/// A new list with the elements of all the given lists, in order
+pub fn concat(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let mut values = vec![];
+    for list in args {
+        values.extend_from_slice(&list_arg(*list)?.values);
+    }
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+/// A new list without repeated elements, keeping the first occurrence of
+/// each. Lists and maps are compared by their contents.
+pub fn unique(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [list] = args else {
+        return Error::runtime_err("Expected 1 argument.");
+    };
+    let mut seen_keys = HashSet::new();
+    let mut values: Vec<Value> = vec![];
+    for element in &list_arg(*list)?.values {
+        let is_new = match MapKey::try_from(*element) {
+            // Numbers and strings can be hashed
+            Ok(key) => seen_keys.insert(key),
+            Err(_) => !values.contains(element),
+        };
+        if is_new {
+            values.push(*element);
+        }
+    }
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+/// A new list with the elements of nested lists spliced in, up to `depth`
+/// levels deep. `depth` defaults to 1.
+pub fn flatten(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let (list, depth) = match args {
+        [list] => (list_arg(*list)?, 1),
+        [list, depth] => (list_arg(*list)?, index(*depth)?),
+        _ => return Error::runtime_err("Expected a list and an optional depth."),
+    };
+    let mut values = vec![];
+    flatten_into(&list.values, depth, &mut values);
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+fn flatten_into(values: &[Value], depth: usize, flattened: &mut Vec<Value>) {
+    for value in values {
+        match value {
+            Value::List(nested) if depth > 0 => flatten_into(&nested.values, depth - 1, flattened),
+            _ => flattened.push(*value),
+        }
+    }
+}
+
+pub fn reverse(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [list] = args else {
+        return Error::runtime_err("Expected 1 argument.");
+    };
+    let values = list_arg(*list)?.values.iter().rev().copied().collect();
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+/// A new list with the elements in ascending order. Elements must be all
+/// numbers or all strings.
+pub fn sort(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [list] = args else {
+        return Error::runtime_err("Expected 1 argument.");
+    };
+    let mut values = list_arg(*list)?.values.clone();
+    let mut comparable = true;
+    values.sort_by(|a, b| {
+        a.partial_cmp(b).unwrap_or_else(|| {
+            comparable = false;
+            Ordering::Equal
+        })
+    });
+    if !comparable {
+        return Error::runtime_err("Can only sort lists of numbers or lists of strings.");
+    }
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+/// A new list with the elements ordered by the keys which the function
+/// returns for them. Keys must be all numbers or all strings, and elements
+/// with equal keys keep their order.
+pub fn sort_by(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [function, list] = args else {
+        return Error::runtime_err("Expected 2 arguments.");
+    };
+    let mut keyed = list_arg(*list)?
+        .values
+        .iter()
+        .map(|element| {
+            let key = vm.call_value(*function, &[*element])?;
+            vm.root(key);
+            Ok((key, *element))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut comparable = true;
+    keyed.sort_by(|(a, _), (b, _)| {
+        a.partial_cmp(b).unwrap_or_else(|| {
+            comparable = false;
+            Ordering::Equal
+        })
+    });
+    if !comparable {
+        return Error::runtime_err("Keys must be all numbers or all strings.");
+    }
+    let values = keyed.into_iter().map(|(_, element)| element).collect();
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+/// A map from each key which the function returns to the list of elements
+/// it returned that key for, in their original order
+pub fn group_by(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [function, list] = args else {
+        return Error::runtime_err("Expected 2 arguments.");
+    };
+    let mut groups: IndexMap<MapKey, Vec<Value>> = IndexMap::new();
+    for element in &list_arg(*list)?.values {
+        let key = vm.call_value(*function, &[*element])?;
+        vm.root(key);
+        groups
+            .entry(MapKey::try_from(key)?)
+            .or_default()
+            .push(*element);
+    }
+    let entries = groups
+        .into_iter()
+        .map(|(key, values)| {
+            let group = Value::List(vm.alloc(List::new(values)));
+            vm.root(group);
+            (key, group)
+        })
+        .collect();
+    Ok(Value::Map(vm.alloc(Map::new(entries)?)))
+}
+
+/// A new list with the elements for which the function returns a truthy value
+pub fn filter(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [function, list] = args else {
+        return Error::runtime_err("Expected 2 arguments.");
+    };
+    let mut values = vec![];
+    for element in &list_arg(*list)?.values {
+        if !vm.call_value(*function, &[*element])?.is_falsey() {
+            values.push(*element);
+        }
+    }
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+/// Fold the elements of a list into an accumulator, starting from `init`, by
+/// calling the function with the accumulator and each element in turn
+pub fn reduce(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [function, init, list] = args else {
+        return Error::runtime_err("Expected 3 arguments.");
+    };
+    list_arg(*list)?
+        .values
+        .iter()
+        .try_fold(*init, |accum, element| {
+            let result = vm.call_value(*function, &[accum, *element])?;
+            vm.root(result);
+            Ok(result)
+        })
+}
+
+/// The numbers from `start` up to, but excluding, `stop`, `step` apart.
+/// Takes `stop`, `start, stop` or `start, stop, step`.
+pub fn range(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let numbers = args
+        .iter()
+        .map(|arg| arg.as_f64())
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| Error::runtime("Operands must be numbers."))?;
+    let (start, stop, step) = match numbers[..] {
+        [stop] => (0.0, stop, 1.0),
+        [start, stop] => (start, stop, 1.0),
+        [start, stop, step] => (start, stop, step),
+        _ => return Error::runtime_err("Expected 1 to 3 arguments."),
+    };
+    if step == 0.0 || !step.is_finite() {
+        return Error::runtime_err("Step must be a non-zero number.");
+    }
+
+    let len = ((stop - start) / step).ceil().max(0.0);
+    let max_len = vm
+        .config()
+        .max_heap_bytes
+        .map_or(MAX_RANGE_LEN, |max| max / mem::size_of::<Value>());
+    if len.is_nan() || len > max_len as f64 {
+        return Error::runtime_err("Range is too long.");
+    }
+    let len = len as usize;
+    let mut values = Vec::new();
+    if values.try_reserve_exact(len).is_err() {
+        return Error::runtime_err("Not enough memory for the range.");
+    }
+    values.extend((0..len).map(|i| Value::Number(start + i as f64 * step)));
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+/// The characters from `start` up to, but excluding, `end`. Indices count
+/// characters, not bytes.
+pub fn substring(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [Value::String(string), start, end] = args else {
+        return Error::runtime_err("Expected a string, a start and an end.");
+    };
+    let (start, end) = (index(*start)?, index(*end)?);
+    let len = string.as_str().chars().count();
+    if start > end || end > len {
+        return Error::runtime_err(format!(
+            "Range {start}..{end} is out of bounds for a string of {len} characters."
+        ));
+    }
+    let substring: String = string
+        .as_str()
+        .chars()
+        .skip(start)
+        .take(end - start)
+        .collect();
+    Ok(vm.string(&substring))
+}
+
+/// The character at index `i`, as a string
+pub fn char_at(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [Value::String(string), i] = args else {
+        return Error::runtime_err("Expected a string and an index.");
+    };
+    let i = index(*i)?;
+    let Some(c) = string.as_str().chars().nth(i) else {
+        return Error::runtime_err(format!(
+            "Index {i} is out of bounds for a string of {} characters.",
+            string.as_str().chars().count()
+        ));
+    };
+    Ok(vm.string(c.encode_utf8(&mut [0; 4])))
+}
+
+/// The running totals of a list of numbers
+pub fn cumsum(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [list] = args else {
+        return Error::runtime_err("Expected 1 argument.");
+    };
+    let mut total = Value::Number(0.0);
+    let values = list_arg(*list)?
+        .values
+        .iter()
+        .map(|value| {
+            total = total.binary_op(
+                *value,
+                |a, b| Value::Number(a + b),
+                |a, b| a.checked_add(b).map(Value::Int),
+            )?;
+            Ok(total)
+        })
+        .collect::<Result<_>>()?;
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+/// The mean of each number and the ones before it, up to `window` numbers in
+/// total. The first means are taken over fewer numbers, so that the result is
+/// as long as the list.
+pub fn running_mean(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [list, window] = args else {
+        return Error::runtime_err("Expected 2 arguments.");
+    };
+    let window = index(*window)?;
+    if window == 0 {
+        return Error::runtime_err("Window must be at least 1.");
+    }
+    let numbers = list_arg(*list)?
+        .values
+        .iter()
+        .map(|value| value.as_f64())
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| Error::runtime("Expected a list of numbers."))?;
+
+    let mut sum = 0.0;
+    let values = numbers
+        .iter()
+        .enumerate()
+        .map(|(i, n)| {
+            sum += n;
+            if i >= window {
+                sum -= numbers[i - window];
+            }
+            Value::Number(sum / (i + 1).min(window) as f64)
+        })
+        .collect();
+    Ok(Value::List(vm.alloc(List::new(values))))
+}
+
+/// The sum of the products of corresponding numbers in two lists
+pub fn dot(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    let [a, b] = args else {
+        return Error::runtime_err("Expected 2 arguments.");
+    };
+    let (a, b) = (list_arg(*a)?, list_arg(*b)?);
+    if a.values.len() != b.values.len() {
+        return Error::runtime_err("Lists must have the same length.");
+    }
+    iter::zip(&a.values, &b.values).try_fold(Value::Number(0.0), |total, (a, b)| {
+        let product = a.binary_op(
+            *b,
+            |a, b| Value::Number(a * b),
+            |a, b| a.checked_mul(b).map(Value::Int),
+        )?;
+        total.binary_op(
+            product,
+            |a, b| Value::Number(a + b),
+            |a, b| a.checked_add(b).map(Value::Int),
+        )
+    })
+}
+
+/// Swap the rows and columns of a list of equally long lists
+pub fn transpose(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [rows] = args else {
+        return Error::runtime_err("Expected 1 argument.");
+    };
+    let rows = list_arg(*rows)?
+        .values
+        .iter()
+        .map(|row| list_arg(*row))
+        .collect::<Result<Vec<_>>>()?;
+    let width = rows.first().map_or(0, |row| row.values.len());
+    if rows.iter().any(|row| row.values.len() != width) {
+        return Error::runtime_err("Rows must have the same length.");
+    }
+    let columns = (0..width)
+        .map(|i| {
+            let column = rows.iter().map(|row| row.values[i]).collect();
+            let column = Value::List(vm.alloc(List::new(column)));
+            vm.root(column);
+            column
+        })
+        .collect();
+    Ok(Value::List(vm.alloc(List::new(columns))))
+}
+
+/// Multiply every number in a list, or in nested lists, by `k`
+pub fn scale(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [list @ Value::List(_), k] = args else {
+        return Error::runtime_err("Expected a list and a number.");
+    };
+    zip_numbers(*list, *k, vm, |a, b| a * b, i64::checked_mul)
+}
+
+/// Replace each `{}` in the template with the next argument. `{:.N}` rounds
+/// numbers to `N` decimal places, and `{{` and `}}` stand for literal braces.
+pub fn format(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let Some((Value::String(template), mut args)) = args.split_first() else {
+        return Error::runtime_err("Expected a template string.");
+    };
+    let mut text = String::new();
+    let mut chars = template.as_str().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.clone().next() == Some('{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.clone().next() == Some('}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut spec = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => spec.push(c),
+                        None => return Error::runtime_err("Unclosed '{' in the template."),
+                    }
+                }
+                let number_format = match spec.as_str() {
+                    "" => vm.config().number_format,
+                    spec => spec
+                        .strip_prefix(":.")
+                        .and_then(|places| places.parse().ok())
+                        .filter(|places| *places <= MAX_DECIMAL_PLACES)
+                        .map(NumberFormat::Fixed)
+                        .ok_or_else(|| {
+                            Error::runtime(format!("Invalid placeholder '{{{spec}}}'."))
+                        })?,
+                };
+                let Some((arg, rest)) = args.split_first() else {
+                    return Error::runtime_err("Not enough arguments for the template.");
+                };
+                args = rest;
+                write!(text, "{}", arg.formatted(number_format))
+                    .map_err(|e| Error::runtime(e.to_string()))?;
+            }
+            '}' => return Error::runtime_err("Unmatched '}' in the template."),
+            c => text.push(c),
+        }
+    }
+    if !args.is_empty() {
+        return Error::runtime_err("Too many arguments for the template.");
+    }
+    Ok(vm.string(&text))
+}
+
+/// The kind of a value, such as "number" or "list"
+pub fn type_of(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [value] = args else {
+        return Error::runtime_err("Expected 1 argument.");
+    };
+    Ok(vm.string(value.type_name()))
+}
+
+fn is_type(args: &[Value], type_name: &str) -> Result<Value> {
+    let [value] = args else {
+        return Error::runtime_err("Expected 1 argument.");
+    };
+    Ok(Value::Bool(value.type_name() == type_name))
+}
+
+pub fn is_number(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    is_type(args, "number")
+}
+
+pub fn is_string(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    is_type(args, "string")
+}
+
+pub fn is_list(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    is_type(args, "list")
+}
+
+pub fn is_nil(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    is_type(args, "nil")
+}
+
+/// Report `message` as an error of the calling node if `condition` is falsey,
+/// without halting execution. Returns whether the assertion held.
+pub fn assert(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [condition, message] = args else {
+        return Error::runtime_err("Expected 2 arguments.");
+    };
+    let held = !condition.is_falsey();
+    if !held {
+        vm.report_error("assertion", message.to_string());
+    }
+    Ok(Value::Bool(held))
+}
+
+/// Report an error of the calling node if `actual` doesn't equal `expected`,
+/// without halting execution. Returns whether they're equal.
+pub fn expect_eq(args: &[Value], vm: &mut Vm) -> Result<Value> {
+    let [actual, expected] = args else {
+        return Error::runtime_err("Expected 2 arguments.");
+    };
+    let equal = actual == expected;
+    if !equal {
+        vm.report_error(
+            "assertion",
+            format!("Expected {expected} but got {actual}."),
+        );
+    }
+    Ok(Value::Bool(equal))
+}
+
+fn number_args<const N: usize>(args: &[Value]) -> Result<[f64; N]> {
+    let args: &[Value; N] = args
+        .try_into()
+        .map_err(|_| Error::runtime(format!("Expected {N} arguments.")))?;
+    let mut numbers = [0.0; N];
+    for (number, arg) in iter::zip(&mut numbers, args) {
+        *number = arg
+            .as_f64()
+            .ok_or_else(|| Error::runtime("Operands must be numbers."))?;
+    }
+    Ok(numbers)
+}
+
+/// Linear interpolation from `a` at `t = 0` to `b` at `t = 1`
+pub fn lerp(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    let [a, b, t] = number_args(args)?;
+    Ok(Value::Number(a + (b - a) * t))
+}
+
+/// Map `x` linearly from the range `in_lo..in_hi` to `out_lo..out_hi`
+pub fn map_range(args: &[Value], _vm: &mut Vm) -> Result<Value> {
+    let [x, in_lo, in_hi, out_lo, out_hi] = number_args(args)?;
+    if in_lo == in_hi {
+        return Error::runtime_err("Input range must not be empty.");
+    }
+    let t = (x - in_lo) / (in_hi - in_lo);
+    Ok(Value::Number(out_lo + (out_hi - out_lo) * t))
+}
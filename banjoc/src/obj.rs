@@ -1,10 +1,17 @@
-use std::fmt::{self, Debug, Formatter, Write};
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Formatter, Write},
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+
+use indexmap::IndexMap;
 
 use crate::{
     chunk::Chunk,
-    error::Result,
+    error::{Error, Result},
     gc::{GcRef, ObjHeader},
-    value::Value,
+    value::{exact_number, Value},
     vm::Vm,
 };
 
@@ -14,8 +21,26 @@ pub enum ObjectType {
     NativeFunction,
     Function,
     List,
+    WeakRef,
+    Map,
+    Buffer,
+}
+
+impl ObjectType {
+    pub fn name(self) -> &'static str {
+        match self {
+            ObjectType::String => "string",
+            ObjectType::NativeFunction => "native",
+            ObjectType::Function => "function",
+            ObjectType::List => "list",
+            ObjectType::WeakRef => "weak",
+            ObjectType::Map => "map",
+            ObjectType::Buffer => "bytes",
+        }
+    }
 }
 
+#[repr(C)]
 pub struct BanjoString {
     pub header: ObjHeader,
     string: String,
@@ -53,6 +78,7 @@ pub fn hash_string(string: &str) -> u32 {
     hash
 }
 
+#[repr(C)]
 pub struct Function {
     pub header: ObjHeader,
     pub arity: usize,
@@ -71,6 +97,16 @@ impl Function {
     }
 }
 
+impl Function {
+    /// The name of the function, or `<script>` for the top-level code
+    pub fn display_name(&self) -> &str {
+        match &self.name {
+            Some(name) => name.as_str(),
+            None => "<script>",
+        }
+    }
+}
+
 impl Debug for Function {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         if let Some(name) = self.name {
@@ -84,16 +120,24 @@ impl Debug for Function {
     }
 }
 
-pub type NativeFn = fn(args: &[Value], vm: &mut Vm) -> Result<Value>;
+/// A native function, which may capture host state. It is shared so that the
+/// same registry can be registered with several VMs.
+pub type NativeFn = Rc<dyn Fn(&[Value], &mut Vm) -> Result<Value>>;
+#[repr(C)]
 pub struct NativeFunction {
     pub header: ObjHeader,
+    pub name: String,
+    /// Number of arguments, checked before calling, or `None` if variadic
+    pub arity: Option<usize>,
     pub function: NativeFn,
 }
 
 impl NativeFunction {
-    pub fn new(function: NativeFn) -> Self {
+    pub fn new(name: &str, arity: Option<usize>, function: NativeFn) -> Self {
         Self {
             header: ObjHeader::new(ObjectType::NativeFunction),
+            name: name.to_string(),
+            arity,
             function,
         }
     }
@@ -101,11 +145,11 @@ impl NativeFunction {
 
 impl Debug for NativeFunction {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.write_str("<native fn>")?;
-        Ok(())
+        write!(f, "<native fn {}>", self.name)
     }
 }
 
+#[repr(C)]
 pub struct List {
     pub header: ObjHeader,
     pub values: Vec<Value>,
@@ -125,3 +169,169 @@ impl Debug for List {
         Debug::fmt(&self.values, f)
     }
 }
+
+/// Refers to a value without keeping it alive. Reads as nil once the garbage
+/// collector frees the target.
+#[repr(C)]
+pub struct WeakRef {
+    pub header: ObjHeader,
+    pub target: Value,
+}
+
+impl WeakRef {
+    pub fn new(target: Value) -> Self {
+        Self {
+            header: ObjHeader::new(ObjectType::WeakRef),
+            target,
+        }
+    }
+}
+
+impl Debug for WeakRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "<weak {:?}>", self.target)
+    }
+}
+
+/// A key of a [`Map`]. Strings are interned, so they compare by identity.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MapKey {
+    String(GcRef<BanjoString>),
+    Number(f64),
+    /// Only for ints which can't be represented exactly as a number
+    Int(i64),
+}
+
+impl MapKey {
+    pub fn to_value(self) -> Value {
+        match self {
+            MapKey::String(s) => Value::String(s),
+            MapKey::Number(n) => Value::Number(n),
+            MapKey::Int(n) => Value::Int(n),
+        }
+    }
+
+    /// The key of a JSON object, which must be a string
+    pub fn to_json_key(self) -> String {
+        match self {
+            MapKey::String(s) => s.as_str().to_string(),
+            MapKey::Number(n) => n.to_string(),
+            MapKey::Int(n) => n.to_string(),
+        }
+    }
+}
+
+impl TryFrom<Value> for MapKey {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::String(s) => Ok(MapKey::String(s)),
+            // 0.0 and -0.0 are the same key
+            Value::Number(0.0) => Ok(MapKey::Number(0.0)),
+            // NaN isn't equal to itself, so it couldn't be looked up
+            Value::Number(n) if n.is_nan() => Error::runtime_err("Map keys can't be NaN."),
+            Value::Number(n) => Ok(MapKey::Number(n)),
+            // Equal ints and numbers must be the same key
+            Value::Int(n) => Ok(exact_number(n).map_or(MapKey::Int(n), MapKey::Number)),
+            _ => Error::runtime_err("Map keys must be strings or numbers."),
+        }
+    }
+}
+
+impl Eq for MapKey {}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            MapKey::String(s) => s.hash.hash(state),
+            MapKey::Number(n) => n.to_bits().hash(state),
+            MapKey::Int(n) => n.hash(state),
+        }
+    }
+}
+
+impl Debug for MapKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.to_value(), f)
+    }
+}
+
+#[repr(C)]
+pub struct Map {
+    pub header: ObjHeader,
+    /// In the order the keys were first inserted
+    pub entries: IndexMap<MapKey, Value>,
+}
+
+impl Map {
+    /// Fails if two keys would be the same key of a JSON object, like `42`
+    /// and `"42"`
+    pub fn new(entries: IndexMap<MapKey, Value>) -> Result<Self> {
+        let mut json_keys = HashMap::with_capacity(entries.len());
+        for key in entries.keys() {
+            if let Some(other) = json_keys.insert(key.to_json_key(), key) {
+                return Error::runtime_err(format!(
+                    "Map keys {other:?} and {key:?} can't both be in a map."
+                ));
+            }
+        }
+        Ok(Self {
+            header: ObjHeader::new(ObjectType::Map),
+            entries,
+        })
+    }
+}
+
+impl Debug for Map {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(&self.entries).finish()
+    }
+}
+
+/// Binary data, such as an image or the contents of a file
+#[repr(C)]
+pub struct Buffer {
+    pub header: ObjHeader,
+    pub data: Vec<u8>,
+}
+
+impl Buffer {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            header: ObjHeader::new(ObjectType::Buffer),
+            data,
+        }
+    }
+
+    /// The data encoded as standard base64, with padding
+    pub fn to_base64(&self) -> String {
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+        let mut encoded = String::with_capacity(self.data.len().div_ceil(3) * 4);
+        for chunk in self.data.chunks(3) {
+            let bytes = [
+                chunk[0],
+                *chunk.get(1).unwrap_or(&0),
+                *chunk.get(2).unwrap_or(&0),
+            ];
+            let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    let index = (bits >> (18 - 6 * i)) & 0x3f;
+                    encoded.push(ALPHABET[index as usize] as char);
+                } else {
+                    encoded.push('=');
+                }
+            }
+        }
+        encoded
+    }
+}
+
+impl Debug for Buffer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "<bytes {}>", self.data.len())
+    }
+}
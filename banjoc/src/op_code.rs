@@ -5,6 +5,9 @@ pub struct Constant {
 
 pub type LocalIndex = u8;
 
+/// Index of a global in the slot table of the VM
+pub type GlobalSlot = u16;
+
 #[derive(Clone, Copy)]
 pub enum OpCode {
     Not,
@@ -28,8 +31,9 @@ pub enum OpCode {
 
     /// Load constant for use to top of stack
     Constant(Constant),
-    DefineGlobal(Constant),
-    GetGlobal(Constant),
+    /// Define the global in this slot, popping its value
+    SetSlot(GlobalSlot),
+    GetSlot(GlobalSlot),
     GetLocal(LocalIndex),
 
     Call {
@@ -40,4 +44,51 @@ pub enum OpCode {
     Output {
         output_index: u8,
     },
+
+    // Superinstructions, which fuse common sequences of the instructions above
+    // to save dispatching twice. Only emitted by `Chunk::fuse_instructions`.
+    /// `GetLocal` followed by `Call`
+    GetLocalCall {
+        local: LocalIndex,
+        arg_count: u8,
+    },
+    /// `Constant` followed by `Add`
+    ConstantAdd(Constant),
+    /// `GetSlot` followed by `Output`
+    GetSlotOutput {
+        slot: GlobalSlot,
+        output_index: u8,
+    },
+}
+
+impl OpCode {
+    /// The name of this instruction, as printed by the disassembler
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpCode::Not => "OP_NOT",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::Add => "OP_ADD",
+            OpCode::Subtract => "OP_SUBTRACT",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::Less => "OP_LESS",
+            OpCode::Return => "OP_RETURN",
+            OpCode::Nil => "OP_NIL",
+            OpCode::True => "OP_TRUE",
+            OpCode::False => "OP_FALSE",
+            OpCode::Pop => "OP_POP",
+            OpCode::Constant(_) => "OP_CONSTANT",
+            OpCode::SetSlot(_) => "OP_SET_SLOT",
+            OpCode::GetSlot(_) => "OP_GET_SLOT",
+            OpCode::GetLocal(_) => "OP_GET_LOCAL",
+            OpCode::Call { .. } => "OP_CALL",
+            OpCode::Function(_) => "OP_FUNCTION",
+            OpCode::Output { .. } => "OP_OUTPUT",
+            OpCode::GetLocalCall { .. } => "OP_GET_LOCAL_CALL",
+            OpCode::ConstantAdd(_) => "OP_CONSTANT_ADD",
+            OpCode::GetSlotOutput { .. } => "OP_GET_SLOT_OUTPUT",
+        }
+    }
 }
@@ -1,96 +1,425 @@
-use std::{collections::HashMap, mem};
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    sync::Arc,
+    time::Duration,
+};
 
-use serde::Serialize;
+use serde::{ser::SerializeMap, Serialize, Serializer};
 
 use crate::{
-    ast::NodeId,
-    error::{Error, Result},
-    value::Value,
+    ast::{NodeId, NodeIndex},
+    config::{OutputLimits, VmConfig},
+    error::{Diagnostic, Error, Result, Severity},
+    gc::{GarbageCollect, Gc},
+    value::{Formatted, NumberFormat, Value},
 };
 
 type NodeValues = HashMap<NodeId, Value>;
 
-#[derive(Default, Debug, Serialize)]
+#[derive(Default, Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OutputErrors {
-    pub node_errors: HashMap<NodeId, String>,
-    pub additional_errors: Vec<String>,
+    /// Every error of each node, in the order they occurred
+    #[serde(serialize_with = "sorted")]
+    pub node_errors: HashMap<NodeId, Vec<Diagnostic>>,
+    pub additional_errors: Vec<Diagnostic>,
+    /// Warnings and infos, which don't stop the graph from running, such as
+    /// unused nodes
+    #[serde(serialize_with = "sorted")]
+    pub node_diagnostics: HashMap<NodeId, Vec<Diagnostic>>,
 }
 
 impl OutputErrors {
-    fn add(&mut self, error: Error) {
+    fn add(&mut self, error: Error, code: &'static str) {
         match error {
-            Error::Compile(s) => self.additional_errors.push(s),
-            Error::Runtime(s) => self.additional_errors.push(s),
+            Error::Compile(s) | Error::Runtime(s) => {
+                self.additional_errors.push(Diagnostic::error(code, s))
+            }
             Error::Node((n, s)) => {
-                self.node_errors.insert(n, s);
+                self.node_errors
+                    .entry(n)
+                    .or_default()
+                    .push(Diagnostic::error(code, s));
             }
         }
     }
+
+    fn add_diagnostic(&mut self, node_id: &str, diagnostic: Diagnostic) {
+        let diagnostics = if diagnostic.severity == Severity::Error {
+            &mut self.node_errors
+        } else {
+            &mut self.node_diagnostics
+        };
+        diagnostics
+            .entry(node_id.to_string())
+            .or_default()
+            .push(diagnostic);
+    }
 }
-#[derive(Serialize, Debug)]
+/// Wall-clock time spent during a run, in milliseconds
+#[derive(Default, Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
+pub struct Timings {
+    /// Time taken to compute each output node since the previous one
+    #[serde(serialize_with = "sorted")]
+    pub nodes: HashMap<NodeId, f64>,
+    /// Total time spent inside each function, summed over all its calls
+    #[serde(serialize_with = "sorted")]
+    pub functions: HashMap<NodeId, f64>,
+}
+
+/// Serialize a map ordered by node id, so that serialized outputs of the same
+/// graph are identical
+fn sorted<S, V>(map: &HashMap<NodeId, V>, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    V: Serialize,
+{
+    serializer.collect_map(map.iter().collect::<BTreeMap<_, _>>())
+}
+
+/// Heap usage during a run, in bytes
+#[derive(Default, Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryStats {
+    /// Size of the heap once the run finished
+    pub bytes_allocated: usize,
+    /// Largest size of the heap during the run
+    pub peak_heap: usize,
+    /// Number of garbage collections which finished during the run
+    pub collections: usize,
+}
+
+#[derive(Debug)]
 pub struct Output {
     pub node_values: NodeValues,
-    #[serde(flatten)]
     pub errors: OutputErrors,
+    /// Only present if the VM was configured to record timings
+    pub timings: Option<Timings>,
+    /// Only present if the VM was configured to record memory usage
+    pub memory: Option<MemoryStats>,
+    /// How the numbers in `node_values` are serialized
+    pub number_format: NumberFormat,
+    /// Whether `node_values` are serialized along with their type
+    pub tag_types: bool,
+    /// How much of each of `node_values` is serialized
+    pub limits: OutputLimits,
+}
+
+/// A node value as serialized in an [`Output`]
+struct NodeValue {
+    value: Formatted,
+    /// Present if the value is serialized along with its type
+    type_name: Option<&'static str>,
+}
+
+impl Serialize for NodeValue {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let truncated = self.value.is_truncated();
+        if self.type_name.is_none() && !truncated {
+            return self.value.serialize(serializer);
+        }
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(type_name) = self.type_name {
+            map.serialize_entry("type", type_name)?;
+        }
+        map.serialize_entry("value", &self.value)?;
+        if truncated {
+            map.serialize_entry("truncated", &true)?;
+        }
+        map.end()
+    }
+}
+
+/// A diagnostic as serialized in an [`Output`]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorObject<'a> {
+    code: &'a str,
+    node_id: Option<&'a str>,
+    message: &'a str,
+    severity: Severity,
+}
+
+impl<'a> ErrorObject<'a> {
+    fn new(node_id: Option<&'a str>, diagnostic: &'a Diagnostic) -> Self {
+        Self {
+            code: diagnostic.code,
+            node_id,
+            message: &diagnostic.message,
+            severity: diagnostic.severity,
+        }
+    }
+
+    /// The diagnostics of each node, ordered by node id
+    fn by_node(
+        diagnostics: &'a HashMap<NodeId, Vec<Diagnostic>>,
+    ) -> BTreeMap<&'a str, Vec<ErrorObject<'a>>> {
+        diagnostics
+            .iter()
+            .map(|(node_id, diagnostics)| {
+                let objects = diagnostics
+                    .iter()
+                    .map(|diagnostic| Self::new(Some(node_id), diagnostic))
+                    .collect();
+                (node_id.as_str(), objects)
+            })
+            .collect()
+    }
+}
+
+impl Serialize for Output {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let node_values: BTreeMap<_, _> = self
+            .node_values
+            .iter()
+            .map(|(node_id, value)| {
+                let node_value = NodeValue {
+                    value: value.formatted(self.number_format).limited(self.limits),
+                    type_name: self.tag_types.then(|| value.type_name()),
+                };
+                (node_id, node_value)
+            })
+            .collect();
+
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("nodeValues", &node_values)?;
+        let additional_errors: Vec<_> = self
+            .errors
+            .additional_errors
+            .iter()
+            .map(|diagnostic| ErrorObject::new(None, diagnostic))
+            .collect();
+        map.serialize_entry(
+            "nodeErrors",
+            &ErrorObject::by_node(&self.errors.node_errors),
+        )?;
+        map.serialize_entry("additionalErrors", &additional_errors)?;
+        map.serialize_entry(
+            "nodeDiagnostics",
+            &ErrorObject::by_node(&self.errors.node_diagnostics),
+        )?;
+        if let Some(timings) = &self.timings {
+            map.serialize_entry("timings", timings)?;
+        }
+        if let Some(memory) = &self.memory {
+            map.serialize_entry("memory", memory)?;
+        }
+        map.end()
+    }
 }
 
 impl Output {
+    /// Encode with MessagePack, with the same structure as the JSON
+    /// serialization
+    #[cfg(feature = "msgpack")]
+    pub fn to_msgpack(&self) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(self).map_err(|e| Error::runtime(e.to_string()))
+    }
+
+    /// Encode with CBOR, with the same structure as the JSON serialization
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        ciborium::into_writer(self, &mut bytes).map_err(|e| Error::runtime(e.to_string()))?;
+        Ok(bytes)
+    }
+
     pub fn from_single_error(error: Error) -> Self {
         let mut errors = OutputErrors::default();
-        errors.add(error);
+        errors.add(error, "compile");
         Self {
             node_values: NodeValues::default(),
             errors,
+            timings: None,
+            memory: None,
+            number_format: NumberFormat::default(),
+            tag_types: false,
+            limits: OutputLimits::default(),
         }
     }
 }
 
-#[derive(Default)]
+/// The values and errors of a run, reused across runs so that their buffers
+/// aren't reallocated each time
 pub struct OutputValues {
-    /// Output values of nodes in order of execution. Indices correspond with
-    /// `Compiler::output_nodes`.
-    output_nodes: Vec<NodeId>,
-    /// IDs of nodes in order of compilation
-    output_values: Vec<Value>,
+    /// The ids of the nodes of the graph being compiled or run
+    node_ids: Arc<[NodeId]>,
+    /// Indices of nodes in `node_ids`, in order of compilation
+    output_nodes: Vec<NodeIndex>,
+    /// Output values of nodes. Indices correspond with `output_nodes`, and
+    /// there's one for each as soon as it's compiled. `None` if the node
+    /// hasn't been computed (yet), e.g. because execution halted early with an
+    /// error.
+    output_values: Vec<Option<Value>>,
     errors: OutputErrors,
+    timings: Option<Timings>,
+}
+
+impl Default for OutputValues {
+    fn default() -> Self {
+        Self {
+            node_ids: Arc::new([]),
+            output_nodes: vec![],
+            output_values: vec![],
+            errors: OutputErrors::default(),
+            timings: None,
+        }
+    }
+}
+
+impl Clone for OutputValues {
+    fn clone(&self) -> Self {
+        Self {
+            node_ids: self.node_ids.clone(),
+            output_nodes: self.output_nodes.clone(),
+            output_values: self.output_values.clone(),
+            errors: self.errors.clone(),
+            timings: self.timings.clone(),
+        }
+    }
+
+    /// Reuse the buffers of `self`, as [`Vm::interpret_batch`] does before each
+    /// run
+    ///
+    /// [`Vm::interpret_batch`]: crate::vm::Vm::interpret_batch
+    fn clone_from(&mut self, source: &Self) {
+        self.node_ids = source.node_ids.clone();
+        self.output_nodes.clone_from(&source.output_nodes);
+        self.output_values.clone_from(&source.output_values);
+        self.errors.clone_from(&source.errors);
+        self.timings.clone_from(&source.timings);
+    }
 }
 
 impl OutputValues {
-    pub fn add_node(&mut self, node_id: &str) -> Result<u8> {
+    /// Use the ids of the nodes of the graph about to be compiled
+    pub fn set_node_ids(&mut self, node_ids: Arc<[NodeId]>) {
+        self.node_ids = node_ids;
+    }
+
+    pub fn add_node(&mut self, node: NodeIndex) -> Result<u8> {
         if self.output_nodes.len() >= 255 {
-            return Error::node_err(node_id, "Can't preview the output of more than 255 nodes");
+            return Error::node_err(
+                &self.node_ids[node as usize],
+                "Can't preview the output of more than 255 nodes",
+            );
         }
-        self.output_nodes.push(node_id.to_string());
+        self.output_nodes.push(node);
+        self.output_values.push(None);
         let output_index = (self.output_nodes.len() - 1) as u8;
         Ok(output_index)
     }
 
     pub fn add_value(&mut self, output_index: u8, value: Value) {
-        let min_len = (output_index + 1) as usize;
-        if self.output_values.len() < min_len {
-            self.output_values.resize_with(min_len, || Value::Nil);
-        }
-        self.output_values[output_index as usize] = value;
+        self.output_values[output_index as usize] = Some(value);
     }
 
+    /// Report an error found while compiling
     pub fn add_error(&mut self, error: Error) {
-        self.errors.add(error)
+        self.errors.add(error, "compile")
     }
 
-    pub fn take(&mut self) -> Output {
-        let output_values = mem::take(&mut self.output_values);
-        let output_nodes = mem::take(&mut self.output_nodes);
-        debug_assert_eq!(output_nodes.len(), output_values.len());
-        let node_values = output_nodes
-            .into_iter()
-            .zip(output_values.into_iter())
-            .collect();
+    /// Report an error which occurred while running, identified by `code`
+    pub fn add_runtime_error(&mut self, error: Error, code: &'static str) {
+        self.errors.add(error, code)
+    }
+
+    /// Report a problem with a node. Those of [`Severity::Error`] are
+    /// reported along with the errors.
+    pub fn add_diagnostic(&mut self, node_id: &str, diagnostic: Diagnostic) {
+        self.errors.add_diagnostic(node_id, diagnostic)
+    }
+
+    pub fn node_id(&self, output_index: u8) -> &str {
+        &self.node_ids[self.output_nodes[output_index as usize] as usize]
+    }
+
+    pub fn output_index(&self, node_id: &str) -> Option<u8> {
+        self.output_nodes
+            .iter()
+            .position(|node| self.node_ids[*node as usize] == node_id)
+            .map(|index| index as u8)
+    }
+
+    /// The values which have been output so far
+    pub fn values(&self) -> impl Iterator<Item = (&str, Value)> {
+        self.output_nodes
+            .iter()
+            .map(|node| self.node_ids[*node as usize].as_str())
+            .zip(self.output_values.iter().copied())
+            .filter_map(|(node_id, value)| Some((node_id, value?)))
+    }
+
+    /// Start collecting timings for the next run
+    pub fn record_timings(&mut self) {
+        self.timings = Some(Timings::default());
+    }
+
+    pub fn add_node_time(&mut self, output_index: u8, elapsed: Duration) {
+        if let Some(timings) = &mut self.timings {
+            let node_id = &self.node_ids[self.output_nodes[output_index as usize] as usize];
+            *timings.nodes.entry(node_id.clone()).or_default() += as_millis(elapsed);
+        }
+    }
+
+    pub fn add_function_time(&mut self, function_id: &str, elapsed: Duration) {
+        if let Some(timings) = &mut self.timings {
+            *timings
+                .functions
+                .entry(function_id.to_string())
+                .or_default() += as_millis(elapsed);
+        }
+    }
+
+    /// Forget everything from a previous run, keeping the buffers
+    pub fn clear(&mut self) {
+        self.output_nodes.clear();
+        self.output_values.clear();
+        self.errors = OutputErrors::default();
+        self.timings = None;
+    }
+
+    pub fn take(&mut self, config: &VmConfig) -> Output {
+        // Keep whatever was computed, even if execution halted early
+        let mut node_values = NodeValues::with_capacity(self.output_values.len());
+        let values = self
+            .output_nodes
+            .drain(..)
+            .zip(self.output_values.drain(..));
+        for (node, value) in values {
+            if let Some(value) = value {
+                node_values.insert(self.node_ids[node as usize].clone(), value);
+            }
+        }
 
         Output {
             node_values,
             errors: mem::take(&mut self.errors),
+            timings: self.timings.take(),
+            memory: None,
+            number_format: config.number_format,
+            tag_types: config.tag_types,
+            limits: config.output_limits,
+        }
+    }
+}
+
+impl GarbageCollect for OutputValues {
+    fn mark_gray(&mut self, gc: &mut Gc) {
+        for value in self.output_values.iter_mut().flatten() {
+            value.mark_gray(gc);
         }
     }
 }
+
+fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    mem,
+    time::{Duration, Instant},
+};
+
+use crate::op_code::OpCode;
+
+/// Execution counts and cumulated time gathered while
+/// [`VmConfig::profile`](crate::config::VmConfig::profile) is set.
+#[derive(Default, Debug)]
+pub struct Profile {
+    /// Keyed by opcode name, as printed by the disassembler
+    pub opcodes: HashMap<&'static str, ProfileEntry>,
+    /// Keyed by function name. Time spent in a function includes the time
+    /// spent in any function it calls.
+    pub functions: HashMap<String, ProfileEntry>,
+    /// Keyed by node id. Counts the instructions compiled from the node
+    /// which were executed, and the time they took.
+    pub nodes: HashMap<String, ProfileEntry>,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct ProfileEntry {
+    pub count: usize,
+    pub time: Duration,
+}
+
+impl ProfileEntry {
+    fn add(&mut self, time: Duration) {
+        self.count += 1;
+        self.time += time;
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Profiler {
+    profile: Profile,
+    /// The instruction currently executing and when it started
+    current: Option<(&'static str, Instant)>,
+    /// The node which the instruction currently executing was compiled from
+    current_node: Option<String>,
+    /// Names and start times of the ongoing function calls, innermost last
+    calls: Vec<(String, Instant)>,
+}
+
+impl Profiler {
+    /// Finish timing the previous instruction and start timing `opcode`,
+    /// compiled from the given node
+    pub fn instruction(&mut self, opcode: OpCode, node_id: Option<&str>) {
+        let now = Instant::now();
+        self.finish_instruction(now);
+        self.current = Some((opcode.name(), now));
+        // Consecutive instructions mostly come from the same node, so avoid
+        // allocating its id again
+        if self.current_node.as_deref() != node_id {
+            self.current_node = node_id.map(str::to_string);
+        }
+    }
+
+    pub fn start_call(&mut self, name: String) {
+        self.calls.push((name, Instant::now()));
+    }
+
+    pub fn end_call(&mut self) {
+        if let Some((name, start)) = self.calls.pop() {
+            self.profile
+                .functions
+                .entry(name)
+                .or_default()
+                .add(start.elapsed());
+        }
+    }
+
+    /// Record a native function call which took `elapsed`
+    pub fn native_call(&mut self, name: &str, elapsed: Duration) {
+        self.profile
+            .functions
+            .entry(name.to_string())
+            .or_default()
+            .add(elapsed);
+    }
+
+    /// Stop timing the current instruction when execution is suspended
+    pub fn suspend(&mut self) {
+        self.finish_instruction(Instant::now());
+    }
+
+    /// Stop timing when execution halts
+    pub fn halt(&mut self) {
+        self.suspend();
+        while !self.calls.is_empty() {
+            self.end_call();
+        }
+    }
+
+    pub fn take(&mut self) -> Profile {
+        mem::take(&mut self.profile)
+    }
+
+    fn finish_instruction(&mut self, now: Instant) {
+        if let Some((name, start)) = self.current.take() {
+            self.profile
+                .opcodes
+                .entry(name)
+                .or_default()
+                .add(now - start);
+            if let Some(node_id) = &self.current_node {
+                match self.profile.nodes.get_mut(node_id) {
+                    Some(entry) => entry.add(now - start),
+                    None => {
+                        let mut entry = ProfileEntry::default();
+                        entry.add(now - start);
+                        self.profile.nodes.insert(node_id.clone(), entry);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast::{LiteralType, NodeId},
+    gc::GcRef,
+    obj::Function,
+    output::OutputValues,
+};
+
+/// Values replacing the output of nodes, keyed by node id. Typically used to
+/// override `const` and `var` nodes so the same program can be run with
+/// different parameters.
+pub type Inputs = HashMap<NodeId, LiteralType>;
+
+/// A graph compiled by [`Vm::compile`](crate::vm::Vm::compile), ready to be
+/// run any number of times with
+/// [`Vm::interpret_batch`](crate::vm::Vm::interpret_batch).
+///
+/// The program lives on the heap of the VM which compiled it and must only be
+/// run by that VM, before its next [`reset`](crate::vm::Vm::reset) or
+/// [`free_program`](crate::vm::Vm::free_program).
+pub struct CompiledProgram {
+    pub(crate) function: GcRef<Function>,
+    /// The nodes to output and any compile errors
+    pub(crate) output: OutputValues,
+}
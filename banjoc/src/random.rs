@@ -0,0 +1,38 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+/// SplitMix64, a small and fast generator. Good enough for simulations, not
+/// for anything security related.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn with_seed(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Seeded from the per-process randomness of the standard library. On
+    /// `wasm32-unknown-unknown` the standard library has no source of
+    /// randomness, so the seed is the same every time; hosts there should seed
+    /// with [`Vm::set_random_seed`](crate::vm::Vm::set_random_seed) instead.
+    pub fn from_entropy() -> Self {
+        Self::with_seed(RandomState::new().build_hasher().finish())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        // The top 53 bits fill the mantissa exactly
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
@@ -0,0 +1,615 @@
+use std::rc::Rc;
+
+use serde::Serialize;
+
+use crate::{
+    config::Capabilities, error::Result, native_functions::*, obj::NativeFn, value::Value, vm::Vm,
+};
+
+/// Natives grouped by namespace. Graphs call them by their qualified name,
+/// such as `math.sqrt`. Register them with
+/// [`Vm::register_natives`](crate::vm::Vm::register_natives).
+#[derive(Default)]
+pub struct NativeRegistry {
+    pub(crate) natives: Vec<RegisteredNative>,
+}
+
+pub(crate) struct RegisteredNative {
+    pub namespace: String,
+    pub name: String,
+    pub description: String,
+    /// Unqualified names, kept for graphs written before namespaces existed
+    pub aliases: &'static [&'static str],
+    /// Number of arguments, checked before calling, or `None` if not checked
+    pub arity: Option<usize>,
+    /// The numbers of arguments it accepts, reported to hosts
+    pub args: ArgCount,
+    pub function: NativeFn,
+}
+
+/// The range of numbers of arguments a native accepts
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct ArgCount {
+    pub min: usize,
+    /// `None` if variadic
+    pub max: Option<usize>,
+}
+
+impl ArgCount {
+    /// The arguments accepted by a native with this arity
+    pub fn from_arity(arity: Option<usize>) -> Self {
+        match arity {
+            Some(arity) => exactly(arity),
+            None => at_least(0),
+        }
+    }
+}
+
+const fn exactly(n: usize) -> ArgCount {
+    ArgCount {
+        min: n,
+        max: Some(n),
+    }
+}
+
+const fn between(min: usize, max: usize) -> ArgCount {
+    ArgCount {
+        min,
+        max: Some(max),
+    }
+}
+
+const fn at_least(min: usize) -> ArgCount {
+    ArgCount { min, max: None }
+}
+
+/// Namespace, name, aliases, accepted arguments, description and function of
+/// a builtin. Builtins check their arguments themselves.
+type Entry = (
+    &'static str,
+    &'static str,
+    &'static [&'static str],
+    ArgCount,
+    &'static str,
+    fn(&[Value], &mut Vm) -> Result<Value>,
+);
+
+const TIME: &[Entry] = &[(
+    "time",
+    "clock",
+    &["clock"],
+    exactly(0),
+    "Seconds since the Unix epoch",
+    clock,
+)];
+
+const HOST_DATA: &[Entry] = &[(
+    "host",
+    "data",
+    &["data"],
+    exactly(1),
+    "Value provided by the host under a key",
+    data,
+)];
+
+const RANDOM: &[Entry] = &[
+    (
+        "random",
+        "random",
+        &["random"],
+        exactly(0),
+        "Uniformly distributed number in [0, 1)",
+        random,
+    ),
+    (
+        "random",
+        "range",
+        &["random_range"],
+        exactly(2),
+        "Uniformly distributed number from a up to b",
+        random_range,
+    ),
+];
+
+const STANDARD: &[Entry] = &[
+    (
+        "math",
+        "sum",
+        &["sum"],
+        at_least(0),
+        "Add values, element-wise for lists",
+        sum,
+    ),
+    (
+        "math",
+        "product",
+        &["product"],
+        at_least(0),
+        "Multiply numbers",
+        product,
+    ),
+    (
+        "math",
+        "int",
+        &["int"],
+        exactly(1),
+        "Convert to an exact integer",
+        int,
+    ),
+    ("math", "sqrt", &["sqrt"], exactly(1), "Square root", sqrt),
+    ("math", "abs", &["abs"], exactly(1), "Absolute value", abs),
+    ("math", "floor", &["floor"], exactly(1), "Round down", floor),
+    ("math", "ceil", &["ceil"], exactly(1), "Round up", ceil),
+    (
+        "math",
+        "round",
+        &["round"],
+        exactly(1),
+        "Round half away from zero",
+        round,
+    ),
+    ("math", "ln", &["ln"], exactly(1), "Natural logarithm", ln),
+    (
+        "math",
+        "log10",
+        &["log10"],
+        exactly(1),
+        "Base 10 logarithm",
+        log10,
+    ),
+    (
+        "math",
+        "exp",
+        &["exp"],
+        exactly(1),
+        "e raised to a power",
+        exp,
+    ),
+    ("math", "sin", &["sin"], exactly(1), "Sine", sin),
+    ("math", "cos", &["cos"], exactly(1), "Cosine", cos),
+    ("math", "tan", &["tan"], exactly(1), "Tangent", tan),
+    ("math", "asin", &["asin"], exactly(1), "Arcsine", asin),
+    ("math", "acos", &["acos"], exactly(1), "Arccosine", acos),
+    ("math", "atan", &["atan"], exactly(1), "Arctangent", atan),
+    (
+        "math",
+        "atan2",
+        &["atan2"],
+        exactly(2),
+        "Angle of the point (x, y), given y and x",
+        atan2,
+    ),
+    (
+        "math",
+        "min",
+        &["min"],
+        at_least(1),
+        "Smallest of the arguments, element-wise for lists",
+        min,
+    ),
+    (
+        "math",
+        "max",
+        &["max"],
+        at_least(1),
+        "Largest of the arguments, element-wise for lists",
+        max,
+    ),
+    (
+        "math",
+        "clamp",
+        &["clamp"],
+        exactly(3),
+        "Limit a value to a range",
+        clamp,
+    ),
+    (
+        "math",
+        "lerp",
+        &["lerp"],
+        exactly(3),
+        "Interpolate linearly between a and b",
+        lerp,
+    ),
+    (
+        "math",
+        "map_range",
+        &["map_range"],
+        exactly(5),
+        "Map a number from one range to another",
+        map_range,
+    ),
+    (
+        "math",
+        "cumsum",
+        &["cumsum"],
+        exactly(1),
+        "Running totals of a list",
+        cumsum,
+    ),
+    (
+        "math",
+        "running_mean",
+        &["running_mean"],
+        exactly(2),
+        "Means over a sliding window",
+        running_mean,
+    ),
+    (
+        "math",
+        "dot",
+        &["dot"],
+        exactly(2),
+        "Dot product of two lists",
+        dot,
+    ),
+    (
+        "math",
+        "transpose",
+        &["transpose"],
+        exactly(1),
+        "Swap rows and columns of a list of lists",
+        transpose,
+    ),
+    (
+        "math",
+        "scale",
+        &["scale"],
+        exactly(2),
+        "Multiply every number in a list",
+        scale,
+    ),
+    (
+        "list",
+        "len",
+        &["len"],
+        exactly(1),
+        "Number of elements in a list",
+        len,
+    ),
+    (
+        "list",
+        "append",
+        &["append"],
+        exactly(2),
+        "List with a value added at the end",
+        append,
+    ),
+    (
+        "list",
+        "concat",
+        &["concat"],
+        at_least(0),
+        "Join lists",
+        concat,
+    ),
+    (
+        "list",
+        "reverse",
+        &["reverse"],
+        exactly(1),
+        "List in reverse order",
+        reverse,
+    ),
+    (
+        "list",
+        "unique",
+        &["unique"],
+        exactly(1),
+        "List without repeated elements",
+        unique,
+    ),
+    (
+        "list",
+        "flatten",
+        &["flatten"],
+        between(1, 2),
+        "Splice nested lists in",
+        flatten,
+    ),
+    (
+        "list",
+        "sort",
+        &["sort"],
+        exactly(1),
+        "List in ascending order",
+        sort,
+    ),
+    (
+        "list",
+        "sort_by",
+        &["sort_by"],
+        exactly(2),
+        "List ordered by the keys a function returns",
+        sort_by,
+    ),
+    (
+        "list",
+        "group_by",
+        &["group_by"],
+        exactly(2),
+        "Map from keys a function returns to lists of elements",
+        group_by,
+    ),
+    (
+        "list",
+        "map",
+        &["list_map"],
+        exactly(2),
+        "Apply a function to every element of a list",
+        list_map,
+    ),
+    (
+        "list",
+        "filter",
+        &["filter"],
+        exactly(2),
+        "Elements for which a function returns a truthy value",
+        filter,
+    ),
+    (
+        "list",
+        "reduce",
+        &["reduce"],
+        exactly(3),
+        "Fold a list with a function",
+        reduce,
+    ),
+    (
+        "list",
+        "range",
+        &["range"],
+        between(1, 3),
+        "List of numbers from start up to stop",
+        range,
+    ),
+    (
+        "map",
+        "new",
+        &["map"],
+        at_least(0),
+        "Map from alternating keys and values",
+        map_new,
+    ),
+    (
+        "map",
+        "get",
+        &["map_get"],
+        exactly(2),
+        "Value of a key in a map",
+        map_get,
+    ),
+    (
+        "str",
+        "from",
+        &["string", "to_string"],
+        exactly(1),
+        "Render a value as text",
+        string,
+    ),
+    (
+        "str",
+        "parse_number",
+        &["parse_number"],
+        exactly(1),
+        "Number written in a string, or nil",
+        parse_number,
+    ),
+    (
+        "str",
+        "substring",
+        &["substring"],
+        exactly(3),
+        "Characters from start up to end",
+        substring,
+    ),
+    (
+        "str",
+        "char_at",
+        &["char_at"],
+        exactly(2),
+        "Character at an index",
+        char_at,
+    ),
+    (
+        "str",
+        "format",
+        &["format"],
+        at_least(1),
+        "Fill {} placeholders in a template",
+        format,
+    ),
+    (
+        "bytes",
+        "len",
+        &["bytes_len"],
+        exactly(1),
+        "Number of bytes",
+        bytes_len,
+    ),
+    (
+        "bytes",
+        "slice",
+        &["bytes_slice"],
+        exactly(3),
+        "Bytes from start up to end",
+        bytes_slice,
+    ),
+    (
+        "weak",
+        "ref",
+        &["weak_ref"],
+        exactly(1),
+        "Weak reference to a value",
+        weak_ref,
+    ),
+    (
+        "weak",
+        "get",
+        &["weak_get"],
+        exactly(1),
+        "Target of a weak reference, or nil",
+        weak_get,
+    ),
+    (
+        "type",
+        "of",
+        &["type_of"],
+        exactly(1),
+        "Kind of a value",
+        type_of,
+    ),
+    (
+        "type",
+        "is_number",
+        &["is_number"],
+        exactly(1),
+        "Whether a value is a number",
+        is_number,
+    ),
+    (
+        "type",
+        "is_string",
+        &["is_string"],
+        exactly(1),
+        "Whether a value is a string",
+        is_string,
+    ),
+    (
+        "type",
+        "is_list",
+        &["is_list"],
+        exactly(1),
+        "Whether a value is a list",
+        is_list,
+    ),
+    (
+        "type",
+        "is_nil",
+        &["is_nil"],
+        exactly(1),
+        "Whether a value is nil",
+        is_nil,
+    ),
+    (
+        "test",
+        "assert",
+        &["assert"],
+        exactly(2),
+        "Report an error if a condition is falsey",
+        assert,
+    ),
+    (
+        "test",
+        "expect_eq",
+        &["expect_eq"],
+        exactly(2),
+        "Report an error if two values differ",
+        expect_eq,
+    ),
+];
+
+impl RegisteredNative {
+    pub fn qualified_name(&self) -> String {
+        format!("{}.{}", self.namespace, self.name)
+    }
+}
+
+/// Description of a native, as returned by
+/// [`Vm::natives`](crate::vm::Vm::natives)
+#[derive(Clone, Debug, Serialize)]
+pub struct NativeInfo {
+    /// The name graphs call it by, qualified by the namespace if it has one
+    pub name: String,
+    /// Other names graphs can call it by
+    pub aliases: Vec<String>,
+    /// Number of arguments, `None` if it varies
+    pub arity: Option<usize>,
+    /// The numbers of arguments it accepts
+    pub args: ArgCount,
+    pub namespace: Option<String>,
+    pub description: String,
+}
+
+impl From<&RegisteredNative> for NativeInfo {
+    fn from(native: &RegisteredNative) -> Self {
+        Self {
+            name: native.qualified_name(),
+            aliases: native
+                .aliases
+                .iter()
+                .map(|alias| alias.to_string())
+                .collect(),
+            arity: native.args.max.filter(|max| *max == native.args.min),
+            args: native.args,
+            namespace: Some(native.namespace.clone()),
+            description: native.description.clone(),
+        }
+    }
+}
+
+impl NativeRegistry {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a native callable as `namespace.name`. If `arity` is given, calls
+    /// with a different number of arguments fail before reaching the function.
+    /// The description is reported by [`Vm::natives`](crate::vm::Vm::natives).
+    pub fn add(
+        &mut self,
+        namespace: &str,
+        name: &str,
+        arity: Option<usize>,
+        description: &str,
+        function: impl Fn(&[Value], &mut Vm) -> Result<Value> + 'static,
+    ) -> &mut Self {
+        self.natives.push(RegisteredNative {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            description: description.to_string(),
+            aliases: &[],
+            arity,
+            args: ArgCount::from_arity(arity),
+            function: Rc::new(function),
+        });
+        self
+    }
+
+    /// The qualified names of the natives, in the order they were added
+    pub fn names(&self) -> impl Iterator<Item = String> + '_ {
+        self.natives.iter().map(RegisteredNative::qualified_name)
+    }
+
+    /// The natives every VM starts with, leaving out those which need a
+    /// capability that isn't allowed
+    #[must_use]
+    pub fn standard(capabilities: &Capabilities) -> Self {
+        let mut entries = STANDARD.to_vec();
+        if capabilities.allow_time {
+            entries.extend_from_slice(TIME);
+        }
+        if capabilities.allow_random {
+            entries.extend_from_slice(RANDOM);
+        }
+        if capabilities.allow_host_data {
+            entries.extend_from_slice(HOST_DATA);
+        }
+        let natives = entries
+            .into_iter()
+            .map(
+                |(namespace, name, aliases, args, description, function)| RegisteredNative {
+                    namespace: namespace.to_string(),
+                    name: name.to_string(),
+                    description: description.to_string(),
+                    aliases,
+                    arity: None,
+                    args,
+                    function: Rc::new(function),
+                },
+            )
+            .collect();
+        Self { natives }
+    }
+}
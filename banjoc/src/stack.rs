@@ -1,35 +1,47 @@
 use std::{
     fmt::{Debug, Write},
+    iter,
     mem::MaybeUninit,
 };
 
-use crate::gc::{GarbageCollect, Gc};
+use crate::{
+    error::{Error, Result},
+    gc::{GarbageCollect, Gc},
+};
 
-pub struct Stack<T, const N: usize> {
-    data: [MaybeUninit<T>; N],
+pub struct Stack<T> {
+    data: Box<[MaybeUninit<T>]>,
     /// Points just past the last used element of the stack
     /// TODO: Use pointer instead of index?
     index: usize,
 }
 
-impl<T, const N: usize> Stack<T, N>
+impl<T> Stack<T>
 where
     T: Default,
 {
-    const INIT: MaybeUninit<T> = MaybeUninit::uninit();
-    pub fn new() -> Self {
+    /// Create a stack which can hold at most `capacity` elements. The memory
+    /// is allocated up front and never moves.
+    pub fn with_capacity(capacity: usize) -> Self {
         Stack {
-            data: [Self::INIT; N],
+            data: iter::repeat_with(MaybeUninit::uninit)
+                .take(capacity)
+                .collect(),
             index: 0,
         }
     }
 
-    pub fn push(&mut self, value: T) {
-        debug_assert!(self.index < N);
+    /// Push a value, failing with a runtime error rather than writing past the
+    /// end of the stack
+    pub fn push(&mut self, value: T) -> Result<()> {
+        if self.index == self.capacity() {
+            return Error::runtime_err("Stack overflow.");
+        }
         unsafe {
             *self.data.get_unchecked_mut(self.index) = MaybeUninit::new(value);
             self.index += 1;
         }
+        Ok(())
     }
 
     pub fn pop(&mut self) -> T {
@@ -40,11 +52,13 @@ where
         }
     }
 
-    pub fn pop_n<'a>(&mut self, num: usize) -> &'a [T] {
+    /// The top `num` values, bottom first, without popping them. The slice
+    /// stays valid while the values remain on the stack, since the stack's
+    /// memory never moves.
+    pub fn peek_n<'a>(&self, num: usize) -> &'a [T] {
         debug_assert!(self.index >= num);
         unsafe {
-            self.index -= num;
-            std::slice::from_raw_parts(self.data.get_unchecked_mut(self.index).as_ptr(), num)
+            std::slice::from_raw_parts(self.data.get_unchecked(self.index - num).as_ptr(), num)
         }
     }
 
@@ -52,14 +66,14 @@ where
     /// e.g. stack: 0,1,2,3
     /// stack.truncate(2) -> stack: 0,1
     pub fn truncate(&mut self, length: usize) {
-        debug_assert!(length <= N);
+        debug_assert!(length <= self.capacity());
         debug_assert!(length <= self.index);
         self.index = length;
     }
 
     pub fn peek(&self, distance: usize) -> &T {
         debug_assert!(distance < self.index);
-        let index = (self.index - distance - 1) as usize;
+        let index = self.index - distance - 1;
         unsafe { self.data.get_unchecked(index).assume_init_ref() }
     }
 
@@ -77,26 +91,26 @@ where
         }
     }
 
+    /// The used part of the stack, bottom first
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.data.as_ptr().cast::<T>(), self.index) }
+    }
+
     pub fn len(&self) -> usize {
         self.index
     }
 
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
     pub fn get_offset(&self) -> usize {
         debug_assert!(self.index > 0);
         self.index - 1
     }
 }
 
-impl<T, const N: usize> Default for Stack<T, N>
-where
-    T: Default,
-{
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-impl<T, const N: usize> Debug for Stack<T, N>
+impl<T> Debug for Stack<T>
 where
     T: Default + Debug,
 {
@@ -109,7 +123,7 @@ where
     }
 }
 
-impl<T, const N: usize> GarbageCollect for Stack<T, N>
+impl<T> GarbageCollect for Stack<T>
 where
     T: GarbageCollect,
 {
@@ -128,12 +142,12 @@ mod tests {
     #[test]
     fn test_stack() {
         const MAX: usize = 1000;
-        let mut stack = Stack::<usize, MAX>::new();
+        let mut stack = Stack::<usize>::with_capacity(MAX);
         for i in 0..MAX {
-            stack.push(i);
+            stack.push(i).unwrap();
             assert_eq!(stack.peek(0), &i);
             for j in 0..i {
-                assert_eq!(stack.read(j as usize), &j);
+                assert_eq!(stack.read(j), &j);
             }
         }
 
@@ -142,4 +156,14 @@ mod tests {
             assert_eq!(popped, i);
         }
     }
+
+    #[test]
+    fn test_overflow() {
+        let mut stack = Stack::<usize>::with_capacity(2);
+        assert!(stack.push(0).is_ok());
+        assert!(stack.push(1).is_ok());
+        assert!(matches!(stack.push(2), Err(Error::Runtime(_))));
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack.peek(0), &1);
+    }
 }
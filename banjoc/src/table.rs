@@ -1,4 +1,4 @@
-use std::{cmp::max, iter};
+use std::{cmp::max, mem};
 
 use crate::{
     gc::{GarbageCollect, Gc, GcRef},
@@ -6,55 +6,112 @@ use crate::{
     value::Value,
 };
 
+#[derive(Clone, Copy)]
 struct Entry {
     // The table doesn't own any of the strings used as keys.
     // Their lifetime is the responsibility of the gc
     key: Option<GcRef<BanjoString>>,
+    /// Copy of the key's hash, so that probing doesn't have to follow the
+    /// pointer to the string
+    hash: u32,
     value: Value,
 }
 
+impl Entry {
+    const EMPTY: Entry = Entry {
+        key: None,
+        hash: 0,
+        value: Value::Nil,
+    };
+    /// Left in the old entries while growing, in place of the entries which
+    /// were moved or removed
+    const TOMBSTONE: Entry = Entry {
+        key: None,
+        hash: 0,
+        value: Value::Bool(true),
+    };
+
+    fn is_empty(&self) -> bool {
+        self.key.is_none() && matches!(self.value, Value::Nil)
+    }
+}
+
 /// A hashmap with key: `BanjoString` and val: Value
+///
+/// Collisions are resolved with robin-hood linear probing: an entry being
+/// inserted takes the place of any entry which is closer to its ideal bucket,
+/// which keeps probe sequences short and lets lookups stop early. Removal
+/// shifts the following entries back instead of leaving tombstones.
+///
+/// Growing doesn't rehash every entry at once. The previous entries are kept
+/// aside and moved a few at a time by each insertion, so that no single
+/// insertion pays for the whole table.
 pub struct Table {
-    // Number of populated entries plus tombstones
+    // Number of populated entries, including those not yet moved out of `old`
     count: usize,
+    /// Power of two sized, so that buckets can be found with a mask
     entries: Vec<Entry>,
+    /// The entries from before the last time the table grew, which are still
+    /// being moved into `entries`. Only ever contains tombstones in place of
+    /// removed entries, which lookups probe past.
+    old: Vec<Entry>,
+    /// Index of the next entry of `old` to move
+    moved: usize,
 }
 
 impl Table {
-    const MAX_LOAD: f64 = 0.75;
+    /// Number of old entries moved by each insertion while growing. Moving two
+    /// for each insertion finishes well before the table needs to grow again.
+    const MOVE_STEP: usize = 2;
+
     pub fn new() -> Self {
         Self {
             count: 0,
             entries: vec![],
+            old: vec![],
+            moved: 0,
         }
     }
 
     pub fn insert(&mut self, key: GcRef<BanjoString>, value: Value) -> bool {
-        if self.count + 1 > (self.capacity() as f64 * Table::MAX_LOAD) as usize {
+        // Keep the load factor below 3/4
+        if (self.count + 1) * 4 > self.capacity() * 3 {
             self.grow();
         }
+        self.move_old(Self::MOVE_STEP);
 
-        let entry = find_entry_mut(&mut self.entries, key);
-        let is_new_key = entry.key.is_none();
-        if is_new_key && matches!(entry.value, Value::Nil) {
+        // A key which hasn't been moved yet is moved now, so that it's never in both
+        let was_old = match find_index(&self.old, key.hash, |k| k == key) {
+            Some(index) => {
+                self.old[index] = Entry::TOMBSTONE;
+                true
+            }
+            None => false,
+        };
+        let entry = Entry {
+            key: Some(key),
+            hash: key.hash,
+            value,
+        };
+        let is_new_key = insert_entry(&mut self.entries, entry) && !was_old;
+        if is_new_key {
             self.count += 1;
         }
-        entry.key = Some(key);
-        entry.value = value;
 
         is_new_key
     }
 
+    #[cfg(test)]
     pub fn get(&self, key: GcRef<BanjoString>) -> Option<Value> {
         if self.count == 0 {
             return None;
         }
 
-        let entry = find_entry(&self.entries, key);
-        if entry.key.is_some() {
-            Some(entry.value)
+        let is_key = |k| k == key;
+        if let Some(index) = find_index(&self.entries, key.hash, is_key) {
+            Some(self.entries[index].value)
         } else {
-            None
+            find_index(&self.old, key.hash, is_key).map(|index| self.old[index].value)
         }
     }
 
@@ -63,14 +120,15 @@ impl Table {
             return false;
         }
 
-        let entry = find_entry_mut(&mut self.entries, key);
-        if entry.key.is_none() {
+        let is_key = |k| k == key;
+        if let Some(index) = find_index(&self.entries, key.hash, is_key) {
+            remove_index(&mut self.entries, index);
+        } else if let Some(index) = find_index(&self.old, key.hash, is_key) {
+            self.old[index] = Entry::TOMBSTONE;
+        } else {
             return false;
         }
-
-        // Place a tombstone in the entry
-        entry.key = None;
-        entry.value = Value::Bool(true);
+        self.count -= 1;
         true
     }
 
@@ -79,64 +137,75 @@ impl Table {
             return None;
         }
 
-        let mut index = hash as usize & (self.capacity() - 1);
-
-        loop {
-            let entry = &self.entries[index];
-            match entry.key {
-                Some(key) => {
-                    if key.as_str().len() == string.len()
-                        && key.hash == hash
-                        && key.as_str() == string
-                    {
-                        // We found it
-                        return Some(key);
-                    }
-                }
-                None => {
-                    // Stop if we find an empty non-tombstone entry
-                    if matches!(entry.value, Value::Nil) {
-                        return None;
-                    }
-                }
-            }
-            index = (index + 1) & (self.capacity() - 1);
-        }
+        let is_string = |key: GcRef<BanjoString>| key.as_str() == string;
+        find_index(&self.entries, hash, is_string)
+            .map(|index| &self.entries[index])
+            .or_else(|| find_index(&self.old, hash, is_string).map(|index| &self.old[index]))
+            .and_then(|entry| entry.key)
     }
 
     /// Clear out any dangling pointers for strings that are freed
     pub fn remove_white(&mut self) {
-        for i in 0..self.capacity() {
-            let entry = &self.entries[i];
+        self.retain(|key, _| key.is_marked());
+    }
+
+    /// Remove every entry for which `f` returns false
+    pub fn retain(&mut self, mut f: impl FnMut(GcRef<BanjoString>, Value) -> bool) {
+        self.move_old(usize::MAX);
+        let capacity = self.capacity();
+        let mask = capacity.wrapping_sub(1);
+        // Start at an entry which is never shifted back by a removal, so that the
+        // shifts can't wrap around into entries which were already visited
+        let Some(start) = (0..capacity).find(|&index| {
+            let entry = &self.entries[index];
+            entry.is_empty() || distance(entry.hash, index, mask) == 0
+        }) else {
+            return;
+        };
+
+        let mut visited = 0;
+        let mut index = start;
+        while visited < capacity {
+            let entry = self.entries[index];
             if let Some(key) = entry.key {
-                if !key.is_marked() {
-                    self.remove(key);
+                if !f(key, entry.value) {
+                    // The next entry may have been shifted into this one, so visit it again
+                    remove_index(&mut self.entries, index);
+                    self.count -= 1;
+                    continue;
                 }
             }
+            index = (index + 1) & mask;
+            visited += 1;
         }
     }
 
     fn grow(&mut self) {
+        // Finish moving the entries from last time before starting again
+        self.move_old(usize::MAX);
+
         // Double the capacity
         let new_capacity = max(8, self.capacity() * 2);
-        let mut new: Vec<_> = iter::repeat_with(|| Entry {
-            key: None,
-            value: Value::Nil,
-        })
-        .take(new_capacity)
-        .collect();
-
-        self.count = 0;
-        for entry in &self.entries {
-            if let Some(key) = entry.key {
-                let dest = find_entry_mut(&mut new, key);
-                dest.key = entry.key;
-                dest.value = entry.value;
-                self.count += 1;
+        let new = vec![Entry::EMPTY; new_capacity];
+        self.old = mem::replace(&mut self.entries, new);
+        self.moved = 0;
+    }
+
+    /// Move up to `step` entries of `old` into `entries`
+    fn move_old(&mut self, step: usize) {
+        let end = self.old.len().min(self.moved.saturating_add(step));
+        for index in self.moved..end {
+            let entry = mem::replace(&mut self.old[index], Entry::TOMBSTONE);
+            if entry.key.is_some() {
+                insert_entry(&mut self.entries, entry);
             }
         }
+        self.moved = end;
 
-        self.entries = new;
+        if self.moved == self.old.len() {
+            self.old = vec![];
+            self.moved = 0;
+        }
     }
 
     fn capacity(&self) -> usize {
@@ -144,82 +213,107 @@ impl Table {
     }
 }
 
-fn find_entry(entries: &[Entry], key: GcRef<BanjoString>) -> &Entry {
-    let mut index = key.hash as usize & (entries.len() - 1);
-    // The first seen tombstone
-    let mut tombstone = None;
+/// How far the entry at `index` is from the bucket it would ideally be in
+fn distance(hash: u32, index: usize, mask: usize) -> usize {
+    index.wrapping_sub(hash as usize) & mask
+}
+
+/// The index of the entry with the given hash whose key matches
+fn find_index(
+    entries: &[Entry],
+    hash: u32,
+    is_key: impl Fn(GcRef<BanjoString>) -> bool,
+) -> Option<usize> {
+    if entries.is_empty() {
+        return None;
+    }
+
+    let mask = entries.len() - 1;
+    let mut index = hash as usize & mask;
+    let mut dist = 0;
 
     loop {
         let entry = &entries[index];
-        if let Some(k) = entry.key {
-            if k == key {
-                // We found the key
-                return entry;
-            }
-        } else {
-            match entry.value {
-                Value::Nil => {
-                    // Empty entry
-                    return if let Some(tombstone) = tombstone {
-                        tombstone
-                    } else {
-                        entry
-                    };
+        match entry.key {
+            Some(key) => {
+                if entry.hash == hash && is_key(key) {
+                    // We found the key
+                    return Some(index);
+                }
+                // The key would have displaced this entry if it were in the table
+                if distance(entry.hash, index, mask) < dist {
+                    return None;
                 }
-                _ => {
-                    // We found a tombstone
-                    if tombstone.is_none() {
-                        tombstone = Some(entry);
-                    }
+            }
+            None => {
+                // Stop if we find an empty non-tombstone entry
+                if entry.is_empty() {
+                    return None;
                 }
             }
         }
 
-        // Collision: linear probe
-        index = (index + 1) & (entries.len() - 1);
+        index = (index + 1) & mask;
+        dist += 1;
+        if dist > mask {
+            return None;
+        }
     }
 }
 
-fn find_entry_mut(entries: &mut [Entry], key: GcRef<BanjoString>) -> &mut Entry {
-    let len = entries.len();
-    let mut index = key.hash as usize & (len - 1);
-    // The first seen tombstone
-    let mut tombstone = None;
+/// Insert the entry, replacing the value of any entry with the same key.
+/// Returns true if the key is new.
+fn insert_entry(entries: &mut [Entry], mut entry: Entry) -> bool {
+    let mask = entries.len() - 1;
+    let mut index = entry.hash as usize & mask;
+    let mut dist = 0;
 
     loop {
-        let entry = &entries[index];
-        if let Some(k) = entry.key {
-            if k == key {
-                // We found the key
-                return &mut entries[index];
+        let slot = &mut entries[index];
+        match slot.key {
+            None => {
+                *slot = entry;
+                return true;
             }
-        } else {
-            match entry.value {
-                Value::Nil => {
-                    // Empty entry
-                    return if let Some(tombstone) = tombstone {
-                        &mut entries[tombstone]
-                    } else {
-                        &mut entries[index]
-                    };
-                }
-                _ => {
-                    // We found a tombstone
-                    if tombstone.is_none() {
-                        tombstone = Some(index);
-                    }
+            Some(key) if key == entry.key.unwrap() => {
+                // An existing key is always found before any entry is displaced
+                slot.value = entry.value;
+                return false;
+            }
+            Some(_) => {
+                // Take from the rich: displace entries closer to their ideal bucket
+                let slot_dist = distance(slot.hash, index, mask);
+                if slot_dist < dist {
+                    mem::swap(slot, &mut entry);
+                    dist = slot_dist;
                 }
             }
         }
 
-        // Collision: linear probe
-        index = (index + 1) & (len - 1);
+        index = (index + 1) & mask;
+        dist += 1;
+    }
+}
+
+/// Empty the entry at `index`, shifting back the entries which follow it until
+/// one that is empty or already in its ideal bucket
+fn remove_index(entries: &mut [Entry], mut index: usize) {
+    let mask = entries.len() - 1;
+    loop {
+        let next = (index + 1) & mask;
+        let entry = entries[next];
+        if entry.is_empty() || distance(entry.hash, next, mask) == 0 {
+            entries[index] = Entry::EMPTY;
+            return;
+        }
+        entries[index] = entry;
+        index = next;
     }
 }
 
 impl GarbageCollect for Table {
     fn mark_gray(&mut self, gc: &mut Gc) {
-        for entry in &mut self.entries {
+        for entry in self.entries.iter_mut().chain(&mut self.old) {
             if let Some(mut key) = entry.key {
                 key.mark_gray(gc);
                 entry.value.mark_gray(gc);
@@ -290,6 +384,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn churn_while_growing() {
+        // Generate some strings
+        let mut strings: Vec<_> = (0..10_000)
+            .map(|n| BanjoString::new(n.to_string()))
+            .collect();
+
+        // Simulate being held by gc
+        let refs = make_refs(&mut strings);
+
+        // Remove and overwrite entries while the table is still moving entries from
+        // before it grew
+        let mut t = Table::new();
+        let value = |key| Value::Number(f64::from(str_to_num(key)));
+        for (i, key) in refs.iter().enumerate() {
+            assert!(t.insert(*key, Value::Nil));
+            if i % 2 == 1 {
+                assert!(t.remove(refs[i / 2]));
+                assert!(t.insert(refs[i / 2], value(refs[i / 2])));
+            }
+            assert!(!t.insert(*key, value(*key)));
+        }
+        assert_eq!(t.count, refs.len());
+
+        // Remove two thirds of the entries
+        t.retain(|key, _| str_to_num(key) % 3 == 1);
+        for key in refs {
+            let expected = str_to_num(key) % 3 == 1;
+            assert_eq!(t.get(key).is_some(), expected);
+            if expected {
+                assert_eq!(to_num(t.get(key).unwrap()), str_to_num(key));
+                assert!(t.find_string(key.as_str(), key.hash) == Some(key));
+            }
+        }
+    }
+
     fn make_refs(strings: &mut [BanjoString]) -> Vec<GcRef<BanjoString>> {
         strings
             .iter_mut()
@@ -1,28 +1,42 @@
 use std::{
+    cmp::Ordering,
     fmt,
     fmt::{Debug, Formatter},
     iter,
+    ptr::NonNull,
 };
 
-use serde::{ser::SerializeSeq, Serialize, Serializer};
+use serde::{
+    ser::{SerializeMap, SerializeSeq},
+    Serialize, Serializer,
+};
 
 use crate::{
+    config::OutputLimits,
     error::{Error, Result},
     gc::{GarbageCollect, Gc, GcRef},
-    obj::{BanjoString, Function, List, NativeFunction},
+    obj::{BanjoString, Buffer, Function, List, Map, MapKey, NativeFunction, WeakRef},
     vm::Vm,
 };
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub enum Value {
+    #[default]
     Nil,
     Bool(bool),
     Number(f64),
+    /// Exact integer, for IDs and counters beyond the 2^53 which `Number`
+    /// represents exactly. Arithmetic between ints stays exact, unless it
+    /// overflows.
+    Int(i64),
     // Following are pointers to garbage collected objects. Value is NOT deep copied.
     String(GcRef<BanjoString>),
     List(GcRef<List>),
     NativeFunction(GcRef<NativeFunction>),
     Function(GcRef<Function>),
+    WeakRef(GcRef<WeakRef>),
+    Map(GcRef<Map>),
+    Bytes(GcRef<Buffer>),
 }
 
 impl Value {
@@ -35,17 +49,59 @@ impl Value {
         }
     }
 
+    /// Name of the kind of value, as seen by graphs. Ints are numbers, and
+    /// natives are functions.
+    #[must_use]
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Number(_) | Value::Int(_) => "number",
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::NativeFunction(_) | Value::Function(_) => "function",
+            Value::WeakRef(_) => "weak",
+            Value::Map(_) => "map",
+            Value::Bytes(_) => "bytes",
+        }
+    }
+
+    /// Whether the garbage collector has marked the object, or `None` if
+    /// the value isn't an object
+    pub(crate) fn is_marked(&self) -> Option<bool> {
+        match self {
+            Value::Nil | Value::Bool(_) | Value::Number(_) | Value::Int(_) => None,
+            Value::String(x) => Some(x.is_marked()),
+            Value::List(x) => Some(x.is_marked()),
+            Value::NativeFunction(x) => Some(x.is_marked()),
+            Value::Function(x) => Some(x.is_marked()),
+            Value::WeakRef(x) => Some(x.is_marked()),
+            Value::Map(x) => Some(x.is_marked()),
+            Value::Bytes(x) => Some(x.is_marked()),
+        }
+    }
+
     pub fn add(self, rhs: Self, vm: &mut Vm) -> Self {
-        // Adding to nil or functions is basically a noop
+        // Adding to nil, functions, weak references, maps or bytes is basically a noop
         if matches!(
             self,
-            Value::Nil | Value::Function(_) | Value::NativeFunction(_)
+            Value::Nil
+                | Value::Function(_)
+                | Value::NativeFunction(_)
+                | Value::WeakRef(_)
+                | Value::Map(_)
+                | Value::Bytes(_)
         ) {
             return rhs;
         }
         if matches!(
             rhs,
-            Value::Nil | Value::Function(_) | Value::NativeFunction(_)
+            Value::Nil
+                | Value::Function(_)
+                | Value::NativeFunction(_)
+                | Value::WeakRef(_)
+                | Value::Map(_)
+                | Value::Bytes(_)
         ) {
             return self;
         }
@@ -57,24 +113,24 @@ impl Value {
                     a.values
                         .iter()
                         .zip(b.values.iter().chain(iter::repeat(&Value::Nil)))
-                        .map(|(a, b)| a.add(*b, vm))
+                        .map(|(a, b)| a.add_rooted(*b, vm))
                         .collect()
                 } else {
                     b.values
                         .iter()
                         .zip(a.values.iter().chain(iter::repeat(&Value::Nil)))
-                        .map(|(a, b)| b.add(*a, vm))
+                        .map(|(a, b)| b.add_rooted(*a, vm))
                         .collect()
                 };
 
                 return Value::List(vm.alloc(List::new(values)));
             } else {
-                let values = a.values.iter().map(|v| v.add(rhs, vm)).collect();
+                let values = a.values.iter().map(|v| v.add_rooted(rhs, vm)).collect();
                 return Value::List(vm.alloc(List::new(values)));
             }
         }
         if let Value::List(b) = rhs {
-            let values = b.values.iter().map(|v| self.add(*v, vm)).collect();
+            let values = b.values.iter().map(|v| self.add_rooted(*v, vm)).collect();
             return Value::List(vm.alloc(List::new(values)));
         }
 
@@ -82,56 +138,519 @@ impl Value {
             Value::Bool(a) => match rhs {
                 Value::Bool(b) => Value::Number(a as i32 as f64 + b as i32 as f64),
                 Value::Number(b) => Value::Number(a as i32 as f64 + b),
+                Value::Int(b) => add_ints(a as i64, b),
                 Value::String(b) => Value::String(vm.intern(&format!("{}{}", a, b.as_str()))),
-                Value::NativeFunction(_) | Value::Function(_) | Value::List(_) | Value::Nil => {
-                    unreachable!()
-                }
+                Value::NativeFunction(_)
+                | Value::Function(_)
+                | Value::List(_)
+                | Value::WeakRef(_)
+                | Value::Map(_)
+                | Value::Bytes(_)
+                | Value::Nil => unreachable!(),
             },
             Value::Number(a) => match rhs {
                 Value::Bool(b) => Value::Number(a + b as i32 as f64),
                 Value::Number(b) => Value::Number(a + b),
+                Value::Int(b) => match rhs.int_operands(self) {
+                    (_, Some(a)) => add_ints(a, b),
+                    _ => Value::Number(a + b as f64),
+                },
                 Value::String(b) => Value::String(vm.intern(&format!("{}{}", a, b.as_str()))),
-                Value::NativeFunction(_) | Value::Function(_) | Value::List(_) | Value::Nil => {
-                    unreachable!()
-                }
+                Value::NativeFunction(_)
+                | Value::Function(_)
+                | Value::List(_)
+                | Value::WeakRef(_)
+                | Value::Map(_)
+                | Value::Bytes(_)
+                | Value::Nil => unreachable!(),
+            },
+            Value::Int(a) => match rhs {
+                Value::Bool(b) => add_ints(a, b as i64),
+                Value::Number(b) => match self.int_operands(rhs) {
+                    (_, Some(b)) => add_ints(a, b),
+                    _ => Value::Number(a as f64 + b),
+                },
+                Value::Int(b) => add_ints(a, b),
+                Value::String(b) => Value::String(vm.intern(&format!("{}{}", a, b.as_str()))),
+                Value::NativeFunction(_)
+                | Value::Function(_)
+                | Value::List(_)
+                | Value::WeakRef(_)
+                | Value::Map(_)
+                | Value::Bytes(_)
+                | Value::Nil => unreachable!(),
             },
             Value::String(a) => match rhs {
                 Value::Bool(b) => Value::String(vm.intern(&format!("{}{}", a.as_str(), b))),
                 Value::Number(b) => Value::String(vm.intern(&format!("{}{}", a.as_str(), b))),
+                Value::Int(b) => Value::String(vm.intern(&format!("{}{}", a.as_str(), b))),
                 Value::String(b) => {
                     Value::String(vm.intern(&format!("{}{}", a.as_str(), b.as_str())))
                 }
-                Value::NativeFunction(_) | Value::Function(_) | Value::List(_) | Value::Nil => {
-                    unreachable!()
-                }
+                Value::NativeFunction(_)
+                | Value::Function(_)
+                | Value::List(_)
+                | Value::WeakRef(_)
+                | Value::Map(_)
+                | Value::Bytes(_)
+                | Value::Nil => unreachable!(),
             },
-            Value::NativeFunction(_) | Value::Function(_) | Value::List(_) | Value::Nil => {
-                unreachable!()
-            }
+            Value::NativeFunction(_)
+            | Value::Function(_)
+            | Value::List(_)
+            | Value::WeakRef(_)
+            | Value::Map(_)
+            | Value::Bytes(_)
+            | Value::Nil => unreachable!(),
         }
     }
 
-    pub fn binary_op(self, rhs: Self, f: impl Fn(f64, f64) -> Value) -> Result<Self> {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Ok(f(a, b)),
+    /// Elements of a new list must survive until the list itself is allocated
+    fn add_rooted(self, rhs: Self, vm: &mut Vm) -> Self {
+        let value = self.add(rhs, vm);
+        vm.root(value);
+        value
+    }
+
+    /// Apply `int` if an operand is an int and the other is integral, falling
+    /// back to `float` if they aren't or if `int` returns `None`, e.g.
+    /// because of an overflow
+    pub fn binary_op(
+        self,
+        rhs: Self,
+        float: impl Fn(f64, f64) -> Value,
+        int: impl Fn(i64, i64) -> Option<Value>,
+    ) -> Result<Self> {
+        if let (Some(a), Some(b)) = self.int_operands(rhs) {
+            if let Some(result) = int(a, b) {
+                return Ok(result);
+            }
+        }
+        match (self.as_f64(), rhs.as_f64()) {
+            (Some(a), Some(b)) => Ok(float(a, b)),
             _ => Error::runtime_err("Operands must be numbers."),
         }
     }
+
+    /// Render with the given number format, using
+    /// [`Display`](fmt::Display) or [`Serialize`]
+    #[must_use]
+    pub fn formatted(self, number_format: NumberFormat) -> Formatted {
+        Formatted {
+            value: self,
+            number_format,
+            limits: OutputLimits::default(),
+        }
+    }
+
+    /// Convert to JSON, the same way values are serialized in an
+    /// [`Output`](crate::output::Output)
+    #[must_use]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    /// Build a value from JSON, allocating strings, lists and maps on the
+    /// heap of `vm`. Objects become maps keyed by strings. Like any other
+    /// value, the result must be [rooted](Vm::root) to survive a collection.
+    pub fn from_json(vm: &mut Vm, json: &serde_json::Value) -> Value {
+        vm.root_scope(|vm| Self::from_json_rooted(vm, json))
+    }
+
+    /// Like [`from_json`](Self::from_json), but roots every value it creates
+    /// in the current scope, so that they survive the following allocations
+    fn from_json_rooted(vm: &mut Vm, json: &serde_json::Value) -> Value {
+        let value = match json {
+            serde_json::Value::Null => Value::Nil,
+            serde_json::Value::Bool(b) => Value::Bool(*b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) if exact_number(i).is_none() => Value::Int(i),
+                _ => Value::Number(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            serde_json::Value::String(s) => Value::String(vm.intern(s)),
+            serde_json::Value::Array(elements) => {
+                let values = elements
+                    .iter()
+                    .map(|element| Self::from_json_rooted(vm, element))
+                    .collect();
+                Value::List(vm.alloc(List::new(values)))
+            }
+            serde_json::Value::Object(object) => {
+                let entries = object
+                    .iter()
+                    .map(|(key, value)| {
+                        let key = vm.intern(key);
+                        vm.root(Value::String(key));
+                        (MapKey::String(key), Self::from_json_rooted(vm, value))
+                    })
+                    .collect();
+                let map = Map::new(entries).expect("JSON object keys are distinct");
+                Value::Map(vm.alloc(map))
+            }
+        };
+        vm.root(value);
+        value
+    }
+
+    /// Both operands as integers, if at least one of them is an int. Keeps
+    /// arithmetic on ints exact when the other operand is an integral number.
+    fn int_operands(self, rhs: Self) -> (Option<i64>, Option<i64>) {
+        if !matches!(self, Value::Int(_)) && !matches!(rhs, Value::Int(_)) {
+            return (None, None);
+        }
+        (self.as_i64(), rhs.as_i64())
+    }
+
+    fn as_i64(self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(n),
+            Value::Number(n)
+                if n.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(&n) =>
+            {
+                Some(n as i64)
+            }
+            _ => None,
+        }
+    }
+
+    /// The value of a number or an int
+    pub(crate) fn as_f64(self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(n),
+            Value::Int(n) => Some(n as f64),
+            _ => None,
+        }
+    }
+}
+
+/// The number equal to an int, if it can represent the int exactly
+pub(crate) fn exact_number(n: i64) -> Option<f64> {
+    let number = n as f64;
+    // Converting back to i64 would saturate, hiding that i64::MAX rounds up to 2^63
+    (number as i128 == i128::from(n)).then_some(number)
+}
+
+fn add_ints(a: i64, b: i64) -> Value {
+    a.checked_add(b)
+        .map_or(Value::Number(a as f64 + b as f64), Value::Int)
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Nil, Into::into)
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        value
+            .as_f64()
+            .ok_or_else(|| Error::runtime("Expected a number."))
+    }
 }
 
+impl TryFrom<Value> for i64 {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        value
+            .as_i64()
+            .ok_or_else(|| Error::runtime("Expected an integer."))
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            _ => Error::runtime_err("Expected a bool."),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::String(s) => Ok(s.as_str().to_string()),
+            _ => Error::runtime_err("Expected a string."),
+        }
+    }
+}
+
+impl TryFrom<Value> for Vec<Value> {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::List(l) => Ok(l.values.clone()),
+            _ => Error::runtime_err("Expected a list."),
+        }
+    }
+}
+
+/// Lists and maps compare equal when their elements do. A list reached again
+/// while its comparison is still under way can only be part of a cycle, so it
+/// is assumed equal, which keeps cyclic structures from recursing forever.
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
+        self.structural_eq(other, &mut Vec::new())
+    }
+}
+
+impl Value {
+    fn structural_eq(&self, other: &Self, visiting: &mut Vec<(*const (), *const ())>) -> bool {
+        match (self, other) {
+            (Value::List(a), Value::List(b)) => {
+                a == b
+                    || Self::visit(a.pointer, b.pointer, visiting, |visiting| {
+                        a.values.len() == b.values.len()
+                            && iter::zip(&a.values, &b.values)
+                                .all(|(a, b)| a.structural_eq(b, visiting))
+                    })
+            }
+            (Value::Map(a), Value::Map(b)) => {
+                a == b
+                    || Self::visit(a.pointer, b.pointer, visiting, |visiting| {
+                        a.entries.len() == b.entries.len()
+                            && a.entries.iter().all(|(key, a)| {
+                                b.entries
+                                    .get(key)
+                                    .is_some_and(|b| a.structural_eq(b, visiting))
+                            })
+                    })
+            }
+            _ => self.shallow_eq(other),
+        }
+    }
+
+    fn visit<T>(
+        a: NonNull<T>,
+        b: NonNull<T>,
+        visiting: &mut Vec<(*const (), *const ())>,
+        compare: impl FnOnce(&mut Vec<(*const (), *const ())>) -> bool,
+    ) -> bool {
+        let pair = (a.as_ptr() as *const (), b.as_ptr() as *const ());
+        if visiting.contains(&pair) {
+            return true;
+        }
+        visiting.push(pair);
+        let equal = compare(visiting);
+        visiting.pop();
+        equal
+    }
+
+    fn shallow_eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Value::Nil, Value::Nil) => true,
             (Value::Bool(a), Value::Bool(b)) => a == b,
             (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Int(a), Value::Number(b)) | (Value::Number(b), Value::Int(a)) => {
+                exact_number(*a) == Some(*b)
+            }
             (Value::String(a), Value::String(b)) => a == b,
-            (Value::List(a), Value::List(b)) => a == b,
             (Value::NativeFunction(a), Value::NativeFunction(b)) => a == b,
             (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::WeakRef(a), Value::WeakRef(b)) => a == b,
+            (Value::Bytes(a), Value::Bytes(b)) => a.data == b.data,
+            _ => false,
+        }
+    }
+}
+
+/// How numbers are rendered when displaying or serializing values. Ints are
+/// always rendered exactly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NumberFormat {
+    /// The fewest digits which read back as the same number
+    #[default]
+    Shortest,
+    /// Rounded to this many decimal places
+    Fixed(usize),
+    /// Scientific notation, with this many decimal places in the mantissa
+    Scientific(usize),
+}
+
+impl NumberFormat {
+    fn write(self, n: f64, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NumberFormat::Shortest => write!(f, "{n}"),
+            NumberFormat::Fixed(places) => write!(f, "{n:.places$}"),
+            NumberFormat::Scientific(places) => write!(f, "{n:.places$e}"),
+        }
+    }
+
+    /// The number closest to how `n` is rendered
+    fn round(self, n: f64) -> f64 {
+        match self {
+            NumberFormat::Shortest => n,
+            NumberFormat::Fixed(places) => format!("{n:.places$}").parse().unwrap_or(n),
+            NumberFormat::Scientific(places) => format!("{n:.places$e}").parse().unwrap_or(n),
+        }
+    }
+}
+
+/// A value rendered with a [`NumberFormat`], both when displayed and when
+/// serialized
+#[derive(Clone, Copy)]
+pub struct Formatted {
+    value: Value,
+    number_format: NumberFormat,
+    /// Only applied when serializing
+    limits: OutputLimits,
+}
+
+impl Formatted {
+    /// Serialize only as much of the value as `limits` allow
+    #[must_use]
+    pub fn limited(self, limits: OutputLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// Whether serializing leaves out part of the value because of its limits
+    pub fn is_truncated(&self) -> bool {
+        match self.value {
+            Value::String(s) => self.limits.truncate_str(s.as_str()).len() < s.as_str().len(),
+            Value::List(l) => {
+                let len = self.limits.list_len(l.values.len());
+                len < l.values.len()
+                    || l.values[..len]
+                        .iter()
+                        .any(|element| self.nested(*element).is_truncated())
+            }
+            Value::Map(m) => {
+                let len = self.limits.list_len(m.entries.len());
+                len < m.entries.len()
+                    || m.entries
+                        .iter()
+                        .take(len)
+                        .any(|(_, value)| self.nested(*value).is_truncated())
+            }
+            Value::WeakRef(w) => self.nested(w.target).is_truncated(),
             _ => false,
         }
     }
+
+    fn nested(self, value: Value) -> Self {
+        Self { value, ..self }
+    }
+}
+
+impl fmt::Display for Formatted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Value::Number(n) => self.number_format.write(n, f),
+            Value::String(s) => f.write_str(s.as_str()),
+            Value::List(l) => {
+                f.write_str("[")?;
+                for (i, element) in l.values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", self.nested(*element))?;
+                }
+                f.write_str("]")
+            }
+            Value::Map(m) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in m.entries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    match key {
+                        MapKey::String(s) => f.write_str(s.as_str())?,
+                        MapKey::Number(n) => self.number_format.write(*n, f)?,
+                        MapKey::Int(n) => write!(f, "{n}")?,
+                    }
+                    write!(f, ": {}", self.nested(*value))?;
+                }
+                f.write_str("}")
+            }
+            Value::WeakRef(w) => write!(f, "{}", self.nested(w.target)),
+            Value::Bytes(b) => f.write_str(&b.to_base64()),
+            value => Debug::fmt(&value, f),
+        }
+    }
+}
+
+impl Serialize for Formatted {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.value {
+            Value::Bool(b) => serializer.serialize_bool(b),
+            Value::Nil => serializer.serialize_none(),
+            Value::Number(n) => serializer.serialize_f64(self.number_format.round(n)),
+            Value::Int(n) => serializer.serialize_i64(n),
+            Value::String(s) => serializer.serialize_str(self.limits.truncate_str(s.as_str())),
+            Value::List(l) => {
+                let len = self.limits.list_len(l.values.len());
+                let mut seq = serializer.serialize_seq(Some(len))?;
+                for element in &l.values[..len] {
+                    seq.serialize_element(&self.nested(*element))?;
+                }
+                seq.end()
+            }
+            Value::NativeFunction(_) | Value::Function(_) => {
+                serializer.serialize_str(&format!("{:?}", self.value))
+            }
+            Value::WeakRef(x) => self.nested(x.target).serialize(serializer),
+            Value::Map(m) => {
+                let len = self.limits.list_len(m.entries.len());
+                let mut map = serializer.serialize_map(Some(len))?;
+                for (key, value) in m.entries.iter().take(len) {
+                    map.serialize_entry(&key.to_json_key(), &self.nested(*value))?;
+                }
+                map.end()
+            }
+            Value::Bytes(b) => serializer.serialize_str(&b.to_base64()),
+        }
+    }
+}
+
+/// Numbers and ints are ordered by value, strings lexicographically. Other
+/// values aren't ordered.
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (*self, *other) {
+            (Value::String(a), Value::String(b)) => Some(a.as_str().cmp(b.as_str())),
+            (a, b) => match a.int_operands(b) {
+                (Some(a), Some(b)) => Some(a.cmp(&b)),
+                _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.formatted(NumberFormat::default()), f)
+    }
 }
 
 impl Debug for Value {
@@ -140,27 +659,29 @@ impl Debug for Value {
             Value::Nil => f.write_str("nil"),
             Value::Bool(x) => Debug::fmt(&x, f),
             Value::Number(x) => Debug::fmt(&x, f),
+            Value::Int(x) => Debug::fmt(&x, f),
             Value::String(x) => Debug::fmt(&**x, f),
             Value::List(x) => Debug::fmt(&**x, f),
             Value::NativeFunction(x) => Debug::fmt(&**x, f),
             Value::Function(x) => Debug::fmt(&**x, f),
+            Value::WeakRef(x) => Debug::fmt(&**x, f),
+            Value::Map(x) => Debug::fmt(&**x, f),
+            Value::Bytes(x) => Debug::fmt(&**x, f),
         }
     }
 }
 
-impl Default for Value {
-    fn default() -> Self {
-        Self::Nil
-    }
-}
-
 impl GarbageCollect for Value {
     fn mark_gray(&mut self, gc: &mut Gc) {
         match self {
             Value::String(x) => x.mark_gray(gc),
+            Value::List(x) => x.mark_gray(gc),
             Value::NativeFunction(x) => x.mark_gray(gc),
             Value::Function(x) => x.mark_gray(gc),
-            _ => {}
+            Value::WeakRef(x) => x.mark_gray(gc),
+            Value::Map(x) => x.mark_gray(gc),
+            Value::Bytes(x) => x.mark_gray(gc),
+            Value::Nil | Value::Bool(_) | Value::Number(_) | Value::Int(_) => {}
         }
     }
 }
@@ -170,21 +691,7 @@ impl Serialize for Value {
     where
         S: Serializer,
     {
-        match self {
-            Value::Bool(b) => serializer.serialize_bool(*b),
-            Value::Nil => serializer.serialize_none(),
-            Value::Number(n) => serializer.serialize_f64(*n),
-            Value::String(s) => serializer.serialize_str(s.as_str()),
-            Value::List(l) => {
-                let mut seq = serializer.serialize_seq(Some(l.values.len()))?;
-                for element in &l.values {
-                    seq.serialize_element(element)?;
-                }
-                seq.end()
-            }
-            Value::NativeFunction(_) | Value::Function(_) => {
-                serializer.serialize_str(&format!("{self:?}"))
-            }
-        }
+        self.formatted(NumberFormat::default())
+            .serialize(serializer)
     }
 }
@@ -1,47 +1,164 @@
-use std::{fmt, fmt::Write, ptr::null};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    f64::consts,
+    fmt,
+    fmt::Write,
+    iter, mem,
+    ptr::null,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
 
+pub use crate::obj::NativeFn;
 use crate::{
-    ast::{Ast, Source},
+    ast::{Ast, NodeId, Source},
+    chunk,
     compiler::Compiler,
+    config::{GlobalsScope, VmConfig},
+    disassembler,
     error::{Error, Result},
     gc::{GarbageCollect, Gc, GcRef},
-    native_functions::{clock, product, sum},
-    obj::{BanjoString, Function, NativeFn, NativeFunction},
-    op_code::{Constant, LocalIndex, OpCode},
-    output::{Output, OutputValues},
+    globals::Globals,
+    hooks::VmHooks,
+    obj::{BanjoString, Buffer, Function, List, NativeFunction},
+    op_code::{Constant, GlobalSlot, LocalIndex, OpCode},
+    output::{MemoryStats, Output, OutputValues},
+    profile::{Profile, Profiler},
+    program::{CompiledProgram, Inputs},
+    random::Rng,
+    registry::{ArgCount, NativeInfo, NativeRegistry, RegisteredNative},
     stack::Stack,
-    table::Table,
     value::Value,
 };
 
-pub type ValueStack = Stack<Value, { Vm::STACK_MAX }>;
+pub type ValueStack = Stack<Value>;
+
+/// The state of the VM after executing a slice of a program with [`Vm::step`]
+#[allow(clippy::large_enum_variant)]
+pub enum Step {
+    /// The program hasn't finished yet. Call [`Vm::step`] again to continue.
+    Running,
+    /// Execution reached a node with a breakpoint. Call [`Vm::step`] to
+    /// continue.
+    Breakpoint(NodeId),
+    /// The program finished, or halted because of an error
+    Done(Output),
+}
+
+/// A snapshot of one call frame of a paused VM
+#[derive(Debug)]
+pub struct FrameInfo<'vm> {
+    /// Name of the called function, or `<script>` for the top-level code
+    pub function: String,
+    /// For the innermost frame, the node of the next instruction to execute.
+    /// For the other frames, the node which made the call to the next frame.
+    pub node_id: Option<String>,
+    /// The stack slots owned by this frame, starting with the callee
+    pub slots: &'vm [Value],
+}
+
+/// An object on the heap, as listed by [`Vm::heap_dump`]
+#[derive(Debug, Serialize)]
+pub struct HeapObject {
+    /// The type of the object, such as `string` or `list`
+    pub kind: &'static str,
+    /// Bytes taken up by the object, including the buffers it owns
+    pub size: usize,
+    /// The debug representation of the object, shortened if too long
+    pub repr: String,
+}
+
+enum RunState {
+    /// Ran out of instructions to execute in this slice
+    Suspended,
+    Breakpoint(NodeId),
+    Finished,
+}
+
+/// Globals defined as numbers rather than natives
+const CONSTANTS: [(&str, f64); 2] = [("pi", consts::PI), ("e", consts::E)];
+
 pub struct Vm {
     gc: Gc,
     output: OutputValues,
     stack: ValueStack,
-    frames: Stack<CallFrame, { Vm::FRAMES_MAX }>,
-    globals: Table,
+    frames: Stack<CallFrame>,
+    globals: Globals,
+    config: VmConfig,
+    timer: Option<Timer>,
+    profiler: Option<Profiler>,
+    breakpoints: HashSet<NodeId>,
+    /// Execution is continuing from a breakpoint, so don't stop at it again
+    resuming: bool,
+    hooks: Option<Box<dyn VmHooks>>,
+    /// Temporaries pinned by [`Vm::root`]
+    temp_roots: Vec<Value>,
+    /// Number of frames below the function currently being run to completion
+    /// by [`Vm::call_value`]. Zero when not called from a native.
+    base_frame: usize,
+    /// [`Vm::call_value`] is running a function to completion, so it can't
+    /// pause at breakpoints. `base_frame` is zero too when the host calls it.
+    in_call_value: bool,
+    /// Functions of the programs returned by [`Vm::compile`], kept alive
+    /// until the next [`Vm::reset`]
+    programs: Vec<GcRef<Function>>,
+    /// Values replacing the output of nodes during the current run, indexed
+    /// by output index
+    inputs: Vec<Option<Value>>,
+    rng: Rng,
+    /// Every native registered so far, in order of registration
+    native_info: Vec<NativeInfo>,
+    /// Values provided by the host, read by the `data` native
+    host_data: HashMap<String, Value>,
 }
 
 impl Vm {
-    const FRAMES_MAX: usize = 64;
-    const STACK_MAX: usize = Self::FRAMES_MAX * (u8::MAX as usize + 1);
-
     #[must_use]
     pub fn new() -> Vm {
-        let gc = Gc::new();
+        Self::with_config(VmConfig::default())
+    }
+
+    /// Create a VM whose limits are taken from the given config
+    #[must_use]
+    pub fn with_config(config: VmConfig) -> Vm {
+        let gc = Gc::with_config(config.gc.clone(), config.max_heap_bytes);
+        let rng = config
+            .random_seed
+            .map_or_else(Rng::from_entropy, Rng::with_seed);
 
         let mut vm = Vm {
             gc,
-            stack: Stack::new(),
-            frames: Stack::new(),
-            globals: Table::new(),
+            stack: Stack::with_capacity(config.stack_max),
+            frames: Stack::with_capacity(config.frames_max),
+            globals: Globals::default(),
             output: OutputValues::default(),
+            profiler: config.profile.then(Profiler::default),
+            config,
+            timer: None,
+            breakpoints: HashSet::new(),
+            resuming: false,
+            hooks: None,
+            temp_roots: vec![],
+            base_frame: 0,
+            in_call_value: false,
+            programs: vec![],
+            inputs: vec![],
+            rng,
+            native_info: vec![],
+            host_data: HashMap::new(),
         };
 
-        vm.define_native("clock", clock);
-        vm.define_native("sum", sum);
-        vm.define_native("product", product);
+        for native in NativeRegistry::standard(&vm.config.capabilities).natives {
+            vm.define_builtin(&native);
+        }
+        vm.define_constants();
+
+        if vm.config.gc.arena {
+            vm.gc.start_region();
+        }
 
         vm
     }
@@ -52,24 +169,358 @@ impl Vm {
     ///
     /// This function can return both compile and runtime errors.
     pub fn interpret(&mut self, source: Source) -> Output {
+        self.interpret_with(source, |_, _| {})
+    }
+
+    /// Like [`interpret`](Self::interpret), but also pass each node's output
+    /// to `on_output` as soon as it's computed, so that hosts can show results
+    /// before the whole graph has finished. The values are only valid until
+    /// the next run starts.
+    pub fn interpret_with(
+        &mut self,
+        source: Source,
+        mut on_output: impl FnMut(&str, Value),
+    ) -> Output {
+        self.start(source);
+        self.run_to_completion(&mut on_output)
+    }
+
+    /// Compile the given AST and prepare to execute it in slices with
+    /// [`step`](Self::step). Any program still in progress is abandoned.
+    pub fn start(&mut self, source: Source) {
+        self.free_arena();
+        if self.config.globals_scope == GlobalsScope::Run {
+            self.forget_globals();
+        }
+        self.prepare();
+
         let ast = Ast::new(&source);
-        let mut compiler: Compiler<'_> = Compiler::new(&ast, &mut self.gc, &mut self.output);
+        let mut compiler: Compiler<'_> =
+            Compiler::new(&ast, &mut self.gc, &mut self.globals, &mut self.output);
         let function = compiler.compile();
 
-        // Leave the <script> function on the stack forever so it's not GC'd
-        self.stack.push(Value::Function(function));
+        self.start_function(function);
+    }
+
+    /// Compile the given AST once, so that it can be run many times with
+    /// [`interpret_batch`](Self::interpret_batch). The program stays on the
+    /// heap until the next [`reset`](Self::reset).
+    pub fn compile(&mut self, source: Source) -> CompiledProgram {
+        self.free_arena();
+        let mut output = OutputValues::default();
+        let ast = Ast::new(&source);
+        let function = Compiler::new(&ast, &mut self.gc, &mut self.globals, &mut output).compile();
+        self.programs.push(function);
+        if self.config.gc.arena {
+            // Keep the program for later runs
+            self.gc.start_region();
+        }
+        CompiledProgram { function, output }
+    }
+
+    /// Stop keeping a program returned by [`compile`](Self::compile) alive,
+    /// so that the next collection can free it without a
+    /// [`reset`](Self::reset)
+    pub fn free_program(&mut self, program: CompiledProgram) {
+        if let Some(index) = self.programs.iter().position(|f| *f == program.function) {
+            self.programs.swap_remove(index);
+        }
+    }
+
+    /// Compile the given AST without running it, reporting the same compile
+    /// errors and diagnostics as [`interpret`](Self::interpret), such as
+    /// cycles and wrong numbers of arguments. The output has no node values.
+    pub fn validate(&mut self, source: Source) -> Output {
+        self.free_arena();
+        let mut output = OutputValues::default();
+        let ast = Ast::new(&source);
+        Compiler::new(&ast, &mut self.gc, &mut self.globals, &mut output).compile();
+        output.take(&self.config)
+    }
+
+    /// Compile the given AST and list the bytecode of the whole graph, followed
+    /// by that of each function, without running anything
+    pub fn disassemble(&mut self, source: Source) -> String {
+        self.free_arena();
+        let mut output = OutputValues::default();
+        let ast = Ast::new(&source);
+        let function = Compiler::new(&ast, &mut self.gc, &mut self.globals, &mut output).compile();
+        let mut disassembly = String::new();
+        disassembler::disassemble_function(&mut disassembly, &function);
+        disassembly
+    }
+
+    /// Run a compiled program once for each set of inputs, reusing the
+    /// compiled chunks and the heap across runs. Each input replaces the
+    /// output of the node with the same id, which is then used by every node
+    /// depending on it.
+    pub fn interpret_batch(&mut self, program: &CompiledProgram, inputs: &[Inputs]) -> Vec<Output> {
+        // Outputs of the whole batch must stay valid
+        self.free_arena();
+        inputs
+            .iter()
+            .map(|inputs| {
+                if self.config.globals_scope == GlobalsScope::Run {
+                    self.forget_globals();
+                }
+                self.prepare();
+                self.output.clone_from(&program.output);
+                if self.config.record_timings {
+                    self.output.record_timings();
+                }
+                for (node_id, value) in inputs {
+                    let Some(output_index) = self.output.output_index(node_id) else {
+                        let error = Error::node(node_id, "No node to receive this input.");
+                        self.output.add_runtime_error(error, "unknown_input");
+                        continue;
+                    };
+                    let output_index = output_index as usize;
+                    if self.inputs.len() <= output_index {
+                        self.inputs.resize(output_index + 1, None);
+                    }
+                    // Converting doesn't trigger a GC, and the value is rooted as soon as it's
+                    // stored
+                    self.inputs[output_index] = Some(chunk::from(value, &mut self.gc));
+                }
+
+                self.start_function(program.function);
+                self.run_to_completion(&mut |_, _| {})
+            })
+            .collect()
+    }
+
+    /// Abandon any program in progress and get ready for a new run
+    fn prepare(&mut self) {
+        self.output.clear();
+        self.stack.truncate(0);
+        self.frames.truncate(0);
+        self.resuming = false;
+        self.gc.take_exhausted();
+        self.inputs.clear();
+        self.temp_roots.clear();
+        self.gc.reset_stats();
+
+        if self.config.record_timings {
+            self.output.record_timings();
+            self.timer = Some(Timer::new());
+        }
+    }
+
+    /// In arena mode, free everything allocated since the previous run
+    /// started
+    fn free_arena(&mut self) {
+        if !self.config.gc.arena {
+            return;
+        }
+        if self.gc.is_marking() {
+            self.collect_garbage();
+        }
+        // Nothing else may reference objects of the previous run
+        self.stack.truncate(0);
+        self.frames.truncate(0);
+        self.temp_roots.clear();
+        self.inputs.clear();
+        self.host_data.clear();
+        self.forget_globals();
+        self.gc.free_region();
+    }
+
+    fn start_function(&mut self, function: GcRef<Function>) {
+        // Leave the <script> function on the stack for the whole run so it's not GC'd
+        self.check_heap()
+            .and_then(|_| self.push(Value::Function(function)))
+            .and_then(|_| self.call(function, 0))
+            .unwrap_or_else(|e| self.output.add_runtime_error(e, "runtime"));
+    }
 
-        self.call(function, 0)
-            .unwrap_or_else(|e| self.output.add_error(e));
+    fn run_to_completion(&mut self, on_output: &mut dyn FnMut(&str, Value)) -> Output {
+        loop {
+            if let Step::Done(output) = self.step_with(usize::MAX, on_output) {
+                return output;
+            }
+        }
+    }
+
+    /// Execute at most `max_instructions` instructions of the program
+    /// previously passed to [`start`](Self::start), so that the host can do
+    /// other work between slices.
+    pub fn step(&mut self, max_instructions: usize) -> Step {
+        self.step_with(max_instructions, &mut |_, _| {})
+    }
 
-        self.run().unwrap_or_else(|e| self.output.add_error(e));
+    /// Abandon the program previously passed to [`start`](Self::start),
+    /// returning the outputs computed so far along with an error saying it was
+    /// cancelled
+    pub fn cancel(&mut self) -> Output {
+        self.stop(Error::runtime("Cancelled."), "cancelled")
+    }
 
-        self.output.take()
+    /// Like [`cancel`](Self::cancel), but report the given error instead,
+    /// identified by `code`. Useful for hosts enforcing their own limits.
+    pub fn stop(&mut self, error: Error, code: &'static str) -> Output {
+        if self.frames.len() > 0 {
+            self.output.add_runtime_error(error, code);
+        }
+        self.finish()
+    }
+
+    /// Like [`step`](Self::step), but also pass each node's output to
+    /// `on_output` as soon as it's computed, as
+    /// [`interpret_with`](Self::interpret_with) does
+    pub fn step_with(
+        &mut self,
+        max_instructions: usize,
+        on_output: &mut dyn FnMut(&str, Value),
+    ) -> Step {
+        if self.frames.len() == 0 {
+            return Step::Done(self.finish());
+        }
+
+        match self.run(max_instructions, on_output) {
+            Ok(RunState::Suspended) => {
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.suspend();
+                }
+                Step::Running
+            }
+            Ok(RunState::Breakpoint(node_id)) => {
+                self.resuming = true;
+                Step::Breakpoint(node_id)
+            }
+            Ok(RunState::Finished) => Step::Done(self.finish()),
+            Err(e) => {
+                self.output.add_runtime_error(e, "runtime");
+                Step::Done(self.finish())
+            }
+        }
+    }
+
+    /// Pause [`step`](Self::step) whenever execution reaches the given node.
+    /// Execution stops before the first instruction of each contiguous run of
+    /// instructions compiled from the node. [`interpret`](Self::interpret)
+    /// ignores breakpoints.
+    pub fn set_breakpoint(&mut self, node_id: &str) {
+        self.breakpoints.insert(node_id.to_string());
+    }
+
+    pub fn clear_breakpoint(&mut self, node_id: &str) {
+        self.breakpoints.remove(node_id);
+    }
+
+    /// The values of the nodes which have been computed so far by the program
+    /// in progress
+    pub fn node_values(&self) -> impl Iterator<Item = (&str, Value)> {
+        self.output.values()
+    }
+
+    /// The value stack of the program in progress, bottom first
+    pub fn stack(&self) -> &[Value] {
+        self.stack.as_slice()
+    }
+
+    /// The call frames of the program in progress, outermost first
+    pub fn frames(&self) -> Vec<FrameInfo<'_>> {
+        let stack = self.stack.as_slice();
+        let frames = self.frames.as_slice();
+        frames
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                let next = frames.get(i + 1);
+                let end = next.map_or(stack.len(), |next| next.slot);
+                // The ip of outer frames points just past their call instruction
+                let offset = if next.is_some() {
+                    frame.offset().saturating_sub(1)
+                } else {
+                    frame.offset()
+                };
+                FrameInfo {
+                    function: frame.function.display_name().to_string(),
+                    node_id: frame.function.chunk.node_id(offset).map(str::to_string),
+                    slots: &stack[frame.slot..end],
+                }
+            })
+            .collect()
+    }
+
+    /// Restart the sequence of numbers returned by the `random` natives, so
+    /// that the following runs are reproducible
+    pub fn set_random_seed(&mut self, seed: u64) {
+        self.rng = Rng::with_seed(seed);
+    }
+
+    /// Uniformly distributed in `[0, 1)`
+    pub(crate) fn next_random(&mut self) -> f64 {
+        self.rng.next_f64()
+    }
+
+    /// Every registered native, so that editors can offer autocompletion
+    pub fn natives(&self) -> &[NativeInfo] {
+        &self.native_info
+    }
+
+    /// Make `value` readable by graphs with `data("key")`, replacing any
+    /// value previously set under `key`. The value stays reachable until it's
+    /// replaced, even across [`reset`](Self::reset). In arena mode, it's freed
+    /// along with everything else allocated since the previous run, so it must
+    /// be set again before every run.
+    pub fn set_host_data(&mut self, key: &str, value: Value) {
+        self.host_data.insert(key.to_string(), value);
+    }
+
+    pub(crate) fn host_data(&self, key: &str) -> Option<Value> {
+        self.host_data.get(key).copied()
+    }
+
+    /// The config this VM was created with
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    /// All global variables, including natives, in no particular order
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        self.globals
+            .iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    fn finish(&mut self) -> Output {
+        self.stack.truncate(0);
+        self.frames.truncate(0);
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.halt();
+        }
+
+        let mut output = self.output.take(&self.config);
+        if self.config.record_memory {
+            output.memory = Some(self.gc.stats());
+        }
+        output
     }
 
     // Returning an error from this function (including ?) halts execution
-    fn run(&mut self) -> Result<()> {
+    fn run(
+        &mut self,
+        mut max_instructions: usize,
+        on_output: &mut dyn FnMut(&str, Value),
+    ) -> Result<RunState> {
         loop {
+            if max_instructions == 0 {
+                return Ok(RunState::Suspended);
+            }
+            max_instructions -= 1;
+
+            // Can't pause in the middle of a native or a host's call, so ignore breakpoints
+            // in functions they call
+            if !self.breakpoints.is_empty() && !self.in_call_value && !mem::take(&mut self.resuming)
+            {
+                if let Some(node_id) = self.breakpoint_hit() {
+                    return Ok(RunState::Breakpoint(node_id));
+                }
+            }
+
             #[cfg(feature = "debug_trace_execution")]
             {
                 print!("        ");
@@ -79,118 +530,400 @@ impl Vm {
             }
             let instruction = unsafe { *self.current_frame().ip };
             self.current_frame().ip = unsafe { self.current_frame().ip.offset(1) };
+            if let Some(profiler) = &mut self.profiler {
+                let frame = self.frames.peek(0);
+                let node_id = frame.function.chunk.node_id(frame.offset() - 1);
+                profiler.instruction(instruction, node_id);
+            }
+            self.with_hooks(|hooks, vm| {
+                hooks.on_instruction(vm, instruction.name(), vm.current_node())
+            });
 
             match instruction {
-                OpCode::Add => {
-                    let b = *self.stack.peek(0);
-                    let a = *self.stack.peek(1);
-                    let result = a.add(b, self);
-                    self.stack.push(result);
-                }
+                OpCode::Add => self.add()?,
                 // Load constant/function onto the stack
                 OpCode::Constant(constant) | OpCode::Function(constant) => {
                     let constant = self.current_frame().read_constant(constant);
-                    self.stack.push(constant);
+                    self.push(constant)?;
                 }
-                OpCode::Divide => self.binary_op(|a, b| Value::Number(a / b))?,
-                OpCode::Multiply => self.binary_op(|a, b| Value::Number(a * b))?,
-                OpCode::Negate => {
-                    if let Value::Number(value) = *self.stack.peek(0) {
+                OpCode::Divide => self.binary_op(
+                    |a, b| Value::Number(a / b),
+                    // Stay exact only if there's no remainder
+                    |a, b| match a.checked_rem(b)? {
+                        0 => a.checked_div(b).map(Value::Int),
+                        _ => None,
+                    },
+                )?,
+                OpCode::Multiply => self.binary_op(
+                    |a, b| Value::Number(a * b),
+                    |a, b| a.checked_mul(b).map(Value::Int),
+                )?,
+                OpCode::Negate => match *self.stack.peek(0) {
+                    Value::Number(value) => {
                         self.stack.pop();
-                        self.stack.push(Value::Number(-value));
-                    } else {
-                        self.runtime_error("Operand must be a number.")?;
+                        self.push(Value::Number(-value))?;
                     }
-                }
+                    Value::Int(value) => {
+                        self.stack.pop();
+                        let negated = value
+                            .checked_neg()
+                            .map_or(Value::Number(-(value as f64)), Value::Int);
+                        self.push(negated)?;
+                    }
+                    _ => self.runtime_error("Operand must be a number.")?,
+                },
                 OpCode::Return => {
                     let result = self.stack.pop();
-                    let fun_stack_start = self.frames.pop().slot;
-                    if self.frames.len() == 0 {
+                    let frame = self.frames.pop();
+                    if let Some(timer) = &mut self.timer {
+                        let elapsed = timer.end_call();
+                        if let Some(name) = frame.function.name {
+                            self.output.add_function_time(name.as_str(), elapsed);
+                        }
+                    }
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.end_call();
+                    }
+                    self.with_hooks(|hooks, vm| {
+                        hooks.on_return(vm, frame.function.display_name(), result)
+                    });
+                    self.stack.truncate(frame.slot);
+                    self.push(result)?;
+                    if self.frames.len() == self.base_frame {
                         // Exit interpreter
-                        return Ok(());
+                        return Ok(RunState::Finished);
                     }
-                    self.stack.truncate(fun_stack_start);
-                    self.stack.push(result);
                 }
-                OpCode::Subtract => self.binary_op(|a, b| Value::Number(a - b))?,
-                OpCode::Nil => self.stack.push(Value::Nil),
-                OpCode::True => self.stack.push(Value::Bool(true)),
-                OpCode::False => self.stack.push(Value::Bool(false)),
+                OpCode::Subtract => self.binary_op(
+                    |a, b| Value::Number(a - b),
+                    |a, b| a.checked_sub(b).map(Value::Int),
+                )?,
+                OpCode::Nil => self.push(Value::Nil)?,
+                OpCode::True => self.push(Value::Bool(true))?,
+                OpCode::False => self.push(Value::Bool(false))?,
                 OpCode::Not => {
                     let value = self.stack.pop();
-                    self.stack.push(Value::Bool(value.is_falsey()));
+                    self.push(Value::Bool(value.is_falsey()))?;
                 }
                 OpCode::Equal => {
                     let a = self.stack.pop();
                     let b = self.stack.pop();
-                    self.stack.push(Value::Bool(a == b));
+                    self.push(Value::Bool(a == b))?;
                 }
-                OpCode::Greater => self.binary_op(|a, b| Value::Bool(a > b))?,
-                OpCode::Less => self.binary_op(|a, b| Value::Bool(a < b))?,
+                OpCode::Greater => self.comparison_op(Ordering::Greater)?,
+                OpCode::Less => self.comparison_op(Ordering::Less)?,
                 OpCode::Pop => {
                     self.stack.pop();
                 }
-                OpCode::DefineGlobal(constant) => {
-                    let name = self.read_string(constant);
-                    self.globals.insert(name, *self.stack.peek(0));
+                OpCode::SetSlot(slot) => {
+                    let value = *self.stack.peek(0);
+                    self.globals
+                        .set(slot, value)
+                        .map_err(|e| self.add_stacktrace(e))?;
                     self.stack.pop();
                 }
-                OpCode::GetGlobal(constant) => {
-                    let name = self.read_string(constant);
-                    if let Some(value) = self.globals.get(name) {
-                        self.stack.push(value);
-                    } else {
-                        self.runtime_error(format!("Undefined variable '{}'.", name.as_str()))?;
-                    }
+                OpCode::GetSlot(slot) => self.get_global(slot)?,
+                OpCode::GetLocal(local) => self.get_local(local)?,
+                OpCode::Call { arg_count } => self.call_instruction(arg_count)?,
+                OpCode::Output { output_index } => self.output_value(output_index, on_output),
+                OpCode::GetLocalCall { local, arg_count } => {
+                    self.get_local(local)?;
+                    self.call_instruction(arg_count)?;
                 }
-                OpCode::GetLocal(offset) => {
-                    let offset = self.current_frame().read_local_offset(offset);
-                    self.stack.push(*self.stack.read(offset));
+                OpCode::ConstantAdd(constant) => {
+                    let constant = self.current_frame().read_constant(constant);
+                    self.push(constant)?;
+                    self.add()?;
                 }
-                OpCode::Call { arg_count } => {
-                    let arg_count = arg_count as usize;
-                    self.call_value(*self.stack.peek(arg_count), arg_count)?;
+                OpCode::GetSlotOutput { slot, output_index } => {
+                    self.get_global(slot)?;
+                    self.output_value(output_index, on_output);
                 }
-                OpCode::Output { output_index } => {
-                    self.output.add_value(output_index, *self.stack.peek(0))
+            }
+        }
+    }
+
+    /// Forget everything left behind by previous calls to
+    /// [`interpret`](Self::interpret): globals defined by graphs, values on the
+    /// stack and pending output. Natives stay registered.
+    ///
+    /// The heap isn't touched; follow up with
+    /// [`collect_garbage`](Self::collect_garbage) to also free the objects and
+    /// interned strings which are no longer reachable.
+    pub fn reset(&mut self) {
+        self.stack.truncate(0);
+        self.frames.truncate(0);
+        // Cleared first, so that the slots only they refer to are freed
+        self.programs.clear();
+        self.forget_globals();
+        self.output = OutputValues::default();
+        self.timer = None;
+        self.inputs.clear();
+    }
+
+    /// Every object currently on the heap, newest first, whether or not it's
+    /// still reachable. Useful for tracking down leaks.
+    pub fn heap_dump(&self) -> Vec<HeapObject> {
+        self.gc.dump()
+    }
+
+    /// Current heap usage. The peak and the number of collections are counted
+    /// from the start of the latest run.
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.gc.stats()
+    }
+
+    /// Undefine the globals defined by graphs, keeping the natives and
+    /// constants
+    fn forget_globals(&mut self) {
+        self.globals.retain(|name, value| {
+            matches!(value, Value::NativeFunction(_)) || CONSTANTS.iter().any(|c| c.0 == name)
+        });
+        // Restore constants which a graph redefined
+        self.define_constants();
+        // Free the slots of the forgotten globals, unless code which is kept
+        // around refers to them
+        let in_use = self.slots_in_use();
+        self.globals.free_undefined(|slot| in_use.contains(&slot));
+    }
+
+    /// The global slots referred to by compiled programs and by functions
+    /// given as host data
+    fn slots_in_use(&self) -> HashSet<GlobalSlot> {
+        let mut functions: Vec<_> = self.programs.clone();
+        functions.extend(self.host_data.values().filter_map(|value| match value {
+            Value::Function(function) => Some(*function),
+            _ => None,
+        }));
+        let mut slots = HashSet::new();
+        while let Some(function) = functions.pop() {
+            for opcode in &function.chunk.code {
+                match *opcode {
+                    OpCode::SetSlot(slot)
+                    | OpCode::GetSlot(slot)
+                    | OpCode::GetSlotOutput { slot, .. } => {
+                        slots.insert(slot);
+                    }
+                    _ => {}
+                }
+            }
+            for constant in &function.chunk.constants {
+                if let Value::Function(function) = constant {
+                    functions.push(*function);
                 }
             }
         }
+        slots
+    }
+
+    /// Run a full garbage collection now, regardless of heap size. Finishes
+    /// any collection started by
+    /// [`collect_garbage_step`](Self::collect_garbage_step).
+    pub fn collect_garbage(&mut self) {
+        self.mark_roots();
+        self.gc.collect_garbage();
+    }
+
+    /// Keep `value` alive until the end of the current
+    /// [`root_scope`](Self::root_scope). Every native function call runs in
+    /// its own scope, so natives can pin the objects they allocate before
+    /// allocating again.
+    pub fn root(&mut self, value: Value) {
+        self.temp_roots.push(value);
+    }
+
+    /// Call `f`, then unpin the values it passed to [`root`](Self::root)
+    pub fn root_scope<R>(&mut self, f: impl FnOnce(&mut Vm) -> R) -> R {
+        let len = self.temp_roots.len();
+        let result = f(self);
+        self.temp_roots.truncate(len);
+        result
+    }
+
+    /// Advance an incremental garbage collection by tracing at most
+    /// `max_objects` objects, starting a new collection if none is in
+    /// progress. Meant to be called between [`step`](Self::step)s so that a
+    /// big heap never causes a long pause. Returns true when the collection
+    /// finished.
+    pub fn collect_garbage_step(&mut self, max_objects: usize) -> bool {
+        if !self.gc.is_marking() {
+            self.mark_roots();
+            self.gc.start_marking();
+        }
+        if self.gc.mark_step(max_objects) {
+            // Roots may have changed since marking started
+            self.collect_garbage();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Install callbacks which are invoked while programs execute, replacing
+    /// any previously installed hooks
+    pub fn set_hooks(&mut self, hooks: Box<dyn VmHooks>) {
+        self.hooks = Some(hooks);
+    }
+
+    /// Remove and return the installed hooks
+    pub fn take_hooks(&mut self) -> Option<Box<dyn VmHooks>> {
+        self.hooks.take()
+    }
+
+    fn with_hooks(&mut self, f: impl FnOnce(&mut dyn VmHooks, &Vm)) {
+        if let Some(mut hooks) = self.hooks.take() {
+            f(hooks.as_mut(), self);
+            self.hooks = Some(hooks);
+        }
+    }
+
+    /// Take the profile gathered since the last call, or `None` if the VM
+    /// wasn't configured to profile.
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        self.profiler.as_mut().map(Profiler::take)
+    }
+
+    /// Is the next instruction the start of a node with a breakpoint?
+    fn breakpoint_hit(&self) -> Option<NodeId> {
+        let frame = self.frames.peek(0);
+        let chunk = &frame.function.chunk;
+        let offset = frame.offset();
+        let node_id = chunk.node_id(offset)?;
+        let is_node_start = offset == 0 || chunk.node_id(offset - 1) != Some(node_id);
+        (is_node_start && self.breakpoints.contains(node_id)).then(|| node_id.to_string())
     }
 
     fn current_frame(&mut self) -> &mut CallFrame {
         self.frames.top()
     }
 
-    fn read_string(&mut self, constant: Constant) -> GcRef<BanjoString> {
-        match self.current_frame().read_constant(constant) {
-            Value::String(name) => name,
-            _ => unreachable!(),
+    fn add(&mut self) -> Result<()> {
+        let b = *self.stack.peek(0);
+        let a = *self.stack.peek(1);
+        let result = self.root_scope(|vm| a.add(b, vm));
+        self.check_heap()?;
+        self.push(result)
+    }
+
+    fn get_global(&mut self, slot: GlobalSlot) -> Result<()> {
+        match (self.globals.get(slot), self.globals.name(slot)) {
+            (Some(value), _) => self.push(value),
+            (None, Some(name)) => self.runtime_error(format!("Undefined variable '{name}'.")),
+            (None, None) => self.runtime_error(format!("No global in slot {slot}.")),
         }
     }
 
-    fn binary_op(&mut self, f: impl Fn(f64, f64) -> Value) -> Result<()> {
+    fn get_local(&mut self, local: LocalIndex) -> Result<()> {
+        let offset = self.current_frame().read_local_offset(local);
+        self.push(*self.stack.read(offset))
+    }
+
+    fn call_instruction(&mut self, arg_count: u8) -> Result<()> {
+        let arg_count = arg_count as usize;
+        self.call_callee(*self.stack.peek(arg_count), arg_count)?;
+        self.check_heap()
+    }
+
+    /// Record the value on top of the stack as the output of a node, unless
+    /// it's replaced by an input
+    fn output_value(&mut self, output_index: u8, on_output: &mut dyn FnMut(&str, Value)) {
+        if let Some(Some(input)) = self.inputs.get(output_index as usize) {
+            *self.stack.top() = *input;
+        }
+        let value = *self.stack.peek(0);
+        self.output.add_value(output_index, value);
+        on_output(self.output.node_id(output_index), value);
+        self.with_hooks(|hooks, vm| hooks.on_output(vm, vm.output.node_id(output_index), value));
+        if let Some(timer) = &mut self.timer {
+            self.output.add_node_time(output_index, timer.lap());
+        }
+    }
+
+    fn binary_op(
+        &mut self,
+        float: impl Fn(f64, f64) -> Value,
+        int: impl Fn(i64, i64) -> Option<Value>,
+    ) -> Result<()> {
         let b = *self.stack.peek(0);
         let a = *self.stack.peek(1);
-        match (a, b) {
-            (Value::Number(a), Value::Number(b)) => {
+        match a.binary_op(b, float, int) {
+            Ok(result) => {
                 self.stack.pop();
                 self.stack.pop();
-                let result = f(a, b);
-                self.stack.push(result);
-                Ok(())
+                self.push(result)
+            }
+            Err(e) => Err(self.add_stacktrace(e)),
+        }
+    }
+
+    /// Push whether the top two values compare as `expected`. Strings compare
+    /// lexicographically, anything else must be numbers.
+    fn comparison_op(&mut self, expected: Ordering) -> Result<()> {
+        if let (Value::String(a), Value::String(b)) = (*self.stack.peek(1), *self.stack.peek(0)) {
+            self.stack.pop();
+            self.stack.pop();
+            return self.push(Value::Bool(a.as_str().cmp(b.as_str()) == expected));
+        }
+        self.binary_op(
+            |a, b| Value::Bool(a.partial_cmp(&b) == Some(expected)),
+            |a, b| Some(Value::Bool(a.cmp(&b) == expected)),
+        )
+    }
+
+    /// Call a banjo or native function with the given arguments, returning
+    /// its result. This allows natives to invoke function values passed to
+    /// them.
+    pub fn call_value(&mut self, callee: Value, args: &[Value]) -> Result<Value> {
+        let stack_len = self.stack.len();
+        let frames_len = self.frames.len();
+        let base_frame = mem::replace(&mut self.base_frame, frames_len);
+        let in_call_value = mem::replace(&mut self.in_call_value, true);
+
+        let result = (|| {
+            self.push(callee)?;
+            for arg in args {
+                self.push(*arg)?;
+            }
+            self.call_callee(callee, args.len())?;
+            if self.frames.len() > frames_len {
+                // Run the banjo function to completion
+                self.run(usize::MAX, &mut |_, _| {})?;
             }
-            _ => self.runtime_error("Operands must be numbers."),
+            Ok(self.stack.pop())
+        })();
+
+        self.base_frame = base_frame;
+        self.in_call_value = in_call_value;
+        if result.is_err() {
+            // Unwind, in case the caller recovers from the error
+            self.frames.truncate(frames_len);
+            self.stack.truncate(stack_len);
         }
+        result
     }
 
-    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<()> {
+    /// Call the callee which is on the stack below its `arg_count` arguments
+    fn call_callee(&mut self, callee: Value, arg_count: usize) -> Result<()> {
         match callee {
             Value::NativeFunction(callee) => {
-                let args = self.stack.pop_n(arg_count);
-                let result = (callee.function)(args, self).map_err(|e| self.add_stacktrace(e))?;
-                self.stack.pop();
-                self.stack.push(result);
+                if let Some(arity) = callee.arity.filter(|arity| *arity != arg_count) {
+                    return self
+                        .runtime_error(format!("Expected {arity} arguments but got {arg_count}."));
+                }
+                self.with_hooks(|hooks, vm| hooks.on_call(vm, &callee.name));
+                // Arguments stay on the stack during the call, keeping them reachable by the GC
+                let args = self.stack.peek_n(arg_count);
+                let start = self.profiler.is_some().then(Instant::now);
+                let result = self
+                    .root_scope(|vm| (callee.function)(args, vm))
+                    .map_err(|e| self.add_stacktrace(e))?;
+                if let (Some(profiler), Some(start)) = (&mut self.profiler, start) {
+                    profiler.native_call(&callee.name, start.elapsed());
+                }
+                self.with_hooks(|hooks, vm| hooks.on_return(vm, &callee.name, result));
+                // Pop the arguments and the callee
+                self.stack.truncate(self.stack.len() - arg_count - 1);
+                self.push(result)?;
                 Ok(())
             }
             Value::Function(callee) => self.call(callee, arg_count),
@@ -206,45 +939,196 @@ impl Vm {
             ));
         }
 
-        if self.frames.len() == Self::FRAMES_MAX {
-            return self.runtime_error("Stack overflow.");
+        if self.frames.len() == self.frames.capacity() {
+            return self.runtime_error(self.frame_overflow_message(callee));
         }
 
         let slot = self.stack.get_offset() - arg_count;
-        self.frames.push(CallFrame::new(callee, slot));
+        self.frames.push(CallFrame::new(callee, slot))?;
+        if let Some(timer) = &mut self.timer {
+            timer.start_call();
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.start_call(callee.display_name().to_string());
+        }
+        self.with_hooks(|hooks, vm| hooks.on_call(vm, callee.display_name()));
         Ok(())
     }
 
+    /// Describe a frame stack overflow which would be caused by calling
+    /// `callee`, pointing out the cycle of calls if it looks like an unbounded
+    /// recursion
+    fn frame_overflow_message(&self, callee: GcRef<Function>) -> String {
+        let names: Vec<&str> = self
+            .frames
+            .as_slice()
+            .iter()
+            .map(|frame| frame.function.display_name())
+            .chain(iter::once(callee.display_name()))
+            .collect();
+        match find_cycle(&names) {
+            Some(cycle) => {
+                let mut message = String::from("Stack overflow. Possible infinite recursion: ");
+                for name in cycle {
+                    write!(message, "{name} → ").unwrap();
+                }
+                write!(message, "{} …", cycle[0]).unwrap();
+                message
+            }
+            None => "Stack overflow.".to_string(),
+        }
+    }
+
+    /// Append a line for each frame, innermost first, naming its function and
+    /// the node being executed: the failing node in the innermost frame, then
+    /// the node calling the next frame
     fn make_stacktrace<M: Into<String>>(&self, message: M) -> String {
-        // Print callstack
         let mut error_str = message.into();
         for i in (0..self.frames.len()).rev() {
             let frame = self.frames.read(i);
             let closure = frame.function;
             write!(error_str, "\nin {:?}", *closure).unwrap();
+            // ip has already moved past the instruction being executed
+            let node_id = frame
+                .offset()
+                .checked_sub(1)
+                .and_then(|offset| closure.chunk.node_id(offset));
+            if let Some(node_id) = node_id {
+                write!(error_str, " at node '{node_id}'").unwrap();
+            }
         }
         error_str
     }
 
     fn runtime_error<M: Into<String>>(&self, message: M) -> Result<()> {
-        Error::runtime_err(self.make_stacktrace(message))
+        Err(self.add_stacktrace(Error::runtime(message)))
+    }
+
+    /// Record a runtime error against the current node, like a native
+    /// returning an error would, but without halting execution
+    pub(crate) fn report_error(&mut self, code: &'static str, message: String) {
+        let error = self.add_stacktrace(Error::Runtime(message));
+        self.output.add_runtime_error(error, code);
     }
 
+    /// Add the callstack to a runtime error and attribute it to the node of the
+    /// instruction being executed, if any
     fn add_stacktrace(&self, error: Error) -> Error {
         match error {
-            Error::Runtime(message) => Error::Runtime(self.make_stacktrace(message)),
+            Error::Runtime(message) => {
+                let message = self.make_stacktrace(message);
+                match self.current_node() {
+                    Some(node_id) => Error::node(node_id, message),
+                    None => Error::Runtime(message),
+                }
+            }
             _ => error,
         }
     }
 
-    fn define_native(&mut self, name: &str, function: NativeFn) {
-        let ls = self.intern(name);
-        // Pushing and popping to and from stack is only to ensure no GC occurs on call
-        // to alloc
-        self.stack.push(Value::String(ls));
-        let native = self.alloc(NativeFunction::new(function));
-        self.globals.insert(ls, Value::NativeFunction(native));
-        self.stack.pop();
+    /// The node of the instruction currently being executed
+    fn current_node(&self) -> Option<&str> {
+        if self.frames.len() == 0 {
+            return None;
+        }
+        let frame = self.frames.peek(0);
+        // ip has already moved past the current instruction
+        frame.function.chunk.node_id(frame.offset().checked_sub(1)?)
+    }
+
+    fn define_constants(&mut self) {
+        for (name, value) in CONSTANTS {
+            self.globals
+                .define(name, Value::Number(value))
+                .expect("Room for the constants");
+        }
+    }
+
+    /// Make a native function callable by graphs under `name`. If `arity` is
+    /// given, calls with a different number of arguments fail before reaching
+    /// the function.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a global named `name` is already defined.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        function: impl Fn(&[Value], &mut Vm) -> Result<Value> + 'static,
+    ) -> Result<()> {
+        let info = NativeInfo {
+            name: name.to_string(),
+            aliases: vec![],
+            arity,
+            args: ArgCount::from_arity(arity),
+            namespace: None,
+            description: String::new(),
+        };
+        self.define_native_checked(name, arity, Rc::new(function), info)
+    }
+
+    fn define_native_checked(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        function: NativeFn,
+        info: NativeInfo,
+    ) -> Result<()> {
+        if self.globals.get_by_name(name).is_some() {
+            return Error::runtime_err(format!("A global named '{name}' is already defined."));
+        }
+        self.define_native_unchecked(name, arity, function)?;
+        self.native_info.push(info);
+        Ok(())
+    }
+
+    /// Make every native of the registry callable by graphs under its
+    /// qualified name
+    ///
+    /// # Errors
+    ///
+    /// Fails on the first name which is already defined, keeping the natives
+    /// registered before it.
+    pub fn register_natives(&mut self, registry: &NativeRegistry) -> Result<()> {
+        for native in &registry.natives {
+            let name = native.qualified_name();
+            let function = native.function.clone();
+            self.define_native_checked(&name, native.arity, function, native.into())?;
+        }
+        Ok(())
+    }
+
+    /// Register a native of the standard library under its qualified name and
+    /// its aliases. It goes by its first alias in hooks and profiles, which
+    /// stay as they were before namespaces existed.
+    fn define_builtin(&mut self, native: &RegisteredNative) {
+        let qualified_name = native.qualified_name();
+        let name = native.aliases.first().copied();
+        let name = name.unwrap_or(&qualified_name);
+        self.define_native_unchecked(name, native.arity, native.function.clone())
+            .expect("Room for the standard library");
+        let value = self.globals.get_by_name(name).unwrap();
+        for alias in iter::once(qualified_name.as_str()).chain(native.aliases.iter().copied()) {
+            self.globals
+                .define(alias, value)
+                .expect("Room for the standard library");
+        }
+        self.native_info.push(native.into());
+    }
+
+    fn define_native_unchecked(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        function: NativeFn,
+    ) -> Result<()> {
+        let native = self.alloc(NativeFunction::new(name, arity, function));
+        self.globals.define(name, Value::NativeFunction(native))
+    }
+
+    fn push(&mut self, value: Value) -> Result<()> {
+        self.stack.push(value).map_err(|e| self.add_stacktrace(e))
     }
 
     pub fn intern(&mut self, string: &str) -> GcRef<BanjoString> {
@@ -252,6 +1136,30 @@ impl Vm {
         self.gc.intern(string)
     }
 
+    /// Intern a string on the heap. Strings need the heap, so there's no
+    /// `From<&str>` for [`Value`].
+    pub fn string(&mut self, string: &str) -> Value {
+        Value::String(self.intern(string))
+    }
+
+    /// Collect values into a new list on the heap. The values are kept alive
+    /// while the list is allocated.
+    pub fn list(&mut self, values: impl IntoIterator<Item = Value>) -> Value {
+        let values: Vec<_> = values.into_iter().collect();
+        self.root_scope(|vm| {
+            for value in &values {
+                vm.root(*value);
+            }
+            Value::List(vm.alloc(List::new(values)))
+        })
+    }
+
+    /// Move binary data to the heap, for example so that the host can pass
+    /// it to the graph as an input
+    pub fn bytes(&mut self, data: Vec<u8>) -> Value {
+        Value::Bytes(self.alloc(Buffer::new(data)))
+    }
+
     /// Move the provided object to the heap and track with the garbage
     /// collector
     pub fn alloc<T>(&mut self, object: T) -> GcRef<T>
@@ -262,10 +1170,25 @@ impl Vm {
         self.gc.alloc(object)
     }
 
+    /// Fail if an allocation took the heap over its configured limit
+    fn check_heap(&mut self) -> Result<()> {
+        if self.gc.take_exhausted() {
+            let max_bytes = self.gc.max_bytes().unwrap_or_default();
+            return self.runtime_error(format!("Heap limit of {max_bytes} bytes exceeded."));
+        }
+        Ok(())
+    }
+
     fn mark_and_collect_garbage(&mut self) {
-        if self.gc.should_gc() {
-            self.mark_roots();
-            self.gc.collect_garbage();
+        if !self.gc.should_gc() {
+            return;
+        }
+        match self.config.gc.incremental_step {
+            // Make room at once rather than give up on a heap which hit its limit
+            Some(max_objects) if !self.gc.is_over_limit() => {
+                self.collect_garbage_step(max_objects);
+            }
+            _ => self.collect_garbage(),
         }
     }
 
@@ -278,6 +1201,27 @@ impl Vm {
 
         // Globals
         self.globals.mark_gray(&mut self.gc);
+
+        // Values output earlier in the current run
+        self.output.mark_gray(&mut self.gc);
+
+        // Data provided by the host
+        for value in self.host_data.values_mut() {
+            value.mark_gray(&mut self.gc);
+        }
+
+        // Temporaries of natives
+        for value in &mut self.temp_roots {
+            value.mark_gray(&mut self.gc);
+        }
+
+        // Compiled programs and their inputs
+        for function in &mut self.programs {
+            function.mark_gray(&mut self.gc);
+        }
+        for input in self.inputs.iter_mut().flatten() {
+            input.mark_gray(&mut self.gc);
+        }
     }
 }
 
@@ -287,6 +1231,54 @@ impl Default for Vm {
     }
 }
 
+/// Find the shortest sequence of calls which repeats at least
+/// `MIN_REPEATS` times at the end of the call stack, oldest call first
+fn find_cycle<'a>(names: &'a [&'a str]) -> Option<&'a [&'a str]> {
+    const MIN_REPEATS: usize = 3;
+    (1..=names.len() / MIN_REPEATS).find_map(|period| {
+        let tail = &names[names.len() - period * MIN_REPEATS..];
+        let cycle = &tail[tail.len() - period..];
+        tail.chunks(period)
+            .all(|chunk| chunk == cycle)
+            .then_some(cycle)
+    })
+}
+
+/// Wall-clock bookkeeping used when [`VmConfig::record_timings`] is set
+struct Timer {
+    /// When the previous output node was produced
+    last_output: Instant,
+    /// Start times of the ongoing function calls, innermost last
+    calls: Vec<Instant>,
+}
+
+impl Timer {
+    fn new() -> Self {
+        Self {
+            last_output: Instant::now(),
+            calls: vec![],
+        }
+    }
+
+    /// Time elapsed since the previous output
+    fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.last_output;
+        self.last_output = now;
+        elapsed
+    }
+
+    fn start_call(&mut self) {
+        self.calls.push(Instant::now());
+    }
+
+    fn end_call(&mut self) -> Duration {
+        self.calls
+            .pop()
+            .map_or(Duration::ZERO, |start| start.elapsed())
+    }
+}
+
 /// Represents a single ongoing function call
 struct CallFrame {
     function: GcRef<Function>,
@@ -316,6 +1308,11 @@ impl CallFrame {
         }
     }
 
+    /// Offset of the next instruction within the function's chunk
+    fn offset(&self) -> usize {
+        unsafe { self.ip.offset_from(self.function.chunk.code.as_ptr()) as usize }
+    }
+
     fn read_constant(&self, constant: Constant) -> Value {
         self.function.chunk.constants[constant.slot as usize]
     }
@@ -330,3 +1327,153 @@ impl GarbageCollect for CallFrame {
         self.function.mark_gray(gc);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native_functions::{weak_get, weak_ref};
+
+    #[test]
+    fn find_recursion_cycle() {
+        let names = [
+            "<script>", "main", "fib", "helper", "fib", "helper", "fib", "helper",
+        ];
+        assert_eq!(find_cycle(&names), Some(&["fib", "helper"][..]));
+
+        let names = ["<script>", "a", "a", "a"];
+        assert_eq!(find_cycle(&names), Some(&["a"][..]));
+
+        let names = ["<script>", "a", "b", "c"];
+        assert_eq!(find_cycle(&names), None);
+    }
+
+    #[test]
+    fn root_temporaries() {
+        let mut vm = Vm::new();
+        let (rooted, unrooted) = vm.root_scope(|vm| {
+            let rooted = vm.intern("rooted");
+            vm.root(Value::String(rooted));
+            let unrooted = vm.intern("unrooted");
+            vm.collect_garbage();
+            assert!(vm.gc.is_live(rooted));
+            assert!(!vm.gc.is_live(unrooted));
+            (rooted, unrooted)
+        });
+        vm.collect_garbage();
+        assert!(!vm.gc.is_live(rooted));
+        assert!(!vm.gc.is_live(unrooted));
+    }
+
+    #[test]
+    fn trace_list_elements() {
+        let mut vm = Vm::new();
+        vm.root_scope(|vm| {
+            let element = vm.intern("element");
+            vm.root(Value::String(element));
+            let inner = vm.alloc(List::new(vec![Value::String(element)]));
+            vm.root(Value::List(inner));
+            let outer = vm.alloc(List::new(vec![Value::List(inner)]));
+            vm.globals.define("outer", Value::List(outer)).unwrap();
+        });
+        vm.collect_garbage();
+        let Some(Value::List(outer)) = vm.globals.get_by_name("outer") else {
+            panic!("Expected a list");
+        };
+        let Value::List(inner) = outer.values[0] else {
+            panic!("Expected a list");
+        };
+        assert!(vm.gc.is_live(inner));
+        assert!(matches!(inner.values[0], Value::String(s) if vm.gc.is_live(s)));
+    }
+
+    #[test]
+    fn clear_weak_refs() {
+        let mut vm = Vm::new();
+        vm.root_scope(|vm| {
+            let weak = vm.root_scope(|vm| {
+                let target = Value::String(vm.intern("target"));
+                vm.root(target);
+                let weak = weak_ref(&[target], vm).unwrap();
+                vm.root(weak);
+                vm.collect_garbage();
+                assert_eq!(weak_get(&[weak], vm).unwrap(), target);
+                weak
+            });
+            vm.root(weak);
+            vm.collect_garbage();
+            assert_eq!(weak_get(&[weak], vm).unwrap(), Value::Nil);
+        });
+    }
+
+    #[test]
+    fn reuse_slots_of_forgotten_globals() {
+        let mut vm = Vm::with_config(VmConfig {
+            globals_scope: GlobalsScope::Run,
+            ..VmConfig::default()
+        });
+        let graph = |id: usize| {
+            serde_json::from_str(&format!(
+                r#"{{
+                    "nodes": [
+                        {{ "id": "x{id}", "type": "const", "value": {id} }},
+                        {{ "id": "ref{id}", "type": "ref", "varNodeId": "x{id}" }}
+                    ]
+                }}"#
+            ))
+            .unwrap()
+        };
+        // Compiled programs keep their slots
+        let program = vm.compile(graph(0));
+        vm.interpret(graph(1));
+        let slot_count = vm.globals.slot_count();
+        for id in 2..1000 {
+            let output = vm.interpret(graph(id));
+            assert!(
+                matches!(output.node_values[&format!("ref{id}")], Value::Number(n) if n == id as f64)
+            );
+        }
+        assert_eq!(vm.globals.slot_count(), slot_count);
+
+        let output = vm.interpret_batch(&program, &[Inputs::new()]);
+        assert!(matches!(output[0].node_values["ref0"], Value::Number(n) if n == 0.0));
+    }
+
+    /// Call the first argument with the remaining arguments
+    fn apply(args: &[Value], vm: &mut Vm) -> Result<Value> {
+        let result = vm.call_value(args[0], &args[1..])?;
+        // The arguments must survive the nested call
+        assert!(matches!(args[1], Value::Number(n) if n == 1.0));
+        Ok(result)
+    }
+
+    #[test]
+    fn native_calls_back_into_function() {
+        let mut vm = Vm::new();
+        vm.define_native("apply", None, apply).unwrap();
+        let source = serde_json::from_str(
+            r#"{
+                "nodes": [
+                    { "id": "lit", "type": "literal", "value": 2 },
+                    { "id": "param", "type": "param" },
+                    { "id": "body", "type": "call", "fnNodeId": "sum", "args": ["param", "lit"] },
+                    { "id": "add_two", "type": "fn", "args": ["body"] },
+                    { "id": "add_two_ref", "type": "ref", "varNodeId": "add_two" },
+                    { "id": "one", "type": "const", "value": 1 },
+                    { "id": "one_ref", "type": "ref", "varNodeId": "one" },
+                    { "id": "result", "type": "call", "fnNodeId": "apply", "args": ["add_two_ref", "one_ref"] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let output = vm.interpret(source);
+        assert!(output.errors.node_errors.is_empty());
+        assert!(matches!(output.node_values["result"], Value::Number(n) if n == 3.0));
+
+        // Natives can be called directly, and failed calls leave the stack balanced
+        let one = Value::Number(1.0);
+        let sum = vm.globals.get_by_name("sum").unwrap();
+        assert!(matches!(vm.call_value(sum, &[one, one]), Ok(Value::Number(n)) if n == 2.0));
+        assert!(vm.call_value(one, &[]).is_err());
+        assert_eq!(vm.stack().len(), 0);
+    }
+}
@@ -5,7 +5,7 @@ use std::{
     path::Path,
 };
 
-use banjoc::{ast::NodeId, output::Output, value::Value, vm::Vm};
+use banjoc::{ast::NodeId, error::Diagnostic, output::Output, value::Value, vm::Vm};
 use serde::{de::DeserializeOwned, Deserialize};
 
 #[test]
@@ -47,7 +47,7 @@ pub struct TestOutput {
     #[serde(default)]
     node_values: HashMap<NodeId, TestValue>,
     #[serde(default)]
-    node_errors: HashMap<NodeId, String>,
+    node_errors: HashMap<NodeId, Vec<String>>,
     #[serde(default)]
     additional_errors: Vec<String>,
 }
@@ -65,11 +65,26 @@ pub enum TestValue {
 impl PartialEq<Output> for TestOutput {
     fn eq(&self, other: &Output) -> bool {
         node_values_eq(&self.node_values, &other.node_values)
-            && self.node_errors == other.errors.node_errors
-            && self.additional_errors == other.errors.additional_errors
+            && node_errors_eq(&self.node_errors, &other.errors.node_errors)
+            && self.additional_errors.iter().eq(other
+                .errors
+                .additional_errors
+                .iter()
+                .map(|e| &e.message))
     }
 }
 
+fn node_errors_eq(a: &HashMap<NodeId, Vec<String>>, b: &HashMap<NodeId, Vec<Diagnostic>>) -> bool {
+    a.len() == b.len()
+        && b.iter().all(|(node_id, diagnostics)| {
+            a.get(node_id).is_some_and(|messages| {
+                messages
+                    .iter()
+                    .eq(diagnostics.iter().map(|diagnostic| &diagnostic.message))
+            })
+        })
+}
+
 fn node_values_eq(a: &HashMap<NodeId, TestValue>, b: &HashMap<NodeId, Value>) -> bool {
     if a.len() != b.len() {
         return false;
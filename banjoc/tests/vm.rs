@@ -0,0 +1,1819 @@
+use std::{cell::RefCell, rc::Rc};
+
+use banjoc::{
+    ast::{LiteralType, Source},
+    config::{Capabilities, GcConfig, GlobalsScope, OutputLimits, VmConfig},
+    error::{Diagnostic, Error, Severity},
+    hooks::VmHooks,
+    program::Inputs,
+    registry::{ArgCount, NativeRegistry},
+    value::{NumberFormat, Value},
+    vm::{HeapObject, Step, Vm},
+};
+
+fn source(json: &str) -> Source {
+    serde_json::from_str(json).expect("Invalid test source")
+}
+
+const ADD_FN: &str = r#"{
+    "nodes": [
+        { "id": "lit", "type": "literal", "value": 2 },
+        { "id": "param", "type": "param" },
+        { "id": "body", "type": "call", "fnNodeId": "sum", "args": ["param", "lit"] },
+        { "id": "add_two", "type": "fn", "args": ["body"] },
+        { "id": "one", "type": "const", "value": 1 },
+        { "id": "one_ref", "type": "ref", "varNodeId": "one" },
+        { "id": "result", "type": "call", "fnNodeId": "add_two", "args": ["one_ref"] }
+    ]
+}"#;
+
+#[test]
+fn timings_disabled_by_default() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(ADD_FN));
+    assert!(output.timings.is_none());
+}
+
+#[test]
+fn records_timings() {
+    let mut vm = Vm::with_config(VmConfig {
+        record_timings: true,
+        ..VmConfig::default()
+    });
+    let output = vm.interpret(source(ADD_FN));
+    let timings = output.timings.expect("Timings should be recorded");
+    let mut nodes: Vec<_> = timings.nodes.keys().map(String::as_str).collect();
+    nodes.sort_unstable();
+    assert_eq!(nodes, ["one", "one_ref", "result"]);
+    assert!(timings.functions.contains_key("add_two"));
+}
+
+#[test]
+fn profiles_opcodes_and_functions() {
+    let mut vm = Vm::with_config(VmConfig {
+        profile: true,
+        ..VmConfig::default()
+    });
+    vm.interpret(source(ADD_FN));
+    let profile = vm.take_profile().expect("Profiling should be enabled");
+    assert_eq!(profile.opcodes["OP_CALL"].count, 2);
+    assert_eq!(profile.functions["add_two"].count, 1);
+    assert_eq!(profile.functions["sum"].count, 1);
+    assert_eq!(profile.functions["<script>"].count, 1);
+    // Getting the function, calling it and outputting. The argument is a node of
+    // its own.
+    assert_eq!(profile.nodes["result"].count, 3);
+
+    let profile = vm.take_profile().unwrap();
+    assert!(profile.opcodes.is_empty());
+    assert!(Vm::new().take_profile().is_none());
+}
+
+#[test]
+fn reset_keeps_natives() {
+    let mut vm = Vm::new();
+    vm.interpret(source(ADD_FN));
+    vm.reset();
+    vm.collect_garbage();
+
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "one", "type": "literal", "value": 1 },
+                { "id": "total", "type": "call", "fnNodeId": "sum", "args": ["one", "one"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.additional_errors.is_empty());
+    assert!(matches!(output.node_values["total"], Value::Number(n) if n == 2.0));
+}
+
+#[test]
+fn step_in_slices() {
+    let mut vm = Vm::new();
+    vm.start(source(ADD_FN));
+    let mut slices = 1;
+    let output = loop {
+        match vm.step(1) {
+            Step::Running | Step::Breakpoint(_) => slices += 1,
+            Step::Done(output) => break output,
+        }
+    };
+    assert!(slices > 1);
+    assert!(matches!(output.node_values["result"], Value::Number(n) if n == 3.0));
+}
+
+#[test]
+fn cancel() {
+    let mut vm = Vm::new();
+    vm.set_breakpoint("result");
+    vm.start(source(ADD_FN));
+    assert!(matches!(vm.step(usize::MAX), Step::Breakpoint(_)));
+    let output = vm.cancel();
+    assert!(output.node_values.contains_key("one"));
+    assert!(!output.node_values.contains_key("result"));
+    assert_eq!(output.errors.additional_errors[0].code, "cancelled");
+
+    // Nothing is left to cancel
+    assert!(vm.cancel().errors.additional_errors.is_empty());
+}
+
+#[test]
+fn stop_with_error() {
+    let mut vm = Vm::new();
+    vm.start(source(ADD_FN));
+    let mut outputs = vec![];
+    while let Step::Running = vm.step_with(1, &mut |node_id, _| outputs.push(node_id.to_string())) {
+        if !outputs.is_empty() {
+            break;
+        }
+    }
+    assert_eq!(outputs, ["one"]);
+    let output = vm.stop(Error::runtime("Too slow."), "time_limit");
+    assert_eq!(output.errors.additional_errors[0].code, "time_limit");
+    assert_eq!(output.errors.additional_errors[0].message, "Too slow.");
+}
+
+#[test]
+fn start_again_after_abandoning() {
+    let mut vm = Vm::new();
+    vm.start(source(ADD_FN));
+    let mut outputs = 0;
+    while let Step::Running = vm.step_with(1, &mut |_, _| outputs += 1) {
+        if outputs > 0 {
+            break;
+        }
+    }
+
+    // Nothing of the abandoned run is output, even though the buffers are reused
+    vm.start(source(
+        r#"{ "nodes": [{ "id": "two", "type": "const", "value": 2 }] }"#,
+    ));
+    let Step::Done(output) = vm.step(usize::MAX) else {
+        panic!("Expected the graph to finish");
+    };
+    assert_eq!(output.node_values.len(), 1);
+    assert!(matches!(output.node_values["two"], Value::Number(n) if n == 2.0));
+
+    let output = vm.interpret(source(ADD_FN));
+    assert_eq!(output.node_values.len(), 3);
+}
+
+#[test]
+fn pause_at_breakpoint() {
+    let mut vm = Vm::new();
+    vm.set_breakpoint("result");
+    vm.start(source(ADD_FN));
+
+    let mut hits = 0;
+    let output = loop {
+        match vm.step(usize::MAX) {
+            Step::Running => {}
+            Step::Breakpoint(node_id) => {
+                assert_eq!(node_id, "result");
+                if hits == 0 {
+                    let computed: Vec<_> = vm.node_values().map(|(id, _)| id).collect();
+                    assert!(computed.contains(&"one"));
+                    assert!(!computed.contains(&"result"));
+                }
+                hits += 1;
+            }
+            Step::Done(output) => break output,
+        }
+    };
+    assert!(hits > 0);
+    assert!(matches!(output.node_values["result"], Value::Number(n) if n == 3.0));
+}
+
+#[test]
+fn inspect_paused_vm() {
+    let mut vm = Vm::new();
+    vm.set_breakpoint("body");
+    vm.start(source(ADD_FN));
+    assert!(matches!(vm.step(usize::MAX), Step::Breakpoint(id) if id == "body"));
+
+    let frames = vm.frames();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].function, "<script>");
+    assert_eq!(frames[0].node_id.as_deref(), Some("result"));
+    assert_eq!(frames[1].function, "add_two");
+    assert_eq!(frames[1].node_id.as_deref(), Some("body"));
+    // The callee followed by the argument
+    assert!(matches!(frames[1].slots, [Value::Function(_), Value::Number(n)] if *n == 1.0));
+    assert_eq!(
+        vm.stack().len(),
+        frames[0].slots.len() + frames[1].slots.len()
+    );
+
+    let globals = vm.globals();
+    assert!(globals.iter().any(|(name, _)| name == "add_two"));
+    assert!(globals.iter().any(|(name, _)| name == "sum"));
+}
+
+#[test]
+fn ignore_breakpoints_in_call_value() {
+    let mut vm = Vm::new();
+    vm.interpret(source(ADD_FN));
+    vm.set_breakpoint("body");
+
+    let add_two = global(&vm, "add_two");
+    let result = vm.call_value(add_two, &[Value::Number(1.0)]).unwrap();
+    assert!(matches!(result, Value::Number(n) if n == 3.0));
+    assert!(vm.stack().is_empty());
+}
+
+#[test]
+fn keep_outputs_computed_before_error() {
+    let mut vm = Vm::new();
+    vm.interpret(source(ADD_FN));
+    vm.reset();
+
+    // add_two is no longer defined
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "one", "type": "const", "value": 1 },
+                { "id": "call", "type": "call", "fnNodeId": "add_two", "args": [] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors["call"][0]
+        .message
+        .starts_with("Undefined variable 'add_two'."));
+    assert_eq!(output.node_values.len(), 1);
+    assert!(matches!(output.node_values["one"], Value::Number(n) if n == 1.0));
+}
+
+#[derive(Default)]
+struct Recorder(Rc<RefCell<Vec<String>>>);
+
+impl VmHooks for Recorder {
+    fn on_call(&mut self, _vm: &Vm, function: &str) {
+        self.0.borrow_mut().push(format!("call {function}"));
+    }
+
+    fn on_return(&mut self, _vm: &Vm, function: &str, _value: Value) {
+        self.0.borrow_mut().push(format!("return {function}"));
+    }
+
+    fn on_output(&mut self, _vm: &Vm, node_id: &str, _value: Value) {
+        self.0.borrow_mut().push(format!("output {node_id}"));
+    }
+}
+
+#[test]
+fn hooks_see_calls_and_outputs() {
+    let events = Rc::new(RefCell::new(vec![]));
+    let mut vm = Vm::new();
+    vm.set_hooks(Box::new(Recorder(events.clone())));
+    vm.interpret(source(ADD_FN));
+    assert_eq!(
+        *events.borrow(),
+        [
+            "call <script>",
+            "output one",
+            "output one_ref",
+            "call add_two",
+            "call sum",
+            "return sum",
+            "return add_two",
+            "output result",
+            "return <script>",
+        ]
+    );
+}
+
+#[test]
+fn enforce_heap_limit() {
+    // Each list of 1000 numbers takes up more than 16000 bytes
+    let list: Vec<_> = (0..1000).map(|n| n.to_string()).collect();
+    let graph = format!(
+        r#"{{
+            "nodes": [
+                {{ "id": "list", "type": "literal", "value": [{}] }},
+                {{ "id": "double", "type": "call", "fnNodeId": "sum", "args": ["list", "list"] }}
+            ]
+        }}"#,
+        list.join(",")
+    );
+
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(&graph));
+    assert!(output.errors.node_errors.is_empty());
+    vm.reset();
+    vm.collect_garbage();
+
+    // Enough for the natives and the compiled graph, which contains the literal
+    // list twice, but not for the new list
+    let natives: usize = vm.heap_dump().iter().map(|object| object.size).sum();
+    let max_heap_bytes = natives + 40000;
+    let mut vm = Vm::with_config(VmConfig {
+        max_heap_bytes: Some(max_heap_bytes),
+        ..VmConfig::default()
+    });
+    let output = vm.interpret(source(&graph));
+    assert!(output.errors.node_errors["double"][0]
+        .message
+        .starts_with(&format!("Heap limit of {max_heap_bytes} bytes exceeded.")));
+}
+
+#[test]
+fn disable_capabilities() {
+    let graph = r#"{
+        "nodes": [
+            { "id": "now", "type": "call", "fnNodeId": "clock", "args": [] }
+        ]
+    }"#;
+
+    let output = Vm::new().interpret(source(graph));
+    assert!(matches!(output.node_values["now"], Value::Number(_)));
+
+    let mut vm = Vm::with_config(VmConfig {
+        capabilities: Capabilities::none(),
+        ..VmConfig::default()
+    });
+    let output = vm.interpret(source(graph));
+    assert!(output.errors.node_errors["now"][0]
+        .message
+        .starts_with("Undefined variable 'clock'."));
+    assert!(vm.globals().iter().any(|(name, _)| name == "sum"));
+}
+
+#[test]
+fn preview_with_sample_args() {
+    let graph = |sample_args: &str| {
+        source(&ADD_FN.replace(
+            r#""type": "fn", "args": ["body"]"#,
+            &format!(r#""type": "fn", "args": ["body"], "sampleArgs": {sample_args}"#),
+        ))
+    };
+
+    let output = Vm::new().interpret(source(ADD_FN));
+    assert!(!output.node_values.contains_key("body"));
+
+    let output = Vm::new().interpret(graph("[5]"));
+    assert_eq!(output.node_values["body"], Value::Number(7.0));
+    assert_eq!(output.node_values["result"], Value::Number(3.0));
+
+    let output = Vm::new().interpret(graph("[5, 6]"));
+    assert!(output.errors.node_errors["add_two"][0]
+        .message
+        .starts_with("Expected 1 sample arguments but got 2."));
+}
+
+#[test]
+fn select_outputs() {
+    let graph = ADD_FN.replace(r#""nodes""#, r#""outputs": ["result"], "nodes""#);
+    let output = Vm::new().interpret(source(&graph));
+    assert_eq!(output.node_values.len(), 1);
+    assert_eq!(output.node_values["result"], Value::Number(3.0));
+}
+
+#[test]
+fn only_compile_needed_nodes() {
+    let graph = r#"{
+        "outputs": ["result"],
+        "nodes": [
+            { "id": "one", "type": "const", "value": 1 },
+            { "id": "one_ref", "type": "ref", "varNodeId": "one" },
+            { "id": "result", "type": "call", "fnNodeId": "sum", "args": ["one_ref", "one_ref"] },
+            { "id": "two", "type": "literal", "value": 2 },
+            { "id": "broken", "type": "call", "fnNodeId": "len", "args": ["two"] },
+            { "id": "unused", "type": "literal", "value": 2 }
+        ]
+    }"#;
+    let output = Vm::new().interpret(source(graph));
+    assert_eq!(output.node_values["result"], Value::Number(2.0));
+    assert!(output.errors.node_errors.is_empty());
+    assert!(output.errors.additional_errors.is_empty());
+    assert!(output.errors.node_diagnostics.is_empty());
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn msgpack_round_trip() {
+    let json: serde_json::Value = serde_json::from_str(ADD_FN).unwrap();
+    let source = Source::from_msgpack(&rmp_serde::to_vec(&json).unwrap()).unwrap();
+    let output = Vm::new().interpret(source);
+    let bytes = output.to_msgpack().unwrap();
+    let decoded: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+    assert_eq!(decoded, serde_json::to_value(&output).unwrap());
+    assert_eq!(decoded["nodeValues"]["result"], 3.0);
+    assert!(Source::from_msgpack(b"nonsense").is_err());
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn cbor_round_trip() {
+    let json: serde_json::Value = serde_json::from_str(ADD_FN).unwrap();
+    let mut bytes = vec![];
+    ciborium::into_writer(&json, &mut bytes).unwrap();
+    let output = Vm::new().interpret(Source::from_cbor(&bytes).unwrap());
+    let bytes = output.to_cbor().unwrap();
+    let decoded: serde_json::Value = ciborium::from_reader(bytes.as_slice()).unwrap();
+    assert_eq!(decoded, serde_json::to_value(&output).unwrap());
+    assert_eq!(decoded["nodeValues"]["result"], 3.0);
+}
+
+#[test]
+fn serialize_sorted() {
+    let graph = r#"{
+        "nodes": [
+            { "id": "c", "type": "const", "value": 3 },
+            { "id": "a", "type": "const", "value": 1 },
+            { "id": "b", "type": "const", "value": 2 }
+        ]
+    }"#;
+    let json = serde_json::to_string(&Vm::new().interpret(source(graph))).unwrap();
+    assert!(json.starts_with(r#"{"nodeValues":{"a":1.0,"b":2.0,"c":3.0}"#));
+}
+
+#[test]
+fn report_diagnostics() {
+    let graph = ADD_FN.replace(
+        r#""nodes": ["#,
+        r#""nodes": [ { "id": "stray", "type": "literal", "value": 4 },"#,
+    );
+    let output = Vm::new().interpret(source(&graph));
+    assert_eq!(
+        output.errors.node_diagnostics["stray"],
+        [Diagnostic::warning("unused_node", "Unused node.")]
+    );
+    assert_eq!(output.errors.node_diagnostics.len(), 1);
+    assert!(output.errors.node_errors.is_empty());
+    let json = serde_json::to_value(&output).unwrap();
+    assert_eq!(
+        json["nodeDiagnostics"]["stray"],
+        serde_json::json!([{
+            "code": "unused_node",
+            "nodeId": "stray",
+            "message": "Unused node.",
+            "severity": "warning",
+        }])
+    );
+
+    let output = Vm::new().interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "lit", "type": "literal", "value": 2 },
+                { "id": "two", "type": "fn", "args": ["lit"] }
+            ]
+        }"#,
+    ));
+    assert_eq!(
+        output.errors.node_diagnostics["two"][0].severity,
+        Severity::Info
+    );
+}
+
+#[test]
+fn multiple_errors_per_node() {
+    let output = Vm::new().interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "param", "type": "param" },
+                { "id": "message", "type": "literal", "value": "Must be positive" },
+                { "id": "body", "type": "call", "fnNodeId": "assert", "args": ["param", "message"] },
+                { "id": "check", "type": "fn", "args": ["body"] },
+                { "id": "off", "type": "literal", "value": false },
+                { "id": "first", "type": "call", "fnNodeId": "check", "args": ["off"] },
+                { "id": "second", "type": "call", "fnNodeId": "check", "args": ["off"] }
+            ]
+        }"#,
+    ));
+    let errors = &output.errors.node_errors["body"];
+    assert_eq!(errors.len(), 2);
+    assert!(errors.iter().all(|error| error.severity == Severity::Error
+        && error.code == "assertion"
+        && error.message.starts_with("Must be positive")));
+}
+
+#[test]
+fn stacktrace_with_nodes() {
+    let output = Vm::new().interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "param", "type": "param" },
+                { "id": "body", "type": "unary", "unary_type": { "type": "negate" }, "args": ["param"] },
+                { "id": "negate", "type": "fn", "args": ["body"] },
+                { "id": "text", "type": "literal", "value": "a" },
+                { "id": "call", "type": "call", "fnNodeId": "negate", "args": ["text"] }
+            ]
+        }"#,
+    ));
+    assert_eq!(output.errors.node_errors["body"][0].code, "runtime");
+    assert_eq!(
+        output.errors.node_errors["body"][0].message,
+        "Operand must be a number.\nin <fn \"negate\"> at node 'body'\nin <script> at node 'call'"
+    );
+}
+
+#[test]
+fn stream_outputs() {
+    let mut streamed = vec![];
+    let output = Vm::new().interpret_with(source(ADD_FN), |node_id, value| {
+        streamed.push((node_id.to_string(), value.to_string()));
+    });
+    assert!(!streamed.is_empty());
+    assert_eq!(streamed.len(), output.node_values.len());
+    for (node_id, value) in &streamed {
+        assert_eq!(*value, output.node_values[node_id].to_string());
+    }
+}
+
+#[test]
+fn host_data() {
+    let graph = |key: &str| {
+        source(&format!(
+            r#"{{
+                "nodes": [
+                    {{ "id": "key", "type": "literal", "value": "{key}" }},
+                    {{ "id": "readings", "type": "call", "fnNodeId": "data", "args": ["key"] }},
+                    {{ "id": "count", "type": "call", "fnNodeId": "len", "args": ["readings"] }}
+                ]
+            }}"#
+        ))
+    };
+
+    let mut vm = Vm::new();
+    let readings = Value::from_json(&mut vm, &serde_json::json!([1.5, 2.5, 3.5]));
+    vm.set_host_data("readings", readings);
+    vm.collect_garbage();
+    let output = vm.interpret(graph("readings"));
+    assert_eq!(output.node_values["count"], Value::Number(3.0));
+
+    let output = vm.interpret(graph("missing"));
+    assert!(output.errors.node_errors["readings"][0]
+        .message
+        .starts_with("No host data named 'missing'."));
+
+    let mut vm = Vm::with_config(VmConfig {
+        capabilities: Capabilities::none(),
+        ..VmConfig::default()
+    });
+    let output = vm.interpret(graph("readings"));
+    assert!(output.errors.node_errors["readings"][0]
+        .message
+        .starts_with("Undefined variable 'data'."));
+}
+
+#[test]
+fn record_memory() {
+    let output = Vm::new().interpret(source(ADD_FN));
+    assert!(output.memory.is_none());
+
+    let mut vm = Vm::with_config(VmConfig {
+        record_memory: true,
+        gc: GcConfig {
+            initial_threshold: 0,
+            grow_factor: 0.0,
+            ..GcConfig::default()
+        },
+        ..VmConfig::default()
+    });
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "list", "type": "literal", "value": [1, 2, 3] },
+                { "id": "reversed", "type": "call", "fnNodeId": "reverse", "args": ["list"] }
+            ]
+        }"#,
+    ));
+    let memory = output.memory.unwrap();
+    assert!(memory.collections > 0);
+    assert!(memory.bytes_allocated > 0);
+    assert!(memory.peak_heap >= memory.bytes_allocated);
+    assert_eq!(vm.memory_stats().bytes_allocated, memory.bytes_allocated);
+    let json = serde_json::to_value(&output).unwrap();
+    assert_eq!(json["memory"]["collections"], memory.collections);
+}
+
+#[test]
+fn validate() {
+    let mut vm = Vm::new();
+    let output = vm.validate(source(
+        r#"{
+            "nodes": [
+                { "id": "a", "type": "var", "args": ["b"] },
+                { "id": "b", "type": "var", "args": ["a"] },
+                { "id": "wrong_args", "type": "call", "fnNodeId": "add_two", "args": [] },
+                { "id": "param", "type": "param" },
+                { "id": "body", "type": "call", "fnNodeId": "sum", "args": ["param", "param"] },
+                { "id": "add_two", "type": "fn", "args": ["body"] }
+            ]
+        }"#,
+    ));
+    assert!(output.node_values.is_empty());
+    assert!(output.errors.node_errors.contains_key("wrong_args"));
+    assert!(!output.errors.node_errors.is_empty());
+
+    // Runtime errors aren't found without running
+    let output = vm.validate(source(
+        r#"{
+            "nodes": [
+                { "id": "lit", "type": "literal", "value": "text" },
+                { "id": "neg", "type": "unary", "unary_type": { "type": "negate" }, "args": ["lit"] },
+                { "id": "out", "type": "var", "args": ["neg"] }
+            ]
+        }"#,
+    ));
+    assert!(output.node_values.is_empty());
+    assert!(output.errors.node_errors.is_empty());
+    assert!(output.errors.additional_errors.is_empty());
+}
+
+#[test]
+fn disassemble() {
+    let disassembly = Vm::new().disassemble(source(ADD_FN));
+    assert!(disassembly.starts_with("== <script> ==\n"));
+    assert!(disassembly.contains("\n== add_two ==\n"));
+    assert!(disassembly.contains("OP_CALL"));
+    assert!(disassembly.contains("OP_OUTPUT"));
+}
+
+#[test]
+fn fuse_instructions() {
+    let json = ADD_FN.replace(r#""args": ["param", "lit"]"#, r#""args": ["lit", "param"]"#);
+    let disassembly = Vm::new().disassemble(source(&json));
+    // Getting the referenced variable and outputting it
+    assert!(disassembly.contains("OP_GET_SLOT_OUTPUT"));
+    // Getting the last argument belongs to another node than calling sum, so
+    // they're kept apart for breakpoints, profiles and errors
+    assert!(!disassembly.contains("OP_GET_LOCAL_CALL"));
+
+    let output = Vm::new().interpret(source(&json));
+    assert!(matches!(output.node_values["one_ref"], Value::Number(n) if n == 1.0));
+    assert!(matches!(output.node_values["result"], Value::Number(n) if n == 3.0));
+}
+
+#[test]
+fn compile_functions_separately() {
+    let json = r#"{
+        "nodes": [
+            { "id": "two", "type": "literal", "value": 2 },
+            { "id": "x", "type": "param" },
+            { "id": "add_two_body", "type": "call", "fnNodeId": "sum", "args": ["x", "two"] },
+            { "id": "add_two", "type": "fn", "args": ["add_two_body"] },
+            { "id": "y", "type": "param" },
+            { "id": "add_four_inner", "type": "call", "fnNodeId": "add_two", "args": ["y"] },
+            { "id": "add_four_body", "type": "call", "fnNodeId": "add_two", "args": ["add_four_inner"] },
+            { "id": "add_four", "type": "fn", "args": ["add_four_body"] },
+            { "id": "z", "type": "param" },
+            { "id": "broken_body", "type": "unary", "unary_type": { "type": "negate" }, "args": ["z", "z"] },
+            { "id": "broken", "type": "fn", "args": ["broken_body"] },
+            { "id": "three", "type": "literal", "value": 3 },
+            { "id": "result", "type": "call", "fnNodeId": "add_four", "args": ["three"] }
+        ]
+    }"#;
+    let mut vm = Vm::new();
+    for _ in 0..2 {
+        let output = vm.interpret(source(json));
+        assert!(matches!(output.node_values["result"], Value::Number(n) if n == 7.0));
+        // A function whose body doesn't compile is left undefined
+        assert_eq!(
+            output.errors.node_errors["broken_body"][0].message,
+            "Unary has invalid input."
+        );
+        assert!(vm.globals().iter().all(|(name, _)| name != "broken"));
+    }
+}
+
+#[test]
+fn interpret_batch_with_inputs() {
+    let mut vm = Vm::new();
+    let program = vm.compile(source(ADD_FN));
+    vm.collect_garbage();
+
+    let inputs: Vec<Inputs> = [1.0, 5.0, -2.0]
+        .into_iter()
+        .map(|n| [("one".to_string(), LiteralType::Number(n))].into())
+        .collect();
+    let outputs = vm.interpret_batch(&program, &inputs);
+    let results: Vec<_> = outputs
+        .iter()
+        .map(|output| match output.node_values["result"] {
+            Value::Number(n) => n,
+            _ => panic!("Expected a number"),
+        })
+        .collect();
+    assert_eq!(results, [3.0, 7.0, 0.0]);
+    assert!(matches!(outputs[1].node_values["one"], Value::Number(n) if n == 5.0));
+
+    let outputs = vm.interpret_batch(
+        &program,
+        &[[("missing".to_string(), LiteralType::Nil)].into()],
+    );
+    assert!(outputs[0].errors.node_errors.contains_key("missing"));
+    assert!(matches!(outputs[0].node_values["result"], Value::Number(n) if n == 3.0));
+}
+
+#[test]
+fn free_compiled_program() {
+    let mut vm = Vm::new();
+    let is_add_two = |object: &HeapObject| object.repr == r#"<fn "add_two">"#;
+    let program = vm.compile(source(ADD_FN));
+    vm.collect_garbage();
+    assert!(vm.heap_dump().iter().any(is_add_two));
+
+    vm.free_program(program);
+    vm.collect_garbage();
+    assert!(!vm.heap_dump().iter().any(is_add_two));
+}
+
+#[test]
+fn incremental_gc() {
+    let mut vm = Vm::new();
+    vm.interpret(source(ADD_FN));
+    let mut steps = 1;
+    while !vm.collect_garbage_step(1) {
+        steps += 1;
+    }
+    assert!(steps > 1);
+
+    // Collect in slices while running
+    let mut vm = Vm::with_config(VmConfig {
+        gc: GcConfig {
+            initial_threshold: 0,
+            incremental_step: Some(1),
+            ..GcConfig::default()
+        },
+        ..VmConfig::default()
+    });
+    for _ in 0..3 {
+        let output = vm.interpret(source(ADD_FN));
+        assert!(matches!(output.node_values["result"], Value::Number(n) if n == 3.0));
+    }
+}
+
+#[test]
+fn free_arena_between_runs() {
+    let list: Vec<_> = (0..1000).map(|n| n.to_string()).collect();
+    let graph = format!(
+        r#"{{
+            "nodes": [
+                {{ "id": "list", "type": "literal", "value": [{}] }},
+                {{ "id": "double", "type": "call", "fnNodeId": "sum", "args": ["list", "list"] }}
+            ]
+        }}"#,
+        list.join(",")
+    );
+
+    // Each run needs less than 100000 bytes, which would add up without the arena
+    let mut vm = Vm::with_config(VmConfig {
+        max_heap_bytes: Some(100000),
+        gc: GcConfig {
+            arena: true,
+            ..GcConfig::default()
+        },
+        ..VmConfig::default()
+    });
+    for _ in 0..10 {
+        let output = vm.interpret(source(&graph));
+        assert!(output.errors.node_errors.is_empty());
+        assert!(matches!(&output.node_values["double"], Value::List(_)));
+    }
+
+    let program = vm.compile(source(ADD_FN));
+    for _ in 0..3 {
+        let outputs = vm.interpret_batch(&program, &[Inputs::new(), Inputs::new()]);
+        for output in outputs {
+            assert!(matches!(output.node_values["result"], Value::Number(n) if n == 3.0));
+        }
+    }
+}
+
+#[test]
+fn forget_globals_between_runs() {
+    let mut vm = Vm::with_config(VmConfig {
+        globals_scope: GlobalsScope::Run,
+        ..VmConfig::default()
+    });
+    vm.interpret(source(ADD_FN));
+    assert!(vm.globals().iter().any(|(name, _)| name == "add_two"));
+
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "call", "type": "call", "fnNodeId": "add_two", "args": [] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors["call"][0]
+        .message
+        .starts_with("Undefined variable 'add_two'."));
+    assert!(vm.globals().iter().all(|(name, _)| name != "add_two"));
+    assert!(vm.globals().iter().any(|(name, _)| name == "sum"));
+}
+
+#[test]
+fn keep_globals_across_graphs() {
+    let mut vm = Vm::new();
+    vm.interpret(source(
+        r#"{ "nodes": [{ "id": "x", "type": "const", "value": 2 }] }"#,
+    ));
+    let uses_x = r#"{
+        "nodes": [
+            { "id": "x_ref", "type": "ref", "varNodeId": "x" },
+            { "id": "y", "type": "call", "fnNodeId": "sum", "args": ["x_ref", "x_ref"] }
+        ]
+    }"#;
+    let output = vm.interpret(source(uses_x));
+    assert!(matches!(output.node_values["y"], Value::Number(n) if n == 4.0));
+
+    vm.reset();
+    let output = vm.interpret(source(uses_x));
+    assert!(output.errors.node_errors["x_ref"][0]
+        .message
+        .starts_with("Undefined variable 'x'."));
+    assert!(matches!(global(&vm, "sum"), Value::NativeFunction(_)));
+    assert!(matches!(global(&vm, "pi"), Value::Number(_)));
+}
+
+#[test]
+fn keep_host_functions_across_reset() {
+    let mut vm = Vm::new();
+    vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "k", "type": "const", "value": 2 },
+                { "id": "p", "type": "param" },
+                { "id": "k_ref", "type": "ref", "varNodeId": "k" },
+                { "id": "body", "type": "call", "fnNodeId": "sum", "args": ["p", "k_ref"] },
+                { "id": "g", "type": "fn", "args": ["body"] }
+            ]
+        }"#,
+    ));
+    let g = global(&vm, "g");
+    vm.set_host_data("f", g);
+    assert_eq!(vm.call_value(g, &[1.into()]).unwrap(), Value::Number(3.0));
+
+    vm.reset();
+    let error = vm.call_value(g, &[1.into()]).unwrap_err();
+    assert!(error.to_string().contains("Undefined variable 'k'."));
+}
+
+#[test]
+fn dump_heap() {
+    let mut vm = Vm::new();
+    let dump = vm.heap_dump();
+    assert!(dump
+        .iter()
+        .any(|object| object.kind == "native" && object.repr == "<native fn sum>"));
+    // The names of globals aren't on the heap, unlike interned strings
+    vm.intern("text");
+    let dump = vm.heap_dump();
+    assert!(dump
+        .iter()
+        .any(|object| object.kind == "string" && object.repr == "\"text\""));
+
+    let list: Vec<_> = (0..100).map(|n| n.to_string()).collect();
+    vm.interpret(source(&format!(
+        r#"{{ "nodes": [{{ "id": "list", "type": "literal", "value": [{}] }}] }}"#,
+        list.join(",")
+    )));
+    let dump = vm.heap_dump();
+    let list = dump
+        .iter()
+        .find(|object| object.kind == "list")
+        .expect("The literal list should be on the heap");
+    assert!(list.size > 100 * 8);
+    assert!(list.repr.starts_with("[0.0, 1.0"));
+    assert!(list.repr.ends_with('…'));
+}
+
+fn global(vm: &Vm, name: &str) -> Value {
+    vm.globals()
+        .into_iter()
+        .find_map(|(global, value)| (global == name).then_some(value))
+        .expect("Undefined global")
+}
+
+#[test]
+fn build_maps() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "key", "type": "literal", "value": "answer" },
+                { "id": "value", "type": "literal", "value": 42 },
+                { "id": "record", "type": "call", "fnNodeId": "map", "args": ["key", "value", "value", "key"] },
+                { "id": "lookup", "type": "call", "fnNodeId": "map_get", "args": ["record", "key"] }
+            ]
+        }"#,
+    ));
+    assert!(matches!(output.node_values["lookup"], Value::Number(n) if n == 42.0));
+    assert_eq!(
+        serde_json::to_value(output.node_values["record"]).unwrap(),
+        serde_json::json!({ "answer": 42.0, "42": "answer" })
+    );
+    // Entries keep the order they were given in
+    assert_eq!(
+        serde_json::to_string(&output.node_values["record"]).unwrap(),
+        r#"{"answer":42.0,"42":"answer"}"#
+    );
+
+    let clash = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "number", "type": "literal", "value": 42 },
+                { "id": "string", "type": "literal", "value": "42" },
+                { "id": "clash", "type": "call", "fnNodeId": "map", "args": ["number", "string", "string", "number"] }
+            ]
+        }"#,
+    ));
+    let error = format!("{:?}", clash.errors.node_errors["clash"]);
+    assert!(error.contains("can't both be in a map."));
+
+    let map_get = global(&vm, "map_get");
+    let record = output.node_values["record"];
+    let error = vm.call_value(map_get, &[record, record]).unwrap_err();
+    assert!(format!("{error:?}").contains("Map keys must be strings or numbers."));
+    let error = vm
+        .call_value(map_get, &[record, Value::Number(f64::NAN)])
+        .unwrap_err();
+    assert!(format!("{error:?}").contains("Map keys can't be NaN."));
+}
+
+#[test]
+fn slice_bytes() {
+    let mut vm = Vm::new();
+    let bytes = vm.bytes(b"hello world".to_vec());
+    assert_eq!(serde_json::to_value(bytes).unwrap(), "aGVsbG8gd29ybGQ=");
+
+    let slice = global(&vm, "bytes_slice");
+    let hello = vm
+        .call_value(slice, &[bytes, Value::Number(0.0), Value::Number(5.0)])
+        .unwrap();
+    assert_eq!(serde_json::to_value(hello).unwrap(), "aGVsbG8=");
+    let len = global(&vm, "bytes_len");
+    assert!(matches!(vm.call_value(len, &[hello]), Ok(Value::Number(n)) if n == 5.0));
+
+    let error = vm
+        .call_value(slice, &[bytes, Value::Number(5.0), Value::Number(12.0)])
+        .unwrap_err();
+    assert!(format!("{error:?}").contains("Slice out of range."));
+}
+
+#[test]
+fn exact_ints() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "big", "type": "literal", "value": 9007199254740993 },
+                { "id": "one", "type": "literal", "value": 1 },
+                { "id": "next", "type": "call", "fnNodeId": "sum", "args": ["big", "one"] },
+                { "id": "diff", "type": "binary", "binary_type": { "type": "subtract" }, "args": ["big", "one"] },
+                { "id": "prev", "type": "var", "args": ["diff"] },
+                { "id": "digits", "type": "literal", "value": "123456789012345678" },
+                { "id": "parsed", "type": "call", "fnNodeId": "int", "args": ["digits"] },
+                { "id": "min", "type": "literal", "value": -9223372036854775808.0 },
+                { "id": "min_int", "type": "call", "fnNodeId": "int", "args": ["min"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty());
+    let json = serde_json::to_value(&output.node_values).unwrap();
+    assert_eq!(json["next"], serde_json::json!(9007199254740994_i64));
+    assert_eq!(json["prev"], serde_json::json!(9007199254740992_i64));
+    assert_eq!(json["parsed"], serde_json::json!(123456789012345678_i64));
+    // -2^63 is i64::MIN exactly, unlike 2^63 and i64::MAX
+    assert_eq!(json["min_int"], serde_json::json!(i64::MIN));
+
+    // i64::MAX rounds up to 2^63 as a number, so it must stay an int
+    let parse_number = global(&vm, "parse_number");
+    let max = vm.string(&i64::MAX.to_string());
+    assert_eq!(
+        vm.call_value(parse_number, &[max]).unwrap(),
+        Value::Int(i64::MAX)
+    );
+    assert_ne!(Value::Int(i64::MAX), Value::Number(2.0_f64.powi(63)));
+}
+
+#[test]
+fn convert_values() {
+    let mut vm = Vm::new();
+    let sum = global(&vm, "sum");
+    let total: f64 = vm
+        .call_value(sum, &[1.5.into(), 2.into(), true.into()])
+        .and_then(f64::try_from)
+        .unwrap();
+    assert_eq!(total, 4.5);
+
+    let greeting = vm.string("hello");
+    let list = vm.list([greeting, Value::from(None::<bool>), 7_i64.into()]);
+    let values = Vec::<Value>::try_from(list).unwrap();
+    assert_eq!(String::try_from(values[0]).unwrap(), "hello");
+    assert_eq!(values[1], Value::Nil);
+    assert_eq!(i64::try_from(values[2]).unwrap(), 7);
+    assert!(bool::try_from(values[2]).is_err());
+}
+
+#[test]
+fn convert_json() {
+    let mut vm = Vm::new();
+    let json = serde_json::json!({
+        "name": "banjo",
+        "tags": ["a", 1.5, null, true],
+        "nested": { "id": 9_007_199_254_740_993_i64 },
+    });
+    let value = Value::from_json(&mut vm, &json);
+    vm.root(value);
+    vm.collect_garbage();
+    assert_eq!(value.to_json(), json);
+
+    let map_get = global(&vm, "map_get");
+    let key = vm.string("tags");
+    let tags = vm.call_value(map_get, &[value, key]).unwrap();
+    assert_eq!(Vec::<Value>::try_from(tags).unwrap().len(), 4);
+}
+
+#[test]
+fn compare_lists_structurally() {
+    let mut vm = Vm::new();
+    let a = vm.list([1.0.into(), 2.0.into()]);
+    vm.root(a);
+    let b = vm.list([1.0.into(), 2.0.into()]);
+    vm.root(b);
+    let c = vm.list([1.0.into(), 3.0.into()]);
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+
+    let outer_a = vm.list([a]);
+    vm.root(outer_a);
+    let outer_b = vm.list([b]);
+    assert_eq!(outer_a, outer_b);
+
+    // Each list contains itself
+    for list in [a, b] {
+        let Value::List(mut list) = list else {
+            unreachable!()
+        };
+        let itself = Value::List(list);
+        list.values.push(itself);
+    }
+    assert_eq!(a, b);
+}
+
+#[test]
+fn compare_strings() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "apple", "type": "literal", "value": "apple" },
+                { "id": "banana", "type": "literal", "value": "banana" },
+                { "id": "gt", "type": "binary", "binary_type": { "type": ">" }, "args": ["banana", "apple"] },
+                { "id": "lt", "type": "binary", "binary_type": { "type": "<" }, "args": ["banana", "apple"] },
+                { "id": "ge", "type": "binary", "binary_type": { "type": ">=" }, "args": ["apple", "apple"] },
+                { "id": "le", "type": "binary", "binary_type": { "type": "<=" }, "args": ["banana", "apple"] },
+                { "id": "greater", "type": "var", "args": ["gt"] },
+                { "id": "less", "type": "var", "args": ["lt"] },
+                { "id": "greater_equal", "type": "var", "args": ["ge"] },
+                { "id": "less_equal", "type": "var", "args": ["le"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty());
+    let json = serde_json::to_value(&output.node_values).unwrap();
+    assert_eq!(json["greater"], true);
+    assert_eq!(json["less"], false);
+    assert_eq!(json["greater_equal"], true);
+    assert_eq!(json["less_equal"], false);
+}
+
+#[test]
+fn format_numbers() {
+    let mut vm = Vm::with_config(VmConfig {
+        number_format: NumberFormat::Fixed(2),
+        ..VmConfig::default()
+    });
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "third", "type": "literal", "value": 0.333333 },
+                { "id": "rounded", "type": "var", "args": ["third"] },
+                { "id": "text", "type": "call", "fnNodeId": "string", "args": ["third"] }
+            ]
+        }"#,
+    ));
+    let json = serde_json::to_value(&output).unwrap();
+    assert_eq!(json["nodeValues"]["rounded"], 0.33);
+    assert_eq!(json["nodeValues"]["text"], "0.33");
+
+    let mut vm = Vm::with_config(VmConfig {
+        number_format: NumberFormat::Scientific(1),
+        ..VmConfig::default()
+    });
+    let list = vm.list([1234.0.into(), 5_i64.into()]);
+    let string = global(&vm, "string");
+    let text = vm.call_value(string, &[list]).unwrap();
+    assert_eq!(String::try_from(text).unwrap(), "[1.2e3, 5]");
+    assert_eq!(Value::Number(0.5).to_string(), "0.5");
+}
+
+#[test]
+fn tag_types() {
+    let graph = r#"{
+        "nodes": [
+            { "id": "a", "type": "literal", "value": 3.5 },
+            { "id": "b", "type": "literal", "value": "3.5" },
+            { "id": "c", "type": "literal", "value": null },
+            { "id": "number", "type": "var", "args": ["a"] },
+            { "id": "string", "type": "var", "args": ["b"] },
+            { "id": "nil", "type": "var", "args": ["c"] }
+        ]
+    }"#;
+    let mut vm = Vm::with_config(VmConfig {
+        tag_types: true,
+        ..VmConfig::default()
+    });
+    let json = serde_json::to_value(vm.interpret(source(graph))).unwrap();
+    let node_values = &json["nodeValues"];
+    assert_eq!(
+        node_values["number"],
+        serde_json::json!({ "type": "number", "value": 3.5 })
+    );
+    assert_eq!(
+        node_values["string"],
+        serde_json::json!({ "type": "string", "value": "3.5" })
+    );
+    assert_eq!(
+        node_values["nil"],
+        serde_json::json!({ "type": "nil", "value": null })
+    );
+
+    let json = serde_json::to_value(Vm::new().interpret(source(graph))).unwrap();
+    assert_eq!(json["nodeValues"]["number"], 3.5);
+}
+
+#[test]
+fn truncate_output() {
+    let mut vm = Vm::with_config(VmConfig {
+        output_limits: OutputLimits {
+            max_list_elements: Some(2),
+            max_string_bytes: Some(3),
+        },
+        ..VmConfig::default()
+    });
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "a", "type": "literal", "value": [[1.5, 2.5, 3.5], 4.5, 5.5] },
+                { "id": "b", "type": "literal", "value": "héllo" },
+                { "id": "c", "type": "literal", "value": [1.5, "ab"] },
+                { "id": "list", "type": "var", "args": ["a"] },
+                { "id": "string", "type": "var", "args": ["b"] },
+                { "id": "short", "type": "var", "args": ["c"] }
+            ]
+        }"#,
+    ));
+    let json = serde_json::to_value(output).unwrap();
+    let node_values = &json["nodeValues"];
+    assert_eq!(
+        node_values["list"],
+        serde_json::json!({ "value": [[1.5, 2.5], 4.5], "truncated": true })
+    );
+    assert_eq!(
+        node_values["string"],
+        serde_json::json!({ "value": "hé", "truncated": true })
+    );
+    assert_eq!(node_values["short"], serde_json::json!([1.5, "ab"]));
+}
+
+#[test]
+fn math_natives() {
+    let mut vm = Vm::new();
+    let mut call = |name: &str, arg: Value| {
+        let native = global(&vm, name);
+        vm.call_value(native, &[arg]).unwrap()
+    };
+    assert_eq!(call("sqrt", 9.0.into()), Value::Number(3.0));
+    assert_eq!(call("abs", (-2.5).into()), Value::Number(2.5));
+    assert_eq!(
+        call("abs", (-9_007_199_254_740_993_i64).into()),
+        Value::Int(9_007_199_254_740_993)
+    );
+    assert_eq!(call("floor", (-1.5).into()), Value::Number(-2.0));
+    assert_eq!(call("ceil", 1.2.into()), Value::Number(2.0));
+    assert_eq!(call("round", 2.5.into()), Value::Number(3.0));
+    assert_eq!(call("ln", 1.0.into()), Value::Number(0.0));
+    assert_eq!(call("log10", 1000.0.into()), Value::Number(3.0));
+    assert_eq!(call("exp", 0.0.into()), Value::Number(1.0));
+
+    let list = vm.list([(-1.0).into(), 4.0.into()]);
+    let abs = global(&vm, "abs");
+    let result = vm.call_value(abs, &[list]).unwrap();
+    vm.root(result);
+    let expected = vm.list([1.0.into(), 4.0.into()]);
+    assert_eq!(result, expected);
+}
+
+#[test]
+fn trigonometry() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "angle", "type": "ref", "varNodeId": "pi" },
+                { "id": "cosine", "type": "call", "fnNodeId": "cos", "args": ["angle"] },
+                { "id": "one", "type": "literal", "value": 1 },
+                { "id": "zero", "type": "literal", "value": 0 },
+                { "id": "quarter", "type": "call", "fnNodeId": "atan2", "args": ["one", "zero"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty());
+    assert_eq!(output.node_values["cosine"], Value::Number(-1.0));
+    assert_eq!(
+        output.node_values["quarter"],
+        Value::Number(std::f64::consts::FRAC_PI_2)
+    );
+    assert_eq!(global(&vm, "e"), Value::Number(std::f64::consts::E));
+}
+
+#[test]
+fn min_max_clamp() {
+    let mut vm = Vm::new();
+    let min = global(&vm, "min");
+    let max = global(&vm, "max");
+    let clamp = global(&vm, "clamp");
+
+    let result = vm.call_value(min, &[3.0.into(), 1.5.into(), 2.into()]);
+    assert_eq!(result.unwrap(), Value::Number(1.5));
+    let result = vm.call_value(max, &[3.into(), 7.into()]);
+    assert_eq!(result.unwrap(), Value::Int(7));
+
+    let a = vm.list([1.0.into(), 5.0.into()]);
+    vm.root(a);
+    let b = vm.list([4.0.into(), 2.0.into()]);
+    vm.root(b);
+    let result = vm.call_value(max, &[a, b]).unwrap();
+    vm.root(result);
+    let expected = vm.list([4.0.into(), 5.0.into()]);
+    assert_eq!(result, expected);
+
+    let values = vm.list([(-1.0).into(), 0.5.into(), 2.0.into()]);
+    let result = vm
+        .call_value(clamp, &[values, 0.0.into(), 1.0.into()])
+        .unwrap();
+    vm.root(result);
+    let expected = vm.list([0.0.into(), 0.5.into(), 1.0.into()]);
+    assert_eq!(result, expected);
+
+    let short = vm.list([1.0.into()]);
+    assert!(vm.call_value(min, &[a, short]).is_err());
+    assert!(vm.call_value(min, &[]).is_err());
+}
+
+#[test]
+fn seeded_random() {
+    let config = VmConfig {
+        random_seed: Some(42),
+        ..VmConfig::default()
+    };
+    let draw = |vm: &mut Vm| {
+        let random_range = global(vm, "random_range");
+        (0..10)
+            .map(|_| {
+                f64::try_from(vm.call_value(random_range, &[5.into(), 10.into()]).unwrap()).unwrap()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut vm = Vm::with_config(config.clone());
+    let numbers = draw(&mut vm);
+    assert!(numbers.iter().all(|n| (5.0..10.0).contains(n)));
+    assert_eq!(numbers, draw(&mut Vm::with_config(config)));
+    assert_ne!(numbers, draw(&mut vm));
+    vm.set_random_seed(42);
+    assert_eq!(numbers, draw(&mut vm));
+}
+
+#[test]
+fn list_natives() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "numbers", "type": "literal", "value": [3, 1, 2] },
+                { "id": "words", "type": "literal", "value": ["b", "a"] },
+                { "id": "four", "type": "literal", "value": 4 },
+                { "id": "count", "type": "call", "fnNodeId": "len", "args": ["numbers"] },
+                { "id": "appended", "type": "call", "fnNodeId": "append", "args": ["numbers", "four"] },
+                { "id": "joined", "type": "call", "fnNodeId": "concat", "args": ["numbers", "words"] },
+                { "id": "reversed", "type": "call", "fnNodeId": "reverse", "args": ["numbers"] },
+                { "id": "sorted", "type": "call", "fnNodeId": "sort", "args": ["words"] }
+            ]
+        }"#,
+    ));
+    let json = serde_json::to_value(&output.node_values).unwrap();
+    assert_eq!(json["count"], 3.0);
+    assert_eq!(json["appended"], serde_json::json!([3.0, 1.0, 2.0, 4.0]));
+    assert_eq!(json["joined"], serde_json::json!([3.0, 1.0, 2.0, "b", "a"]));
+    assert_eq!(json["reversed"], serde_json::json!([2.0, 1.0, 3.0]));
+    assert_eq!(json["sorted"], serde_json::json!(["a", "b"]));
+
+    let sort = global(&vm, "sort");
+    let numbers = vm.list([3.into(), 1.5.into(), 2.into()]);
+    let sorted = vm.call_value(sort, &[numbers]).unwrap();
+    vm.root(sorted);
+    let expected = vm.list([1.5.into(), 2.into(), 3.into()]);
+    assert_eq!(sorted, expected);
+    let word = vm.string("a");
+    let mixed = vm.list([1.into(), word]);
+    assert!(vm.call_value(sort, &[mixed]).is_err());
+
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "four", "type": "literal", "value": 4 },
+                { "id": "count", "type": "call", "fnNodeId": "len", "args": ["four"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors["count"][0]
+        .message
+        .contains("Expected a list."));
+}
+
+#[test]
+fn higher_order_natives() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "numbers", "type": "literal", "value": [1, 2, 3, 4] },
+                { "id": "x", "type": "param" },
+                { "id": "two", "type": "literal", "value": 2 },
+                { "id": "doubled", "type": "call", "fnNodeId": "product", "args": ["x", "two"] },
+                { "id": "double", "type": "fn", "args": ["doubled"] },
+                { "id": "double_ref", "type": "ref", "varNodeId": "double" },
+                { "id": "y", "type": "param" },
+                { "id": "big", "type": "binary", "binary_type": { "type": ">" }, "args": ["y", "two"] },
+                { "id": "is_big", "type": "fn", "args": ["big"] },
+                { "id": "is_big_ref", "type": "ref", "varNodeId": "is_big" },
+                { "id": "sum_ref", "type": "ref", "varNodeId": "sum" },
+                { "id": "zero", "type": "literal", "value": 0 },
+                { "id": "mapped", "type": "call", "fnNodeId": "list_map", "args": ["double_ref", "numbers"] },
+                { "id": "filtered", "type": "call", "fnNodeId": "filter", "args": ["is_big_ref", "numbers"] },
+                { "id": "total", "type": "call", "fnNodeId": "reduce", "args": ["sum_ref", "zero", "numbers"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty(), "{:?}", output.errors);
+    let json = serde_json::to_value(&output.node_values).unwrap();
+    assert_eq!(json["mapped"], serde_json::json!([2.0, 4.0, 6.0, 8.0]));
+    assert_eq!(json["filtered"], serde_json::json!([3.0, 4.0]));
+    assert_eq!(json["total"], 10.0);
+}
+
+#[test]
+fn map_needs_a_function() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "numbers", "type": "literal", "value": [1, 2] },
+                { "id": "key", "type": "literal", "value": "a" },
+                { "id": "mapped", "type": "call", "fnNodeId": "list_map", "args": ["key", "numbers"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors["mapped"][0]
+        .message
+        .starts_with("Expected a function."));
+}
+
+#[test]
+fn range() {
+    let mut vm = Vm::new();
+    let range = global(&vm, "range");
+    let mut call = |args: &[Value]| {
+        let list = vm.call_value(range, args).unwrap();
+        serde_json::to_value(list).unwrap()
+    };
+    assert_eq!(call(&[3.into()]), serde_json::json!([0.0, 1.0, 2.0]));
+    assert_eq!(call(&[1.into(), 3.into()]), serde_json::json!([1.0, 2.0]));
+    assert_eq!(
+        call(&[0.into(), 1.into(), 0.25.into()]),
+        serde_json::json!([0.0, 0.25, 0.5, 0.75])
+    );
+    assert_eq!(
+        call(&[3.into(), 0.into(), (-1).into()]),
+        serde_json::json!([3.0, 2.0, 1.0])
+    );
+    assert_eq!(call(&[3.into(), 0.into()]), serde_json::json!([]));
+    assert!(vm
+        .call_value(range, &[0.into(), 1.into(), 0.into()])
+        .is_err());
+    assert!(vm.call_value(range, &[1e300.into()]).is_err());
+    assert!(vm
+        .call_value(range, &[0.into(), 1e9.into(), 1.into()])
+        .is_err());
+}
+
+#[test]
+fn index_strings() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "text", "type": "literal", "value": "héllo wörld" },
+                { "id": "one", "type": "literal", "value": 1 },
+                { "id": "five", "type": "literal", "value": 5 },
+                { "id": "twenty", "type": "literal", "value": 20 },
+                { "id": "sub", "type": "call", "fnNodeId": "substring", "args": ["text", "one", "five"] },
+                { "id": "char", "type": "call", "fnNodeId": "char_at", "args": ["text", "one"] },
+                { "id": "out_of_range", "type": "call", "fnNodeId": "char_at", "args": ["text", "twenty"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors["out_of_range"][0]
+        .message
+        .contains("out of bounds"));
+    let substring = global(&vm, "substring");
+    let text = vm.string("héllo wörld");
+    vm.root(text);
+    let sub = vm
+        .call_value(substring, &[text, 7.into(), 11.into()])
+        .unwrap();
+    assert_eq!(String::try_from(sub).unwrap(), "örld");
+    assert!(vm
+        .call_value(substring, &[text, 3.into(), 12.into()])
+        .is_err());
+}
+
+#[test]
+fn parse_numbers() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "text", "type": "literal", "value": " 2.5e3 " },
+                { "id": "parsed", "type": "call", "fnNodeId": "parse_number", "args": ["text"] },
+                { "id": "again", "type": "call", "fnNodeId": "to_string", "args": ["parsed"] },
+                { "id": "word", "type": "literal", "value": "banjo" },
+                { "id": "invalid", "type": "call", "fnNodeId": "parse_number", "args": ["word"] },
+                { "id": "big", "type": "literal", "value": "9007199254740993" },
+                { "id": "exact", "type": "call", "fnNodeId": "parse_number", "args": ["big"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty());
+    assert_eq!(output.node_values["parsed"], Value::Number(2500.0));
+    assert_eq!(
+        String::try_from(output.node_values["again"]).unwrap(),
+        "2500"
+    );
+    assert_eq!(output.node_values["invalid"], Value::Nil);
+    assert_eq!(
+        output.node_values["exact"],
+        Value::Int(9_007_199_254_740_993)
+    );
+}
+
+#[test]
+fn running_aggregates() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "series", "type": "literal", "value": [1, 2, 3, 4, 5] },
+                { "id": "window", "type": "literal", "value": 2 },
+                { "id": "totals", "type": "call", "fnNodeId": "cumsum", "args": ["series"] },
+                { "id": "means", "type": "call", "fnNodeId": "running_mean", "args": ["series", "window"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty());
+    let json = serde_json::to_value(&output.node_values).unwrap();
+    assert_eq!(
+        json["totals"],
+        serde_json::json!([1.0, 3.0, 6.0, 10.0, 15.0])
+    );
+    assert_eq!(json["means"], serde_json::json!([1.0, 1.5, 2.5, 3.5, 4.5]));
+}
+
+#[test]
+fn linear_algebra() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "a", "type": "literal", "value": [1, 2, 3] },
+                { "id": "b", "type": "literal", "value": [4, 5, 6] },
+                { "id": "matrix", "type": "literal", "value": [[1, 2, 3], [4, 5, 6]] },
+                { "id": "half", "type": "literal", "value": 0.5 },
+                { "id": "product", "type": "call", "fnNodeId": "dot", "args": ["a", "b"] },
+                { "id": "transposed", "type": "call", "fnNodeId": "transpose", "args": ["matrix"] },
+                { "id": "scaled", "type": "call", "fnNodeId": "scale", "args": ["matrix", "half"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty());
+    let json = serde_json::to_value(&output.node_values).unwrap();
+    assert_eq!(json["product"], 32.0);
+    assert_eq!(
+        json["transposed"],
+        serde_json::json!([[1.0, 4.0], [2.0, 5.0], [3.0, 6.0]])
+    );
+    assert_eq!(
+        json["scaled"],
+        serde_json::json!([[0.5, 1.0, 1.5], [2.0, 2.5, 3.0]])
+    );
+}
+
+#[test]
+fn format_template() {
+    let mut vm = Vm::new();
+    let format = global(&vm, "format");
+    let mut call = |template: &str, args: &[Value]| {
+        let template = vm.string(template);
+        vm.root(template);
+        let args: Vec<_> = std::iter::once(template)
+            .chain(args.iter().copied())
+            .collect();
+        vm.call_value(format, &args)
+            .map(|text| String::try_from(text).unwrap())
+    };
+    assert_eq!(
+        call("{} + {} = {:.2}", &[1.into(), 2.5.into(), 3.5.into()]).unwrap(),
+        "1 + 2.5 = 3.50"
+    );
+    assert_eq!(call("{{{}}}", &[true.into()]).unwrap(), "{true}");
+    assert!(call("{}", &[]).is_err());
+    assert!(call("{}", &[1.into(), 2.into()]).is_err());
+    assert!(call("{", &[1.into()]).is_err());
+    assert!(call("{:x}", &[1.into()]).is_err());
+    assert!(call("{:.4000000000}", &[1.into()]).is_err());
+    assert_eq!(call("{:.100}", &[1.5.into()]).unwrap().len(), 102);
+}
+
+#[test]
+fn type_introspection() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "list", "type": "literal", "value": [1] },
+                { "id": "nothing", "type": "literal", "value": null },
+                { "id": "list_type", "type": "call", "fnNodeId": "type_of", "args": ["list"] },
+                { "id": "sum_ref", "type": "ref", "varNodeId": "sum" },
+                { "id": "sum_type", "type": "call", "fnNodeId": "type_of", "args": ["sum_ref"] },
+                { "id": "list_is_list", "type": "call", "fnNodeId": "is_list", "args": ["list"] },
+                { "id": "list_is_number", "type": "call", "fnNodeId": "is_number", "args": ["list"] },
+                { "id": "nothing_is_nil", "type": "call", "fnNodeId": "is_nil", "args": ["nothing"] },
+                { "id": "nothing_is_string", "type": "call", "fnNodeId": "is_string", "args": ["nothing"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty());
+    let json = serde_json::to_value(&output.node_values).unwrap();
+    assert_eq!(json["list_type"], "list");
+    assert_eq!(json["sum_type"], "function");
+    assert_eq!(json["list_is_list"], true);
+    assert_eq!(json["list_is_number"], false);
+    assert_eq!(json["nothing_is_nil"], true);
+    assert_eq!(json["nothing_is_string"], false);
+}
+
+#[test]
+fn sort_by_key() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "numbers", "type": "literal", "value": [3, -1, 2, -4] },
+                { "id": "x", "type": "param" },
+                { "id": "magnitude", "type": "call", "fnNodeId": "abs", "args": ["x"] },
+                { "id": "by_magnitude", "type": "fn", "args": ["magnitude"] },
+                { "id": "key_ref", "type": "ref", "varNodeId": "by_magnitude" },
+                { "id": "sorted", "type": "call", "fnNodeId": "sort_by", "args": ["key_ref", "numbers"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty());
+    let json = serde_json::to_value(&output.node_values).unwrap();
+    assert_eq!(json["sorted"], serde_json::json!([-1.0, 2.0, 3.0, -4.0]));
+}
+
+#[test]
+fn group_by_key() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "words", "type": "literal", "value": ["apple", "bob", "avocado", "banana", "cherry"] },
+                { "id": "word", "type": "param" },
+                { "id": "zero", "type": "literal", "value": 0 },
+                { "id": "initial", "type": "call", "fnNodeId": "char_at", "args": ["word", "zero"] },
+                { "id": "by_initial", "type": "fn", "args": ["initial"] },
+                { "id": "key_ref", "type": "ref", "varNodeId": "by_initial" },
+                { "id": "groups", "type": "call", "fnNodeId": "group_by", "args": ["key_ref", "words"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty());
+    let json = serde_json::to_value(&output.node_values).unwrap();
+    assert_eq!(
+        json["groups"],
+        serde_json::json!({
+            "a": ["apple", "avocado"],
+            "b": ["bob", "banana"],
+            "c": ["cherry"],
+        })
+    );
+}
+
+#[test]
+fn unique() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "values", "type": "literal", "value": [3, "a", 3, [1, 2], "a", [1, 2], null, 1, null] },
+                { "id": "deduplicated", "type": "call", "fnNodeId": "unique", "args": ["values"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty());
+    let json = serde_json::to_value(&output.node_values).unwrap();
+    assert_eq!(
+        json["deduplicated"],
+        serde_json::json!([3.0, "a", [1.0, 2.0], null, 1.0])
+    );
+}
+
+#[test]
+fn flatten() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "nested", "type": "literal", "value": [1, [2, [3, [4]]], 5] },
+                { "id": "two", "type": "literal", "value": 2 },
+                { "id": "once", "type": "call", "fnNodeId": "flatten", "args": ["nested"] },
+                { "id": "twice", "type": "call", "fnNodeId": "flatten", "args": ["nested", "two"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors.is_empty());
+    let json = serde_json::to_value(&output.node_values).unwrap();
+    assert_eq!(
+        json["once"],
+        serde_json::json!([1.0, 2.0, [3.0, [4.0]], 5.0])
+    );
+    assert_eq!(
+        json["twice"],
+        serde_json::json!([1.0, 2.0, 3.0, [4.0], 5.0])
+    );
+}
+
+#[test]
+fn interpolate() {
+    let mut vm = Vm::new();
+    let lerp = global(&vm, "lerp");
+    let map_range = global(&vm, "map_range");
+    let result = vm.call_value(lerp, &[10.into(), 20.into(), 0.25.into()]);
+    assert_eq!(result.unwrap(), Value::Number(12.5));
+    let args = [5.into(), 0.into(), 10.into(), 100.into(), 200.into()];
+    assert_eq!(
+        vm.call_value(map_range, &args).unwrap(),
+        Value::Number(150.0)
+    );
+    let empty = [5.into(), 1.into(), 1.into(), 0.into(), 1.into()];
+    assert!(vm.call_value(map_range, &empty).is_err());
+    assert!(vm.call_value(lerp, &[1.into(), 2.into()]).is_err());
+}
+
+fn triple(args: &[Value], _vm: &mut Vm) -> banjoc::error::Result<Value> {
+    Ok(Value::Number(f64::try_from(args[0])? * 3.0))
+}
+
+#[test]
+fn define_native() {
+    let mut vm = Vm::new();
+    vm.define_native("triple", Some(1), triple).unwrap();
+    assert!(vm.define_native("triple", None, triple).is_err());
+    let error = vm.define_native("sum", None, triple).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Runtime error: A global named 'sum' is already defined."
+    );
+
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "two", "type": "literal", "value": 2 },
+                { "id": "six", "type": "call", "fnNodeId": "triple", "args": ["two"] }
+            ]
+        }"#,
+    ));
+    assert_eq!(output.node_values["six"], Value::Number(6.0));
+
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "two", "type": "literal", "value": 2 },
+                { "id": "wrong", "type": "call", "fnNodeId": "triple", "args": ["two", "two"] }
+            ]
+        }"#,
+    ));
+    assert!(output.errors.node_errors["wrong"][0]
+        .message
+        .starts_with("Expected 1 arguments but got 2."));
+}
+
+#[test]
+fn stateful_native() {
+    let mut vm = Vm::new();
+    let calls = Rc::new(RefCell::new(0));
+    let counter = calls.clone();
+    let offset = 10.0;
+    vm.define_native("count", Some(1), move |args, _vm| {
+        *counter.borrow_mut() += 1;
+        Ok(Value::Number(f64::try_from(args[0])? + offset))
+    })
+    .unwrap();
+
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "two", "type": "literal", "value": 2 },
+                { "id": "a", "type": "call", "fnNodeId": "count", "args": ["two"] },
+                { "id": "b", "type": "call", "fnNodeId": "count", "args": ["a"] }
+            ]
+        }"#,
+    ));
+    assert_eq!(output.node_values["b"], Value::Number(22.0));
+    assert_eq!(*calls.borrow(), 2);
+}
+
+#[test]
+fn namespaced_natives() {
+    let mut vm = Vm::new();
+    let output = vm.interpret(source(
+        r#"{
+            "nodes": [
+                { "id": "nine", "type": "literal", "value": 9 },
+                { "id": "qualified", "type": "call", "fnNodeId": "math.sqrt", "args": ["nine"] },
+                { "id": "alias", "type": "call", "fnNodeId": "sqrt", "args": ["nine"] }
+            ]
+        }"#,
+    ));
+    assert_eq!(output.node_values["qualified"], Value::Number(3.0));
+    assert_eq!(output.node_values["alias"], Value::Number(3.0));
+
+    let mut registry = NativeRegistry::new();
+    registry.add("geo", "triple", Some(1), "Multiply by 3", triple);
+    assert_eq!(registry.names().collect::<Vec<_>>(), ["geo.triple"]);
+    vm.register_natives(&registry).unwrap();
+    assert!(vm.register_natives(&registry).is_err());
+    let triple = global(&vm, "geo.triple");
+    assert_eq!(
+        vm.call_value(triple, &[2.into()]).unwrap(),
+        Value::Number(6.0)
+    );
+
+    let standard = NativeRegistry::standard(&Capabilities::none());
+    assert!(standard.names().any(|name| name == "list.len"));
+    assert!(!standard.names().any(|name| name == "time.clock"));
+}
+
+#[test]
+fn describe_natives() {
+    let mut vm = Vm::new();
+    vm.define_native("triple", Some(1), triple).unwrap();
+    let natives = vm.natives();
+    let sqrt = natives
+        .iter()
+        .find(|native| native.name == "math.sqrt")
+        .unwrap();
+    assert_eq!(sqrt.namespace.as_deref(), Some("math"));
+    assert_eq!(sqrt.aliases, ["sqrt"]);
+    assert!(!sqrt.description.is_empty());
+    assert_eq!(sqrt.arity, Some(1));
+    let native = |name: &str| natives.iter().find(|native| native.name == name).unwrap();
+    let range = native("list.range");
+    assert_eq!(range.arity, None);
+    assert_eq!(
+        range.args,
+        ArgCount {
+            min: 1,
+            max: Some(3)
+        }
+    );
+    assert_eq!(native("math.sum").args, ArgCount { min: 0, max: None });
+    let triple = natives.last().unwrap();
+    assert_eq!(triple.name, "triple");
+    assert_eq!(triple.arity, Some(1));
+    assert_eq!(
+        triple.args,
+        ArgCount {
+            min: 1,
+            max: Some(1)
+        }
+    );
+    assert_eq!(triple.namespace, None);
+}
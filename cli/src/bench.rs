@@ -0,0 +1,70 @@
+use std::{
+    fs, process,
+    time::{Duration, Instant},
+};
+
+use banjoc::{program::Inputs, vm::Vm};
+
+use crate::{
+    options::{BenchOptions, Format},
+    parse,
+};
+
+/// Parse, compile and execute a graph many times, then print how long each
+/// phase took. Execution reuses a single compiled program.
+pub fn bench(vm: &mut Vm, options: &BenchOptions) {
+    let path = &options.path;
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) => {
+            eprintln!("Unable to read file {}: {}", path, error);
+            process::exit(74);
+        }
+    };
+    let format = Format::from_path(path);
+    let program = match parse(&content, format) {
+        Ok(source) => vm.compile(source),
+        Err(error) => {
+            eprintln!("{error}");
+            process::exit(65);
+        }
+    };
+    let no_inputs = [Inputs::default()];
+
+    let mut parse_times = Vec::with_capacity(options.iterations);
+    let mut compile_times = Vec::with_capacity(options.iterations);
+    let mut execute_times = Vec::with_capacity(options.iterations);
+    for _ in 0..options.iterations {
+        let now = Instant::now();
+        let source = parse(&content, format).expect("Parsed successfully before");
+        parse_times.push(now.elapsed());
+
+        let now = Instant::now();
+        vm.validate(source);
+        compile_times.push(now.elapsed());
+
+        let now = Instant::now();
+        vm.interpret_batch(&program, &no_inputs);
+        execute_times.push(now.elapsed());
+    }
+
+    println!("{} iterations of {path}", options.iterations);
+    println!("{:<10}{:>12}{:>12}{:>12}", "", "min", "mean", "p95");
+    print_stats("parse", parse_times);
+    print_stats("compile", compile_times);
+    print_stats("execute", execute_times);
+}
+
+fn print_stats(phase: &str, mut times: Vec<Duration>) {
+    times.sort();
+    let min = times[0];
+    let mean = times.iter().sum::<Duration>() / times.len() as u32;
+    let p95 = times[(times.len() * 95).div_ceil(100) - 1];
+    println!(
+        "{:<10}{:>12}{:>12}{:>12}",
+        phase,
+        format!("{min:.1?}"),
+        format!("{mean:.1?}"),
+        format!("{p95:.1?}")
+    );
+}
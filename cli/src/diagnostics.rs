@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+
+use banjoc::{
+    error::{Diagnostic, Severity},
+    output::Output,
+};
+use serde_json::Value as Json;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const BLUE: &str = "\x1b[34m";
+const CYAN: &str = "\x1b[36m";
+
+/// Print the errors and diagnostics of an output to stderr for humans to
+/// read, along with the label and inputs of each offending node found in
+/// `source`, the graph as JSON
+pub fn print_diagnostics(output: &Output, source: Option<&Json>, color: bool) {
+    let style = |code: &'static str| if color { code } else { "" };
+    let (reset, bold, blue) = (style(RESET), style(BOLD), style(BLUE));
+
+    let by_node: BTreeMap<_, _> = output
+        .errors
+        .node_errors
+        .iter()
+        .chain(&output.errors.node_diagnostics)
+        .flat_map(|(node_id, diagnostics)| diagnostics.iter().map(move |d| (node_id, d)))
+        .fold(
+            BTreeMap::<_, Vec<_>>::new(),
+            |mut by_node, (node_id, diagnostic)| {
+                by_node.entry(node_id).or_default().push(diagnostic);
+                by_node
+            },
+        );
+
+    for error in &output.errors.additional_errors {
+        print_heading(error, color);
+    }
+    for (node_id, diagnostics) in by_node {
+        let node = source.and_then(|source| find_node(source, node_id));
+        for diagnostic in diagnostics {
+            print_heading(diagnostic, color);
+            let label = node
+                .and_then(|node| node["label"].as_str())
+                .map(|label| format!(" \"{label}\""))
+                .unwrap_or_default();
+            eprintln!("  {blue}-->{reset} node '{node_id}'{label}");
+            let inputs = node.map(inputs).unwrap_or_default();
+            if !inputs.is_empty() {
+                eprintln!(
+                    "  {blue} | {reset}{bold}inputs:{reset} {}",
+                    inputs.join(", ")
+                );
+            }
+        }
+    }
+}
+
+fn print_heading(diagnostic: &Diagnostic, color: bool) {
+    let (name, severity_color) = match diagnostic.severity {
+        Severity::Error => ("error", RED),
+        Severity::Warning => ("warning", YELLOW),
+        Severity::Info => ("info", CYAN),
+    };
+    if color {
+        eprintln!(
+            "{BOLD}{severity_color}{name}[{}]{RESET}{BOLD}: {}{RESET}",
+            diagnostic.code, diagnostic.message
+        );
+    } else {
+        eprintln!("{name}[{}]: {}", diagnostic.code, diagnostic.message);
+    }
+}
+
+fn find_node<'a>(source: &'a Json, node_id: &str) -> Option<&'a Json> {
+    source["nodes"]
+        .as_array()?
+        .iter()
+        .find(|node| node["id"] == node_id)
+}
+
+/// Ids of the nodes which the node reads from
+fn inputs(node: &Json) -> Vec<&str> {
+    let args = node["args"].as_array().into_iter().flatten();
+    args.chain([&node["fnNodeId"], &node["varNodeId"]])
+        .filter_map(Json::as_str)
+        .collect()
+}
@@ -0,0 +1,69 @@
+use std::{fs, process};
+
+use serde_json::Value as Json;
+
+/// Print a JSON graph in Graphviz syntax. Arguments are drawn as solid edges
+/// and references to variables and functions as dashed ones.
+pub fn export_dot(path: &str) {
+    let graph: Json = match fs::read_to_string(path) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(graph) => graph,
+            Err(error) => {
+                eprintln!("JSON parsing error: {error}");
+                process::exit(65);
+            }
+        },
+        Err(error) => {
+            eprintln!("Unable to read file {}: {}", path, error);
+            process::exit(74);
+        }
+    };
+    print!("{}", to_dot(&graph));
+}
+
+pub fn to_dot(graph: &Json) -> String {
+    let nodes = graph["nodes"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let mut dot = String::from("digraph {\n");
+    for node in nodes {
+        let Some(id) = node["id"].as_str() else {
+            continue;
+        };
+        let mut label = node["label"].as_str().unwrap_or(id).to_string();
+        if let Some(node_type) = node["type"].as_str() {
+            label += &format!("\n({node_type})");
+        }
+        if !node["value"].is_null() {
+            label += &format!("\n{}", node["value"]);
+        }
+        dot += &format!("    {} [label={}];\n", quote(id), quote(&label));
+
+        for arg in node["args"].as_array().into_iter().flatten() {
+            if let Some(arg) = arg.as_str() {
+                dot += &format!("    {} -> {};\n", quote(arg), quote(id));
+            }
+        }
+        for reference in [&node["fnNodeId"], &node["varNodeId"]] {
+            if let Some(reference) = reference.as_str() {
+                dot += &format!(
+                    "    {} -> {} [style=dashed];\n",
+                    quote(reference),
+                    quote(id)
+                );
+            }
+        }
+    }
+    dot += "}\n";
+    dot
+}
+
+fn quote(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
@@ -0,0 +1,199 @@
+use std::{fs, process};
+
+use banjoc::{registry::NativeInfo, vm::Vm};
+use serde_json::{Map, Value as Json};
+
+use crate::dot;
+
+/// Fields of a node in the order they're written. Others follow in
+/// alphabetical order.
+const FIELD_ORDER: &[&str] = &[
+    "id",
+    "type",
+    "label",
+    "unary_type",
+    "binary_type",
+    "value",
+    "varNodeId",
+    "fnNodeId",
+    "args",
+    "sampleArgs",
+];
+
+/// Target of `banjo convert`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Json,
+    Dot,
+    Yaml,
+}
+
+impl Target {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Self::Json),
+            "dot" => Some(Self::Dot),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// Rewrite a JSON graph in canonical form, so that equivalent graphs are
+/// written identically and diffs only show what changed
+pub fn fmt_file(path: &str) {
+    let formatted = to_json(&canonicalize(read_graph(path)));
+    if let Err(error) = fs::write(path, formatted) {
+        eprintln!("Unable to write file {}: {}", path, error);
+        process::exit(74);
+    }
+}
+
+/// Print a JSON graph in canonical form, in the given format
+pub fn convert_file(path: &str, target: Target) {
+    let graph = canonicalize(read_graph(path));
+    let converted = match target {
+        Target::Json => to_json(&graph),
+        Target::Dot => dot::to_dot(&graph),
+        Target::Yaml => to_yaml(&graph),
+    };
+    print!("{converted}");
+}
+
+fn read_graph(path: &str) -> Json {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) => {
+            eprintln!("Unable to read file {}: {}", path, error);
+            process::exit(74);
+        }
+    };
+    match serde_json::from_str(&content) {
+        Ok(graph) => graph,
+        Err(error) => {
+            eprintln!("JSON parsing error: {error}");
+            process::exit(65);
+        }
+    }
+}
+
+/// Sort nodes by id and call natives by their qualified names rather than
+/// their aliases
+fn canonicalize(mut graph: Json) -> Json {
+    let Some(nodes) = graph.get_mut("nodes").and_then(Json::as_array_mut) else {
+        return graph;
+    };
+    nodes.sort_by(|a, b| a["id"].as_str().cmp(&b["id"].as_str()));
+
+    let natives = Vm::new().natives().to_vec();
+    let ids: Vec<String> = nodes
+        .iter()
+        .filter_map(|node| Some(node["id"].as_str()?.to_string()))
+        .collect();
+    for node in nodes.iter_mut() {
+        let Some(fn_node_id) = node.get_mut("fnNodeId") else {
+            continue;
+        };
+        let Some(name) = fn_node_id.as_str() else {
+            continue;
+        };
+        // Nodes shadow natives
+        if ids.iter().any(|id| id == name) {
+            continue;
+        }
+        if let Some(native) = find_alias(&natives, name) {
+            *fn_node_id = Json::String(native.name.clone());
+        }
+    }
+    graph
+}
+
+fn find_alias<'a>(natives: &'a [NativeInfo], name: &str) -> Option<&'a NativeInfo> {
+    natives
+        .iter()
+        .find(|native| native.aliases.iter().any(|alias| alias == name))
+}
+
+/// The fields of an object, nodes' fields in canonical order
+fn ordered_fields(object: &Map<String, Json>) -> Vec<(&String, &Json)> {
+    let rank = |key: &str| {
+        FIELD_ORDER
+            .iter()
+            .position(|field| *field == key)
+            .unwrap_or(FIELD_ORDER.len())
+    };
+    let mut fields: Vec<_> = object.iter().collect();
+    fields.sort_by(|(a, _), (b, _)| rank(a).cmp(&rank(b)).then(a.cmp(b)));
+    fields
+}
+
+/// Write the graph with one node per line, then the other top-level fields
+fn to_json(graph: &Json) -> String {
+    let Json::Object(graph) = graph else {
+        return format!("{graph}\n");
+    };
+    let mut fields: Vec<String> = vec![];
+    for (key, value) in graph {
+        let value = match (key.as_str(), value) {
+            ("nodes", Json::Array(nodes)) => {
+                let nodes: Vec<String> = nodes.iter().map(node_to_json).collect();
+                format!("[\n    {}\n  ]", nodes.join(",\n    "))
+            }
+            (_, value) => value.to_string(),
+        };
+        fields.push(format!("  {}: {value}", Json::from(key.as_str())));
+    }
+    format!("{{\n{}\n}}\n", fields.join(",\n"))
+}
+
+fn node_to_json(node: &Json) -> String {
+    let Json::Object(node) = node else {
+        return node.to_string();
+    };
+    let fields: Vec<String> = ordered_fields(node)
+        .into_iter()
+        .map(|(key, value)| format!("{}: {value}", Json::from(key.as_str())))
+        .collect();
+    format!("{{ {} }}", fields.join(", "))
+}
+
+fn to_yaml(graph: &Json) -> String {
+    let mut yaml = String::new();
+    write_yaml(&mut yaml, graph, 0);
+    yaml
+}
+
+/// Write `value` in block style, each line indented by `indent` spaces.
+/// Strings are written as double-quoted JSON strings, which YAML accepts.
+fn write_yaml(yaml: &mut String, value: &Json, indent: usize) {
+    let padding = " ".repeat(indent);
+    match value {
+        Json::Object(object) if !object.is_empty() => {
+            for (key, value) in ordered_fields(object) {
+                yaml.push_str(&format!("{padding}{}:", Json::from(key.as_str())));
+                write_yaml_child(yaml, value, indent);
+            }
+        }
+        Json::Array(array) if !array.is_empty() => {
+            for value in array {
+                yaml.push_str(&format!("{padding}-"));
+                write_yaml_child(yaml, value, indent);
+            }
+        }
+        scalar => yaml.push_str(&format!("{padding}{scalar}\n")),
+    }
+}
+
+fn write_yaml_child(yaml: &mut String, value: &Json, indent: usize) {
+    match value {
+        Json::Object(object) if !object.is_empty() => {
+            yaml.push('\n');
+            write_yaml(yaml, value, indent + 2);
+        }
+        Json::Array(array) if !array.is_empty() => {
+            yaml.push('\n');
+            write_yaml(yaml, value, indent + 2);
+        }
+        scalar => yaml.push_str(&format!(" {scalar}\n")),
+    }
+}
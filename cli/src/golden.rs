@@ -0,0 +1,117 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use banjoc::{output::Output, vm::Vm};
+use serde_json::{json, Value as Json};
+
+use crate::{options::Format, parse};
+
+/// Run every `*.json` graph in `dir` and compare its output with the
+/// `*.output.json` file next to it, the same way banjoc's own file tests do.
+/// Only node values and error messages are compared. Exits with status 1 if
+/// any file fails.
+pub fn test_dir(dir: &str) {
+    let mut sources: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                let name = path.to_string_lossy();
+                name.ends_with(".json") && !name.ends_with(".output.json")
+            })
+            .collect(),
+        Err(error) => {
+            eprintln!("Unable to read directory {}: {}", dir, error);
+            process::exit(74);
+        }
+    };
+    sources.sort();
+
+    let mut failed = 0;
+    for path in &sources {
+        match test_file(path) {
+            Ok(()) => println!("PASS {}", path.display()),
+            Err(message) => {
+                failed += 1;
+                println!("FAIL {}\n{message}", path.display());
+            }
+        }
+    }
+    println!("{} passed; {} failed", sources.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+fn test_file(path: &Path) -> Result<(), String> {
+    let read = |path: &Path| {
+        fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {e}", path.display()))
+    };
+    let source = parse(&read(path)?, Format::Json).map_err(|e| e.to_string())?;
+    let expected_path = path.with_extension("output.json");
+    let expected: Json = serde_json::from_str(&read(&expected_path)?)
+        .map_err(|e| format!("Invalid JSON in {}: {e}", expected_path.display()))?;
+    let expected = json!({
+        "nodeValues": expected.get("nodeValues").cloned().unwrap_or_else(|| json!({})),
+        "nodeErrors": expected.get("nodeErrors").cloned().unwrap_or_else(|| json!({})),
+        "additionalErrors": expected.get("additionalErrors").cloned().unwrap_or_else(|| json!([])),
+    });
+
+    let mut vm = Vm::new();
+    let actual = comparable(&vm.interpret(source));
+    if json_eq(&expected, &actual) {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected: {}\nactual:   {}",
+            serde_json::to_string(&expected).unwrap(),
+            serde_json::to_string(&actual).unwrap()
+        ))
+    }
+}
+
+/// The parts of an output which golden files specify
+fn comparable(output: &Output) -> Json {
+    let node_values: BTreeMap<_, _> = output
+        .node_values
+        .iter()
+        .map(|(node_id, value)| (node_id, value.to_json()))
+        .collect();
+    let node_errors: BTreeMap<_, Vec<_>> = output
+        .errors
+        .node_errors
+        .iter()
+        .map(|(node_id, errors)| (node_id, errors.iter().map(|e| &e.message).collect()))
+        .collect();
+    let additional_errors: Vec<_> = output
+        .errors
+        .additional_errors
+        .iter()
+        .map(|e| &e.message)
+        .collect();
+    json!({
+        "nodeValues": node_values,
+        "nodeErrors": node_errors,
+        "additionalErrors": additional_errors,
+    })
+}
+
+/// Like `==`, but numbers are equal if they have the same value, whether
+/// written as integers or not
+fn json_eq(a: &Json, b: &Json) -> bool {
+    match (a, b) {
+        (Json::Number(a), Json::Number(b)) => a.as_f64() == b.as_f64(),
+        (Json::Array(a), Json::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_eq(a, b))
+        }
+        (Json::Object(a), Json::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(key, a)| b.get(key).is_some_and(|b| json_eq(a, b)))
+        }
+        (a, b) => a == b,
+    }
+}
@@ -1,30 +1,42 @@
+mod bench;
+mod diagnostics;
+mod dot;
+mod fmt;
+mod golden;
+mod options;
+mod profile;
+mod repl;
+mod trace;
+
 use std::{
+    collections::BTreeMap,
     env, fs,
-    io::{self, Write},
-    process,
-    time::Instant,
+    io::{self, IsTerminal},
+    process, thread,
+    time::{Duration, Instant},
 };
 
-use banjoc::{ast::Source, error::Error, output::Output, vm::Vm};
+use banjoc::{ast::Source, config::VmConfig, error::Error, output::Output, vm::Vm};
+use options::{BenchOptions, Format, RunOptions};
+use repl::Repl;
 use serde_json::from_str;
+use trace::Tracer;
 
-fn repl(vm: &mut Vm) {
-    loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
-        let mut line = String::new();
-        io::stdin()
-            .read_line(&mut line)
-            .expect("Unable to read line from the REPL");
-        if line.is_empty() {
-            break;
-        }
-        let result = interpret(vm, &line);
-        println!("{}", serde_json::to_string_pretty(&result).unwrap());
-    }
-}
+const USAGE: &str = "\
+Usage: banjo [path]
+       banjo run [--watch] [--format json|dot] [--output file] [--compact] [--trace]
+                 [--pretty-errors] [--nodes a,b] [--profile] path
+       banjo bench [--iterations n] path
+       banjo test dir
+       banjo export-dot path
+       banjo fmt path
+       banjo convert --to json|dot|yaml path";
+
+/// How often `--watch` checks whether the file changed
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
 
-fn run_file(vm: &mut Vm, path: &str) {
+fn run_file(vm: &mut Vm, options: &RunOptions) {
+    let path = &options.path;
     let source = match fs::read_to_string(path) {
         Ok(content) => content,
         Err(error) => {
@@ -32,31 +44,188 @@ fn run_file(vm: &mut Vm, path: &str) {
             process::exit(74);
         }
     };
-    let output = interpret(vm, &source);
-    println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    let output = interpret(vm, &source, options.format(), options.nodes.as_deref());
+    write_output(&output, options);
+    if let Some(profile) = vm.take_profile() {
+        profile::print_profile(&profile);
+    }
+    let terminal = io::stderr().is_terminal();
+    if options.pretty_errors || terminal {
+        let json = (options.format() == Format::Json)
+            .then(|| from_str(&source).ok())
+            .flatten();
+        diagnostics::print_diagnostics(&output, json.as_ref(), terminal);
+    }
+    if let Some(code) = exit_code(&output) {
+        process::exit(code);
+    }
+}
+
+/// The status to exit with if the output has errors: 65 if the graph didn't
+/// compile, otherwise 70
+fn exit_code(output: &Output) -> Option<i32> {
+    let mut errors = output
+        .errors
+        .node_errors
+        .values()
+        .flatten()
+        .chain(&output.errors.additional_errors)
+        .peekable();
+    errors.peek()?;
+    if errors.any(|error| error.code == "compile") {
+        Some(65)
+    } else {
+        Some(70)
+    }
+}
+
+fn write_output(output: &Output, options: &RunOptions) {
+    let json = if options.compact {
+        serde_json::to_string(output)
+    } else {
+        serde_json::to_string_pretty(output)
+    }
+    .unwrap();
+    match &options.output {
+        Some(path) => {
+            if let Err(error) = fs::write(path, json) {
+                eprintln!("Unable to write file {}: {}", path, error);
+                process::exit(74);
+            }
+        }
+        None => println!("{json}"),
+    }
+}
+
+/// Run the file again whenever it's modified, printing only the node values
+/// which changed since the previous run
+fn watch_file(vm: &mut Vm, options: &RunOptions) {
+    let path = &options.path;
+    let mut modified = None;
+    let mut previous: Option<BTreeMap<String, serde_json::Value>> = None;
+    loop {
+        let last_modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if last_modified != modified {
+            modified = last_modified;
+            match fs::read_to_string(path) {
+                Ok(source) => {
+                    let output = interpret(vm, &source, options.format(), options.nodes.as_deref());
+                    let values = output
+                        .node_values
+                        .iter()
+                        .map(|(node_id, value)| (node_id.clone(), value.to_json()))
+                        .collect();
+                    match &previous {
+                        Some(previous) => {
+                            print_changes(previous, &values);
+                            print_errors(&output);
+                        }
+                        None => write_output(&output, options),
+                    }
+                    previous = Some(values);
+                }
+                Err(error) => eprintln!("Unable to read file {}: {}", path, error),
+            }
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
 }
 
-fn interpret(vm: &mut Vm, source: &str) -> Output {
+fn print_changes(
+    previous: &BTreeMap<String, serde_json::Value>,
+    current: &BTreeMap<String, serde_json::Value>,
+) {
+    for (node_id, value) in current {
+        match previous.get(node_id) {
+            Some(old) if old == value => {}
+            Some(old) => println!("~ {node_id}: {old} -> {value}"),
+            None => println!("+ {node_id}: {value}"),
+        }
+    }
+    for node_id in previous.keys() {
+        if !current.contains_key(node_id) {
+            println!("- {node_id}");
+        }
+    }
+}
+
+fn print_errors(output: &Output) {
+    let node_errors: BTreeMap<_, _> = output.errors.node_errors.iter().collect();
+    for (node_id, errors) in node_errors {
+        for error in errors {
+            println!("! {node_id}: {}", error.message);
+        }
+    }
+    for error in &output.errors.additional_errors {
+        println!("! {}", error.message);
+    }
+}
+
+/// Parse and run a graph. If `nodes` is given, only those nodes are computed
+/// and output.
+fn interpret(vm: &mut Vm, source: &str, format: Format, nodes: Option<&[String]>) -> Output {
     let now = Instant::now();
-    let source: Source = match from_str(source) {
+    let mut source = match parse(source, format) {
         Ok(source) => source,
-        Err(e) => {
-            return Output::from_single_error(Error::Compile(format!("JSON parsing error: {e}")))
-        }
+        Err(e) => return Output::from_single_error(e),
     };
-    println!("Parsing took {:.0?}", now.elapsed());
+    if let Some(nodes) = nodes {
+        source.outputs = Some(nodes.to_vec());
+    }
+    eprintln!("Parsing took {:.0?}", now.elapsed());
     vm.interpret(source)
 }
 
+fn parse(source: &str, format: Format) -> Result<Source, Error> {
+    match format {
+        Format::Json => {
+            from_str(source).map_err(|e| Error::Compile(format!("JSON parsing error: {e}")))
+        }
+        Format::Dot => Err(Error::Compile(
+            "Graphviz (DOT) sources aren't supported yet.".to_string(),
+        )),
+    }
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut vm = Vm::new();
-    match args.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&mut vm, &args[1]),
-        _ => {
-            eprintln!("Usage: banjo [path]");
-            process::exit(64);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let options = match args.as_slice() {
+        [] => return Repl::new(&mut Vm::new()).run(),
+        ["bench", args @ ..] => {
+            let Some(options) = BenchOptions::parse(args) else {
+                eprintln!("Usage: banjo bench [--iterations n] path");
+                process::exit(64);
+            };
+            return bench::bench(&mut Vm::new(), &options);
+        }
+        ["test", dir] => return golden::test_dir(dir),
+        ["export-dot", path] => return dot::export_dot(path),
+        ["fmt", path] => return fmt::fmt_file(path),
+        ["convert", "--to", target, path] | ["convert", path, "--to", target] => {
+            match fmt::Target::from_name(target) {
+                Some(target) => return fmt::convert_file(path, target),
+                None => None,
+            }
         }
+        ["run", args @ ..] => RunOptions::parse(args),
+        [path] if !path.starts_with("--") => RunOptions::parse(&[path]),
+        _ => None,
+    };
+    let Some(options) = options else {
+        eprintln!("{USAGE}");
+        process::exit(64);
+    };
+    let mut vm = Vm::with_config(VmConfig {
+        profile: options.profile,
+        ..VmConfig::default()
+    });
+    if options.trace {
+        vm.set_hooks(Box::new(Tracer));
+    }
+    if options.watch {
+        watch_file(&mut vm, &options);
+    } else {
+        run_file(&mut vm, &options);
     }
 }
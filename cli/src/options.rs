@@ -0,0 +1,129 @@
+use std::path::Path;
+
+/// Syntax of a source file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    /// Graphviz syntax
+    Dot,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Self::Json),
+            "dot" | "gv" => Some(Self::Dot),
+            _ => None,
+        }
+    }
+
+    /// Guess the format from the extension of the file, defaulting to JSON.
+    /// Formats which can't be parsed yet are never guessed, so that files
+    /// with their extension which contain JSON still run.
+    pub fn from_path(path: &str) -> Self {
+        Path::new(path)
+            .extension()
+            .and_then(|extension| Self::from_name(&extension.to_string_lossy()))
+            .filter(|format| format.is_supported())
+            .unwrap_or(Self::Json)
+    }
+
+    fn is_supported(self) -> bool {
+        // There's no DOT frontend yet
+        self != Self::Dot
+    }
+}
+
+/// Arguments of `banjo run`
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    pub path: String,
+    /// Run again whenever the file changes
+    pub watch: bool,
+    /// `None` if it should be guessed from the path
+    pub format: Option<Format>,
+    /// Write the output to this file instead of stdout
+    pub output: Option<String>,
+    /// Write the output as minified JSON
+    pub compact: bool,
+    /// Print each instruction as it's executed
+    pub trace: bool,
+    /// Also print errors in a human readable form, even if stderr isn't a
+    /// terminal
+    pub pretty_errors: bool,
+    /// Only compute and output these nodes
+    pub nodes: Option<Vec<String>>,
+    /// Print how long each node and function took to execute
+    pub profile: bool,
+}
+
+impl RunOptions {
+    /// Parse the arguments following `run`. Returns `None` if they're invalid.
+    pub fn parse(args: &[&str]) -> Option<Self> {
+        let mut options = Self::default();
+        let mut path = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match *arg {
+                "--watch" => options.watch = true,
+                "--format" => options.format = Some(Format::from_name(args.next()?)?),
+                "--output" => options.output = Some(args.next()?.to_string()),
+                "--compact" => options.compact = true,
+                "--trace" => options.trace = true,
+                "--pretty-errors" => options.pretty_errors = true,
+                "--profile" => options.profile = true,
+                "--nodes" => {
+                    let nodes = args.next()?.split(',').map(str::to_string).collect();
+                    options.nodes = Some(nodes);
+                }
+                flag if flag.starts_with("--") => return None,
+                arg => {
+                    if path.replace(arg).is_some() {
+                        return None;
+                    }
+                }
+            }
+        }
+        options.path = path?.to_string();
+        Some(options)
+    }
+
+    pub fn format(&self) -> Format {
+        self.format.unwrap_or_else(|| Format::from_path(&self.path))
+    }
+}
+
+/// Arguments of `banjo bench`
+#[derive(Debug)]
+pub struct BenchOptions {
+    pub path: String,
+    /// How many times to parse, compile and execute the graph
+    pub iterations: usize,
+}
+
+impl BenchOptions {
+    const DEFAULT_ITERATIONS: usize = 100;
+
+    /// Parse the arguments following `bench`. Returns `None` if they're
+    /// invalid.
+    pub fn parse(args: &[&str]) -> Option<Self> {
+        let mut path = None;
+        let mut iterations = Self::DEFAULT_ITERATIONS;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match *arg {
+                "--iterations" => iterations = args.next()?.parse().ok().filter(|n| *n > 0)?,
+                flag if flag.starts_with("--") => return None,
+                arg => {
+                    if path.replace(arg).is_some() {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(Self {
+            path: path?.to_string(),
+            iterations,
+        })
+    }
+}
@@ -0,0 +1,29 @@
+use std::collections::HashMap;
+
+use banjoc::profile::{Profile, ProfileEntry};
+
+/// Print the nodes and functions of a profile to stderr, slowest first
+pub fn print_profile(profile: &Profile) {
+    print_table("node", &profile.nodes);
+    eprintln!();
+    print_table("function", &profile.functions);
+}
+
+fn print_table(title: &str, entries: &HashMap<String, ProfileEntry>) {
+    let mut entries: Vec<_> = entries.iter().collect();
+    entries.sort_by(|(a_name, a), (b_name, b)| b.time.cmp(&a.time).then(a_name.cmp(b_name)));
+    let width = entries
+        .iter()
+        .map(|(name, _)| name.len())
+        .chain([title.len()])
+        .max()
+        .unwrap_or_default();
+    eprintln!("{title:<width$}{:>12}{:>10}", "time", "count");
+    for (name, entry) in entries {
+        eprintln!(
+            "{name:<width$}{:>12}{:>10}",
+            format!("{:.1?}", entry.time),
+            entry.count
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    fs,
+    io::{self, Write},
+};
+
+use banjoc::{ast::Source, error::Error, output::Output, vm::Vm};
+use serde_json::{json, Value as Json};
+
+use crate::{interpret, options::Format};
+
+const HELP: &str = "\
+Enter a whole graph as JSON to run it, or edit the current graph with:
+  :load <file>       replace the graph with the one in a file
+  :set <id> <json>   add or replace a node, e.g. :set a {\"type\": \"const\", \"value\": 1}
+  :show <id>         print a node and its value
+  :nodes             list the nodes of the graph";
+
+/// An interactive session, editing a graph which is run after each change
+pub struct Repl<'vm> {
+    vm: &'vm mut Vm,
+    /// The nodes of the graph being edited, as JSON objects
+    nodes: Vec<Json>,
+    /// The result of the latest run of the graph
+    output: Option<Output>,
+}
+
+impl<'vm> Repl<'vm> {
+    pub fn new(vm: &'vm mut Vm) -> Self {
+        Self {
+            vm,
+            nodes: vec![],
+            output: None,
+        }
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            print!("> ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            io::stdin()
+                .read_line(&mut line)
+                .expect("Unable to read line from the REPL");
+            if line.is_empty() {
+                break;
+            }
+            let line = line.trim();
+            if line.starts_with(':') {
+                if let Err(message) = self.command(line) {
+                    eprintln!("{message}");
+                }
+            } else if !line.is_empty() {
+                let result = interpret(self.vm, line, Format::Json, None);
+                println!("{}", serde_json::to_string_pretty(&result).unwrap());
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str) -> Result<(), String> {
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        match command {
+            ":load" => {
+                let content = fs::read_to_string(rest)
+                    .map_err(|e| format!("Unable to read file {rest}: {e}"))?;
+                let mut graph: Json =
+                    serde_json::from_str(&content).map_err(|e| format!("Invalid JSON: {e}"))?;
+                let Some(Json::Array(nodes)) = graph.get_mut("nodes").map(Json::take) else {
+                    return Err("Expected an object with a list of nodes.".to_string());
+                };
+                self.nodes = nodes;
+                self.evaluate();
+            }
+            ":set" => {
+                let (id, node) = rest
+                    .split_once(' ')
+                    .ok_or_else(|| "Usage: :set <id> <json>".to_string())?;
+                let mut node: Json =
+                    serde_json::from_str(node).map_err(|e| format!("Invalid JSON: {e}"))?;
+                let Json::Object(fields) = &mut node else {
+                    return Err("Expected a JSON object.".to_string());
+                };
+                fields.insert("id".to_string(), json!(id));
+                match self.nodes.iter_mut().find(|n| n["id"] == id) {
+                    Some(existing) => *existing = node,
+                    None => self.nodes.push(node),
+                }
+                self.evaluate();
+            }
+            ":show" => {
+                let node = self
+                    .nodes
+                    .iter()
+                    .find(|n| n["id"] == rest)
+                    .ok_or_else(|| format!("No node with id '{rest}'."))?;
+                println!("{}", serde_json::to_string_pretty(node).unwrap());
+                if let Some(output) = &self.output {
+                    if let Some(value) = output.node_values.get(rest) {
+                        println!("= {value}");
+                    }
+                    for error in output.errors.node_errors.get(rest).into_iter().flatten() {
+                        println!("! {}", error.message);
+                    }
+                }
+            }
+            ":nodes" => {
+                for node in &self.nodes {
+                    let id = node["id"].as_str().unwrap_or_default();
+                    let node_type = node["type"].as_str().unwrap_or_default();
+                    println!("{id} ({node_type})");
+                }
+            }
+            ":help" => println!("{HELP}"),
+            _ => return Err(format!("Unknown command {command}. Type :help for help.")),
+        }
+        Ok(())
+    }
+
+    /// Run the graph being edited and print its output
+    fn evaluate(&mut self) {
+        let graph = json!({ "nodes": self.nodes });
+        let output = match serde_json::from_value::<Source>(graph) {
+            Ok(source) => self.vm.interpret(source),
+            Err(e) => Output::from_single_error(Error::Compile(format!("JSON parsing error: {e}"))),
+        };
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+        self.output = Some(output);
+    }
+}
@@ -0,0 +1,16 @@
+use banjoc::{hooks::VmHooks, vm::Vm};
+
+/// Prints the stack and each instruction as it's executed, like the
+/// `debug_trace_execution` feature of banjoc but without recompiling it.
+/// Written to stderr so it doesn't mix with the output.
+pub struct Tracer;
+
+impl VmHooks for Tracer {
+    fn on_instruction(&mut self, vm: &Vm, instruction: &'static str, node_id: Option<&str>) {
+        eprintln!("        {:?}", vm.stack());
+        match node_id {
+            Some(node_id) => eprintln!("{instruction:<16} {node_id}"),
+            None => eprintln!("{instruction}"),
+        }
+    }
+}